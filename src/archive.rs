@@ -1,50 +1,178 @@
-use std::{fs::File, collections::HashMap, io::Read, sync::Arc};
+use std::{fs::{self, File}, collections::HashMap, io::{self, Read, Write}, sync::Arc, fmt::{self, Display}, error::Error, path::Path};
 
-use tar::{Archive as Tar, Entry};
-use flate2::read::GzDecoder;
+use tar::{Archive as Tar, Builder as TarBuilder, Header, Entry};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 
-use crate::{vm_config::VMConfig, block_info::BlockInfo};
+use crate::{vm_config::{VMConfig, ConfigError}, block_info::{BlockInfo, BlockInfoError}, debug_info::{DebugInfo, DebugInfoError}};
 
-fn read_entry<'a>(readable: &mut Entry<'a, GzDecoder<File>>) -> Box<[u8]> {
-    let mut vec = Vec::with_capacity(readable.header().size().unwrap() as usize);
+const RESERVED_ENTRIES: [&str; 4] = ["Main.bin", "Conf.bin", "BlockInfo.bin", "DebugInfo.bin"];
 
-    readable.read_to_end(&mut vec).unwrap();
+fn append_entry<W: Write>(tar: &mut TarBuilder<W>, name: &str, data: &[u8]) -> io::Result<()> {
+    let mut header = Header::new_gnu();
 
-    vec.into_boxed_slice()
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    tar.append_data(&mut header, name, data)
+}
+
+fn read_entry<'a>(readable: &mut Entry<'a, &[u8]>) -> Result<Box<[u8]>, ArchiveError> {
+    let mut vec = Vec::with_capacity(readable.header().size().map_err(ArchiveError::Tar)? as usize);
+
+    readable.read_to_end(&mut vec).map_err(ArchiveError::Tar)?;
+
+    Ok(vec.into_boxed_slice())
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(io::Error),
+    Gzip(io::Error),
+    Tar(io::Error),
+    InvalidEntryName(String),
+    MissingEntry(&'static str),
+    InvalidConfig(ConfigError),
+    InvalidBlockInfo(BlockInfoError),
+    InvalidDebugInfo(DebugInfoError),
+}
+
+impl Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Io(err) => write!(f, "Cannot read archive: {err}"),
+            ArchiveError::Gzip(err) => write!(f, "Archive is not a valid gzip stream: {err}"),
+            ArchiveError::Tar(err) => write!(f, "Archive is not a valid tar file: {err}"),
+            ArchiveError::InvalidEntryName(name) => write!(f, "Archive entry name `{name}` is not valid UTF-8."),
+            ArchiveError::MissingEntry(name) => write!(f, "Archive does not contain required entry `{name}`."),
+            ArchiveError::InvalidConfig(err) => write!(f, "Malformed `Conf.bin`: {err}"),
+            ArchiveError::InvalidBlockInfo(err) => write!(f, "Malformed `BlockInfo.bin`: {err}"),
+            ArchiveError::InvalidDebugInfo(err) => write!(f, "Malformed `DebugInfo.bin`: {err}"),
+        }
+    }
+}
+
+impl Error for ArchiveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ArchiveError::Io(err) | ArchiveError::Gzip(err) | ArchiveError::Tar(err) => Some(err),
+            ArchiveError::InvalidConfig(err) => Some(err),
+            ArchiveError::InvalidBlockInfo(err) => Some(err),
+            ArchiveError::InvalidDebugInfo(err) => Some(err),
+            _ => None
+        }
+    }
 }
 
 pub struct Archive {
     pub block_info: Option<Arc<BlockInfo>>,
+    pub debug_info: Option<Arc<DebugInfo>>,
     pub files: HashMap<String, Box<[u8]>>,
     pub code: Box<[u8]>,
     pub conf: VMConfig,
 }
 
 impl Archive {
-    pub fn open(path: impl Into<String>) -> Archive {
-        let file = File::open(path.into());
-
-        if let Ok(file) = file {
-            let tar = GzDecoder::new(file);
-            let mut tar = Tar::new(tar);
-            let mut entries = tar
-                .entries()
-                .unwrap()
-                .map(|x| x.unwrap())
-                .fold(HashMap::new(), |mut acc, mut x| {
-                    acc.insert(x.path().unwrap().to_mut().clone().into_os_string().into_string().unwrap(), read_entry(&mut x));
-
-                    acc
-                });
-
-            Archive {
-                code: entries.remove("Main.bin").unwrap(),
-                conf: VMConfig::read(entries.remove("Conf.bin").unwrap()),
-                block_info: entries.remove("BlockInfo.bin").map(|buffer| BlockInfo::read(buffer)),
-                files: entries
+    pub fn open(path: impl Into<String>) -> Result<Archive, ArchiveError> {
+        Archive::read(File::open(path.into()).map_err(ArchiveError::Io)?)
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<Archive, ArchiveError> {
+        let mut compressed = Vec::new();
+        let mut buffer = Vec::new();
+
+        reader.read_to_end(&mut compressed).map_err(ArchiveError::Io)?;
+
+        GzDecoder::new(compressed.as_slice()).read_to_end(&mut buffer).map_err(ArchiveError::Gzip)?;
+
+        let mut tar = Tar::new(buffer.as_slice());
+        let mut entries = HashMap::new();
+
+        for entry in tar.entries().map_err(ArchiveError::Tar)? {
+            let mut entry = entry.map_err(ArchiveError::Tar)?;
+            let path = entry.path().map_err(ArchiveError::Tar)?.into_owned();
+            let name = path
+                .into_os_string()
+                .into_string()
+                .map_err(|x| ArchiveError::InvalidEntryName(x.to_string_lossy().into_owned()))?;
+
+            entries.insert(name, read_entry(&mut entry)?);
+        }
+
+        Ok(Archive {
+            code: entries.remove("Main.bin").ok_or(ArchiveError::MissingEntry("Main.bin"))?,
+            conf: VMConfig::read(entries.remove("Conf.bin").ok_or(ArchiveError::MissingEntry("Conf.bin"))?).map_err(ArchiveError::InvalidConfig)?,
+            block_info: entries.remove("BlockInfo.bin").map(BlockInfo::read).transpose().map_err(ArchiveError::InvalidBlockInfo)?,
+            debug_info: entries.remove("DebugInfo.bin").map(DebugInfo::read).transpose().map_err(ArchiveError::InvalidDebugInfo)?,
+            files: entries
+        })
+    }
+
+    pub fn new(code: Box<[u8]>, conf: VMConfig, block_info: Option<Arc<BlockInfo>>) -> Archive {
+        Archive { files: HashMap::new(), code, conf, block_info, debug_info: None }
+    }
+
+    // Adds every file below `dir` as an asset, named by its `/`-separated relative path
+    pub fn add_dir(&mut self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let mut pending = vec![dir.as_ref().to_path_buf()];
+
+        while let Some(current) = pending.pop() {
+            for entry in fs::read_dir(&current)? {
+                let path = entry?.path();
+
+                if path.is_dir() {
+                    pending.push(path);
+
+                    continue;
+                }
+
+                let name = path
+                    .strip_prefix(dir.as_ref())
+                    .unwrap()
+                    .components()
+                    .map(|x| x.as_os_str().to_str())
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("`{}` is not valid UTF-8.", path.display())))?
+                    .join("/");
+
+                if RESERVED_ENTRIES.contains(&name.as_str()) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Asset name `{name}` is reserved.")));
+                }
+
+                self.files.insert(name, fs::read(&path)?.into_boxed_slice());
             }
-        } else {
-            panic!("Cannot open file.")
         }
+
+        Ok(())
     }
-}
\ No newline at end of file
+
+    pub fn write(&self, writer: impl Write) -> io::Result<()> {
+        let mut tar = TarBuilder::new(GzEncoder::new(writer, Compression::default()));
+        let mut files = self.files.iter().collect::<Vec<_>>();
+
+        files.sort_unstable_by_key(|x| x.0);
+
+        append_entry(&mut tar, "Main.bin", &self.code)?;
+        append_entry(&mut tar, "Conf.bin", &self.conf.write())?;
+
+        if let Some(block_info) = &self.block_info {
+            append_entry(&mut tar, "BlockInfo.bin", &block_info.write())?;
+        }
+
+        if let Some(debug_info) = &self.debug_info {
+            append_entry(&mut tar, "DebugInfo.bin", &debug_info.write())?;
+        }
+
+        for (name, data) in files {
+            append_entry(&mut tar, name, data)?;
+        }
+
+        tar.into_inner()?.finish()?;
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.write(File::create(path)?)
+    }
+}
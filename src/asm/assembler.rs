@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+
+use crate::{op_codes::{OpCodes, OpLayout}, register::register_id, vm_intrinsics::intrinsics::Intrinsic};
+
+use super::{AsmError, CMP_TYPES};
+
+enum Section {
+    Data,
+    Code,
+}
+
+#[derive(Clone, Copy)]
+enum Label {
+    Data(u64),
+    Code(u64),
+}
+
+// How a code label is encoded: a word index for CALL/SPAWN, a byte offset for TRY,
+// and for JMP/JT the offset 8 bytes before the target, as execution continues after the encoded one
+enum Target {
+    Word,
+    Byte,
+    Jump,
+}
+
+enum Statement<'a> {
+    Str(Vec<u8>),
+    U64(u64),
+    Inst(&'a str, Vec<&'a str>),
+}
+
+struct Assembler<'a> {
+    labels: HashMap<&'a str, Label>,
+    code_start: u64,
+    line: usize,
+}
+
+fn error<T>(line: usize, message: impl Into<String>) -> Result<T, AsmError> {
+    Err(AsmError { line, message: message.into() })
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_str = false;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_str => escaped = true,
+            '"' => in_str = !in_str,
+            ';' if !in_str => return &line[..i],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+fn is_ident(value: &str) -> bool {
+    let mut chars = value.chars();
+
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub fn parse_uint(value: &str) -> Option<u64> {
+    let value = value.replace('_', "");
+
+    if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()
+    } else {
+        value.parse::<u64>().ok()
+    }
+}
+
+fn parse_string(line: usize, value: &str) -> Result<Vec<u8>, AsmError> {
+    let inner = match value.strip_prefix('"').and_then(|x| x.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return error(line, format!("Invalid string literal `{value}`.")),
+    };
+
+    let mut bytes = Vec::with_capacity(inner.len());
+    let mut chars = inner.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('"') => bytes.push(b'"'),
+            Some('x') => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 => bytes.push(byte),
+                    _ => return error(line, format!("Invalid escape sequence `\\x{hex}`.")),
+                }
+            }
+            Some(x) => return error(line, format!("Invalid escape sequence `\\{x}`.")),
+            None => return error(line, "Unterminated escape sequence."),
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn is_immediate(operand: &str) -> bool {
+    !operand.starts_with('[') && register_id(operand).is_none()
+}
+
+// `(f64) x`, `(u64) x` and `(i64) x`, plain integers are raw bits and anything else is read as f64
+pub fn parse_immediate(operand: &str) -> Result<u64, String> {
+    let invalid = || Err(format!("Invalid immediate `{operand}`."));
+
+    if let Some(rest) = operand.strip_prefix('(') && let Some((kind, value)) = rest.split_once(')') {
+        let value = value.trim();
+
+        return match kind.trim() {
+            "f64" => value.parse::<f64>().map(f64::to_bits).or_else(|_| invalid()),
+            "u64" => parse_uint(value).map_or_else(invalid, Ok),
+            "i64" => value.parse::<i64>().map(|x| x as u64).or_else(|_| invalid()),
+            _ => Err(format!("Unknown immediate type `{}`.", kind.trim())),
+        };
+    }
+
+    if let Some(value) = parse_uint(operand) {
+        Ok(value)
+    } else if let Ok(value) = operand.parse::<f64>() {
+        Ok(value.to_bits())
+    } else {
+        invalid()
+    }
+}
+
+fn inst(op: u8, a: u8, b: u8, c: u8, imm: u32) -> [u8; 8] {
+    let imm = imm.to_le_bytes();
+
+    [op, a, b, c, imm[0], imm[1], imm[2], imm[3]]
+}
+
+impl<'a> Assembler<'a> {
+    fn register(&self, operand: &str) -> Result<u8, AsmError> {
+        register_id(operand).map_or_else(|| error(self.line, format!("Unknown register `{operand}`.")), Ok)
+    }
+
+    fn immediate32(&self, operand: &str) -> Result<u32, AsmError> {
+        match parse_uint(operand) {
+            Some(x) if x <= u32::MAX as u64 => Ok(x as u32),
+            _ => error(self.line, format!("Invalid 32-bit immediate `{operand}`.")),
+        }
+    }
+
+    fn immediate64(&self, operand: &str) -> Result<u64, AsmError> {
+        parse_immediate(operand).or_else(|message| error(self.line, message))
+    }
+
+    fn offset(&self, operand: &str) -> Result<u32, AsmError> {
+        match self.labels.get(operand) {
+            Some(&Label::Data(word)) => Ok(word as u32),
+            Some(&Label::Code(offset)) => Ok(((self.code_start + offset) / 8) as u32),
+            None if is_ident(operand) => error(self.line, format!("Unknown label `{operand}`.")),
+            None => self.immediate32(operand),
+        }
+    }
+
+    // `[reg]`, `[reg+offset]` or `[reg+reg+offset]` when `index` is set
+    fn memory(&self, operand: &str, index: bool) -> Result<(u8, u8, u32), AsmError> {
+        let inner = match operand.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+            Some(inner) => inner,
+            None => return error(self.line, format!("Expected memory operand, found `{operand}`.")),
+        };
+
+        let mut parts = inner.split('+').map(str::trim);
+        let base = self.register(parts.next().unwrap_or_default())?;
+        let index = if index { self.register(parts.next().unwrap_or_default())? } else { 0 };
+        let offset = parts.next().map_or(Ok(0), |x| self.offset(x))?;
+
+        if let Some(extra) = parts.next() {
+            return error(self.line, format!("Unexpected `{extra}` in memory operand."));
+        }
+
+        Ok((base, index, offset))
+    }
+
+    fn target(&self, operand: &str, kind: Target) -> Result<u32, AsmError> {
+        match self.labels.get(operand) {
+            Some(&Label::Code(offset)) => {
+                let addr = self.code_start + offset;
+
+                Ok(match kind {
+                    Target::Word => addr / 8,
+                    Target::Byte => addr,
+                    Target::Jump => addr - 8,
+                } as u32)
+            }
+            Some(Label::Data(_)) => error(self.line, format!("`{operand}` is a data label, expected a code label.")),
+            None if is_ident(operand) => error(self.line, format!("Unknown label `{operand}`.")),
+            None => self.immediate32(operand),
+        }
+    }
+
+    fn encode(&self, mnemonic: &str, operands: &[&str], out: &mut Vec<u8>) -> Result<(), AsmError> {
+        let op = match OpCodes::from_name(mnemonic) {
+            Some(op) => op,
+            None => return error(self.line, format!("Unknown instruction `{mnemonic}`.")),
+        };
+
+        let expected = match op {
+            OpCodes::END | OpCodes::RET | OpCodes::ENDTRY => 0,
+            OpCodes::CALL | OpCodes::JT | OpCodes::JMP | OpCodes::SPAWN | OpCodes::PUSHR
+                | OpCodes::PUSHI | OpCodes::POP | OpCodes::INT | OpCodes::DROP | OpCodes::TRY | OpCodes::THROW => 1,
+            OpCodes::CMP | OpCodes::ELEM => 3,
+            _ => 2,
+        };
+
+        // `spawn target, reg` also stores the new thread's id
+        let optional = op == OpCodes::SPAWN && operands.len() == 2;
+
+        if operands.len() != expected && !optional {
+            return error(self.line, format!("`{mnemonic}` expects {expected} operand(s), found {}.", operands.len()));
+        }
+
+        let (bytes, immediate) = match op {
+            OpCodes::MOV => {
+                let (dest, src) = (operands[0], operands[1]);
+
+                match (dest.starts_with('['), src.starts_with('['), is_immediate(src)) {
+                    (false, false, false) => (inst(op | OpLayout::R_R, self.register(dest)?, self.register(src)?, 0, 0), None),
+                    (false, true, _) => {
+                        let (base, _, offset) = self.memory(src, false)?;
+
+                        (inst(op | OpLayout::R_RO, self.register(dest)?, base, 0, offset), None)
+                    }
+                    (true, false, false) => {
+                        let (base, _, offset) = self.memory(dest, false)?;
+
+                        (inst(op | OpLayout::RO_R, base, self.register(src)?, 0, offset), None)
+                    }
+                    (false, false, true) => (inst(op | OpLayout::R_I, self.register(dest)?, 0, 0, 0), Some(self.immediate64(src)?)),
+                    (true, false, true) => {
+                        let (base, _, offset) = self.memory(dest, false)?;
+
+                        (inst(op | OpLayout::RO_I, base, 0, 0, offset), Some(self.immediate64(src)?))
+                    }
+                    (true, true, _) => return error(self.line, "Memory to memory `mov` is not supported."),
+                }
+            }
+            OpCodes::LSTR | OpCodes::LEA1 => {
+                let (base, _, offset) = self.memory(operands[1], false)?;
+
+                (inst(op, self.register(operands[0])?, base, 0, offset), None)
+            }
+            OpCodes::LEA2 => {
+                let (base, index, offset) = self.memory(operands[1], true)?;
+
+                (inst(op, self.register(operands[0])?, base, index, offset), None)
+            }
+            OpCodes::ELEM => {
+                let (base, _, offset) = self.memory(operands[1], false)?;
+
+                (inst(op, self.register(operands[0])?, base, self.register(operands[2])?, offset), None)
+            }
+            OpCodes::ADD | OpCodes::SUB | OpCodes::MUL | OpCodes::DIV | OpCodes::IDIV | OpCodes::REM => {
+                (inst(op, self.register(operands[0])?, self.register(operands[1])?, 0, 0), None)
+            }
+            OpCodes::CMP => {
+                let cmp_type = match CMP_TYPES.iter().position(|x| x.eq_ignore_ascii_case(operands[2])) {
+                    Some(x) => x as u8,
+                    None => match parse_uint(operands[2]) {
+                        Some(x) if (x as usize) < CMP_TYPES.len() => x as u8,
+                        _ => return error(self.line, format!("Unknown comparison `{}`.", operands[2])),
+                    }
+                };
+
+                (inst(op, self.register(operands[0])?, self.register(operands[1])?, cmp_type, 0), None)
+            }
+            OpCodes::CALL => (inst(op, 0, 0, 0, self.target(operands[0], Target::Word)?), None),
+            OpCodes::SPAWN => {
+                let reg = operands.get(1).map_or(Ok(0), |x| self.register(x))?;
+
+                (inst(op, reg, 0, 0, self.target(operands[0], Target::Word)?), None)
+            }
+            OpCodes::JT | OpCodes::JMP => (inst(op, 0, 0, 0, self.target(operands[0], Target::Jump)?), None),
+            OpCodes::TRY => (inst(op, 0, 0, 0, self.target(operands[0], Target::Byte)?), None),
+            OpCodes::PUSHR | OpCodes::POP | OpCodes::DROP | OpCodes::THROW => (inst(op, self.register(operands[0])?, 0, 0, 0), None),
+            OpCodes::PUSHI => (inst(op, 0, 0, 0, 0), Some(self.immediate64(operands[0])?)),
+            OpCodes::INT => {
+                let id = match Intrinsic::from_name(operands[0]) {
+                    Some(id) => id,
+                    None => match parse_uint(operands[0]) {
+                        Some(x) if x <= u8::MAX as u64 => x as u8,
+                        _ => return error(self.line, format!("Unknown intrinsic `{}`.", operands[0])),
+                    }
+                };
+
+                (inst(op, id, 0, 0, 0), None)
+            }
+            OpCodes::ENV | OpCodes::ENVJ => {
+                let extension_id = self.immediate32(operands[0])?;
+
+                if extension_id > 0x00FFFFFF {
+                    return error(self.line, format!("Extension id `{}` does not fit in 24 bits.", operands[0]));
+                }
+
+                // The executor reads the id from bytes 0..3, so its low byte is the opcode
+                if extension_id & 0xFF != op as u32 {
+                    return error(self.line, format!("Extension id `{}` must end in the opcode byte 0x{op:02X}.", operands[0]));
+                }
+
+                let id = extension_id.to_le_bytes();
+
+                (inst(op, id[1], id[2], 0, self.immediate32(operands[1])?), None)
+            }
+            OpCodes::SUB32 | OpCodes::ADD32 => (inst(op, self.register(operands[0])?, 0, 0, self.immediate32(operands[1])?), None),
+            _ => (inst(op, 0, 0, 0, 0), None),
+        };
+
+        out.extend_from_slice(&bytes);
+
+        if let Some(immediate) = immediate {
+            out.extend_from_slice(&immediate.to_le_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+fn instruction_size(mnemonic: &str, operands: &[&str]) -> u64 {
+    match OpCodes::from_name(mnemonic) {
+        Some(OpCodes::PUSHI) => 16,
+        Some(OpCodes::MOV) if operands.len() == 2 && is_immediate(operands[1]) => 16,
+        _ => 8,
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Box<[u8]>, AsmError> {
+    let mut labels = HashMap::new();
+    let mut statements = Vec::new();
+    let mut section = Section::Code;
+    let mut data_words = 1u64;
+    let mut code_size = 0u64;
+
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i + 1;
+        let mut line = strip_comment(line).trim();
+
+        while let Some((label, rest)) = line.split_once(':') && is_ident(label.trim()) {
+            let label = label.trim();
+            let value = match section {
+                Section::Data => Label::Data(data_words),
+                Section::Code => Label::Code(code_size),
+            };
+
+            if labels.insert(label, value).is_some() {
+                return error(line_no, format!("Duplicated label `{label}`."));
+            }
+
+            line = rest.trim();
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let (head, rest) = line.split_once(char::is_whitespace).map_or((line, ""), |(x, y)| (x, y.trim()));
+
+        match (head.to_ascii_lowercase().as_str(), &section) {
+            (".data", _) => section = Section::Data,
+            (".code", _) => section = Section::Code,
+            (".str", Section::Data) => {
+                let bytes = parse_string(line_no, rest)?;
+
+                data_words += 1 + (bytes.len() as u64 + 7) / 8;
+                statements.push((line_no, Statement::Str(bytes)));
+            }
+            (".f64", Section::Data) => match rest.parse::<f64>() {
+                Ok(value) => {
+                    data_words += 1;
+                    statements.push((line_no, Statement::U64(value.to_bits())));
+                }
+                Err(_) => return error(line_no, format!("Invalid float `{rest}`.")),
+            },
+            (".u64", Section::Data) => match parse_uint(rest) {
+                Some(value) => {
+                    data_words += 1;
+                    statements.push((line_no, Statement::U64(value)));
+                }
+                None => return error(line_no, format!("Invalid integer `{rest}`.")),
+            },
+            (x, _) if x.starts_with('.') => return error(line_no, format!("Unknown or misplaced directive `{head}`.")),
+            (_, Section::Data) => return error(line_no, "Instructions are only allowed in `.code` section."),
+            (_, Section::Code) => {
+                let operands = if rest.is_empty() { Vec::new() } else { rest.split(',').map(str::trim).collect::<Vec<_>>() };
+
+                code_size += instruction_size(head, &operands);
+                statements.push((line_no, Statement::Inst(head, operands)));
+            }
+        }
+    }
+
+    let mut assembler = Assembler { labels, code_start: data_words * 8, line: 0 };
+    let mut data = Vec::with_capacity(data_words as usize * 8);
+    let mut code = Vec::with_capacity(code_size as usize);
+
+    data.extend_from_slice(&(data_words - 1).to_le_bytes());
+
+    for (line, statement) in statements {
+        assembler.line = line;
+
+        match statement {
+            Statement::Str(bytes) => {
+                data.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+                data.extend_from_slice(&bytes);
+                data.resize((data.len() + 7) / 8 * 8, 0);
+            }
+            Statement::U64(value) => data.extend_from_slice(&value.to_le_bytes()),
+            Statement::Inst(mnemonic, operands) => assembler.encode(mnemonic, &operands, &mut code)?,
+        }
+    }
+
+    data.extend_from_slice(&code);
+
+    Ok(data.into_boxed_slice())
+}
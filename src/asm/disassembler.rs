@@ -0,0 +1,225 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt::Write};
+
+use crate::{op_codes::{OpCodes, OpLayout}, register::register_name, vm_intrinsics::intrinsics::Intrinsic};
+
+use super::{Instruction, CMP_TYPES, sections};
+
+enum Data {
+    Str(String),
+    F64(u64),
+}
+
+struct Labels {
+    data: HashMap<u64, String>,
+    code: BTreeMap<u64, String>,
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\0' => escaped.push_str("\\0"),
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            c if c.is_control() && (c as u32) < 0x80 => { let _ = write!(escaped, "\\x{:02x}", c as u32); }
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
+
+// `{:?}` prints the shortest representation that parses back to the same value
+fn immediate(bits: u64) -> String {
+    let value = f64::from_bits(bits);
+    let text = format!("{value:?}");
+
+    if text.parse::<f64>().map(f64::to_bits) == Ok(bits) {
+        format!("(f64) {text}")
+    } else {
+        format!("0x{bits:X}")
+    }
+}
+
+fn reg(idx: u8) -> Option<String> {
+    register_name(idx).map(str::to_ascii_lowercase)
+}
+
+impl Labels {
+    fn offset(&self, base: u8, offset: u32) -> String {
+        match self.data.get(&(offset as u64)) {
+            Some(label) if base == 1 => label.clone(),
+            _ => offset.to_string()
+        }
+    }
+
+    fn memory(&self, base: u8, offset: u32) -> Option<String> {
+        Some(match offset {
+            0 => format!("[{}]", reg(base)?),
+            _ => format!("[{}+{}]", reg(base)?, self.offset(base, offset)),
+        })
+    }
+
+    fn target(&self, inst: &Instruction) -> String {
+        match inst.target().and_then(|x| self.code.get(&x)) {
+            Some(label) => label.clone(),
+            None => inst.imm32.to_string()
+        }
+    }
+
+    fn format(&self, inst: &Instruction) -> Option<String> {
+        let mnemonic = OpCodes::name(inst.opcode())?.to_ascii_lowercase();
+
+        let operands = match inst.op {
+            x if x == OpCodes::MOV | OpLayout::R_R => format!("{}, {}", reg(inst.a)?, reg(inst.b)?),
+            x if x == OpCodes::MOV | OpLayout::R_RO => format!("{}, {}", reg(inst.a)?, self.memory(inst.b, inst.imm32)?),
+            x if x == OpCodes::MOV | OpLayout::RO_R => format!("{}, {}", self.memory(inst.a, inst.imm32)?, reg(inst.b)?),
+            x if x == OpCodes::MOV | OpLayout::R_I => format!("{}, {}", reg(inst.a)?, immediate(inst.imm64?)),
+            x if x == OpCodes::MOV | OpLayout::RO_I => format!("{}, {}", self.memory(inst.a, inst.imm32)?, immediate(inst.imm64?)),
+            OpCodes::MOV => return None,
+            OpCodes::LSTR | OpCodes::LEA1 => format!("{}, {}", reg(inst.a)?, self.memory(inst.b, inst.imm32)?),
+            OpCodes::LEA2 => format!("{}, [{}+{}+{}]", reg(inst.a)?, reg(inst.b)?, reg(inst.c)?, inst.imm32),
+            OpCodes::ELEM => format!("{}, {}, {}", reg(inst.a)?, self.memory(inst.b, inst.imm32)?, reg(inst.c)?),
+            OpCodes::ADD | OpCodes::SUB | OpCodes::MUL | OpCodes::DIV | OpCodes::IDIV | OpCodes::REM => format!("{}, {}", reg(inst.a)?, reg(inst.b)?),
+            OpCodes::CMP => format!("{}, {}, {}", reg(inst.a)?, reg(inst.b)?, CMP_TYPES.get(inst.c as usize)?),
+            OpCodes::SPAWN if inst.a != 0 => format!("{}, {}", self.target(inst), reg(inst.a)?),
+            OpCodes::CALL | OpCodes::SPAWN | OpCodes::JT | OpCodes::JMP | OpCodes::TRY => self.target(inst),
+            OpCodes::PUSHR | OpCodes::POP | OpCodes::DROP | OpCodes::THROW => reg(inst.a)?,
+            OpCodes::PUSHI => immediate(inst.imm64?),
+            OpCodes::INT => Intrinsic::name(inst.a).map_or_else(|| inst.a.to_string(), String::from),
+            OpCodes::ENV | OpCodes::ENVJ => format!("{}, {}", inst.extension_id(), inst.imm32),
+            OpCodes::SUB32 | OpCodes::ADD32 => format!("{}, {}", reg(inst.a)?, inst.imm32),
+            OpCodes::END | OpCodes::RET | OpCodes::ENDTRY => String::new(),
+            _ => return None,
+        };
+
+        Some(if operands.is_empty() { mnemonic } else { format!("{mnemonic} {operands}") })
+    }
+}
+
+// Text of a single instruction, with plain numbers instead of labels
+pub fn format_instruction(inst: &Instruction) -> Option<String> {
+    Labels { data: HashMap::new(), code: BTreeMap::new() }.format(inst)
+}
+
+fn read_data(code: &[u8], words: u64, string_refs: &HashSet<u64>) -> BTreeMap<u64, Data> {
+    let word = |w: u64| u64::from_le_bytes(code[(w as usize * 8)..(w as usize * 8 + 8)].try_into().unwrap());
+    let mut data = BTreeMap::new();
+    let mut w = 1;
+
+    while w <= words {
+        let len = word(w);
+        let span = len.checked_add(7).map(|x| x / 8 + 1);
+
+        if let Some(span) = span && (len > 0 || string_refs.contains(&w)) && w + span - 1 <= words {
+            let bytes = &code[(w as usize * 8 + 8)..((w + span) as usize * 8)];
+
+            if let Ok(value) = std::str::from_utf8(&bytes[..len as usize]) && bytes[len as usize..].iter().all(|&x| x == 0) {
+                data.insert(w, Data::Str(value.to_owned()));
+
+                w += span;
+
+                continue;
+            }
+        }
+
+        data.insert(w, Data::F64(len));
+
+        w += 1;
+    }
+
+    data
+}
+
+pub fn disassemble(code: &[u8]) -> Result<String, String> {
+    let (words, start) = sections(code).ok_or_else(|| String::from("Main.bin is too short or its data length exceeds the file."))?;
+
+    let mut insts = Vec::new();
+    let mut offset = start;
+
+    while offset < code.len() {
+        match Instruction::decode(code, offset) {
+            Some(inst) => {
+                offset += inst.size();
+                insts.push(Ok(inst));
+            }
+            None => {
+                insts.push(Err(offset));
+
+                break;
+            }
+        }
+    }
+
+    let boundaries = insts.iter().filter_map(|x| x.as_ref().ok()).map(|x| x.offset as u64).collect::<HashSet<_>>();
+    let string_refs = insts
+        .iter()
+        .filter_map(|x| x.as_ref().ok())
+        .filter(|x| x.op == OpCodes::LSTR && x.b == 1)
+        .map(|x| x.imm32 as u64)
+        .collect::<HashSet<_>>();
+
+    let data = read_data(code, words, &string_refs);
+
+    let labels = Labels {
+        data: data.iter().map(|(&w, x)| (w, match x {
+            Data::Str(_) => format!("str_{w}"),
+            Data::F64(_) => format!("num_{w}"),
+        })).collect(),
+        code: insts
+            .iter()
+            .filter_map(|x| x.as_ref().ok().and_then(Instruction::target))
+            .filter(|x| boundaries.contains(x))
+            .map(|x| (x, format!("loc_{x:04X}")))
+            .collect(),
+    };
+
+    let mut out = String::new();
+
+    let _ = writeln!(out, "; {} data words, code section at 0x{start:04X}", words);
+
+    if !data.is_empty() {
+        let _ = writeln!(out, "\n.data");
+
+        for (w, x) in &data {
+            let _ = match x {
+                Data::Str(value) => writeln!(out, "{}: .str {}", labels.data[w], escape(value)),
+                Data::F64(bits) => match immediate(*bits).strip_prefix("(f64) ") {
+                    Some(value) => writeln!(out, "{}: .f64 {value}", labels.data[w]),
+                    None => writeln!(out, "{}: .u64 0x{bits:X}", labels.data[w]),
+                }
+            };
+        }
+    }
+
+    let _ = writeln!(out, "\n.code");
+
+    for inst in &insts {
+        match inst {
+            Ok(inst) => {
+                if let Some(label) = labels.code.get(&(inst.offset as u64)) {
+                    let _ = writeln!(out, "{label}:");
+                }
+
+                let bytes = &code[inst.offset..(inst.offset + 8)];
+
+                let _ = match labels.format(inst) {
+                    Some(text) => writeln!(out, "    {text:<40}; 0x{:04X}", inst.offset),
+                    None => writeln!(out, "    ; 0x{:04X}: invalid instruction {:02X?}", inst.offset, bytes),
+                };
+            }
+            Err(offset) => {
+                let _ = writeln!(out, "    ; 0x{offset:04X}: truncated instruction {:02X?}", &code[*offset..]);
+            }
+        }
+    }
+
+    Ok(out)
+}
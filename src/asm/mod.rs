@@ -0,0 +1,98 @@
+use std::{fmt::{self, Display}, error::Error};
+
+use crate::op_codes::{OpCodes, OpLayout};
+
+pub mod assembler;
+pub mod disassembler;
+
+pub use assembler::assemble;
+pub use disassembler::disassemble;
+
+pub const CMP_TYPES: [&str; 6] = ["eq", "ne", "lt", "lte", "gt", "gte"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+// Raw fields of an 8-byte instruction, followed by the 8-byte immediate of fat instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub op: u8,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub imm32: u32,
+    pub imm64: Option<u64>,
+}
+
+impl Instruction {
+    pub fn is_fat(op: u8) -> bool {
+        op == OpCodes::MOV | OpLayout::R_I || op == OpCodes::MOV | OpLayout::RO_I || op == OpCodes::PUSHI
+    }
+
+    pub fn decode(code: &[u8], offset: usize) -> Option<Instruction> {
+        let bytes = code.get(offset..offset.checked_add(8)?)?;
+        let imm64 = if Instruction::is_fat(bytes[0]) {
+            Some(u64::from_le_bytes(code.get((offset + 8)..(offset + 16))?.try_into().unwrap()))
+        } else {
+            None
+        };
+
+        Some(Instruction {
+            offset,
+            op: bytes[0],
+            a: bytes[1],
+            b: bytes[2],
+            c: bytes[3],
+            imm32: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            imm64,
+        })
+    }
+
+    // Opcode without the MOV layout bits
+    pub fn opcode(&self) -> u8 {
+        if self.op & 0x80 != 0 { self.op & 0x0F } else { self.op }
+    }
+
+    pub fn size(&self) -> usize {
+        if self.imm64.is_some() { 16 } else { 8 }
+    }
+
+    // ENV/ENVJ mask the opcode byte into the id, so its low byte is always the opcode
+    pub fn extension_id(&self) -> u32 {
+        u32::from_le_bytes([self.op, self.a, self.b, 0])
+    }
+
+    // Byte offset in Main.bin that JMP, JT, CALL and SPAWN transfer control to, or TRY's handler
+    pub fn target(&self) -> Option<u64> {
+        match self.op {
+            OpCodes::JMP | OpCodes::JT => Some(self.imm32 as u64 + 8),
+            OpCodes::TRY => Some(self.imm32 as u64),
+            OpCodes::CALL | OpCodes::SPAWN => Some(self.imm32 as u64 * 8),
+            _ => None
+        }
+    }
+}
+
+// Returns (data length in words, code section start) of Main.bin
+pub fn sections(code: &[u8]) -> Option<(u64, usize)> {
+    let words = u64::from_le_bytes(code.get(0..8)?.try_into().unwrap());
+    let start = words.checked_mul(8)?.checked_add(8)?;
+
+    if start > code.len() as u64 {
+        return None;
+    }
+
+    Some((words, start as usize))
+}
@@ -1,17 +1,48 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, fmt::{self, Display}, error::Error};
 
 use crate::utils::ReadBuffer;
 
+#[derive(Debug, PartialEq, Eq)]
 pub enum UnlockInfo {
     Current,
     Addr(u64)
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockInfoError {
+    InvalidSize { expected: usize, found: usize },
+    TooManyEntries(u64),
+}
+
+impl Display for BlockInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockInfoError::InvalidSize { expected, found } => write!(f, "Expected {expected} bytes, found {found} bytes."),
+            BlockInfoError::TooManyEntries(size) => write!(f, "Entry count {size} is too large."),
+        }
+    }
+}
+
+impl Error for BlockInfoError {}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct BlockInfo(HashMap<u64, UnlockInfo>);
 
 impl BlockInfo {
-    pub fn read(buffer: Box<[u8]>) -> Arc<BlockInfo> {
+    pub fn read(buffer: Box<[u8]>) -> Result<Arc<BlockInfo>, BlockInfoError> {
+        if buffer.len() < 8 {
+            return Err(BlockInfoError::InvalidSize { expected: 8, found: buffer.len() });
+        }
+
         let size = buffer.const_read::<0, u64>();
+        let expected = (size as usize).checked_mul(16).and_then(|x| x.checked_add(8));
+
+        match expected {
+            Some(expected) if expected != buffer.len() => return Err(BlockInfoError::InvalidSize { expected, found: buffer.len() }),
+            None => return Err(BlockInfoError::TooManyEntries(size)),
+            _ => {}
+        }
+
         let mut map = HashMap::new();
 
         for i in (0..size).map(|i| i as isize * 16 + 8) {
@@ -25,10 +56,34 @@ impl BlockInfo {
             }
         }
 
+        Ok(Arc::new(BlockInfo(map)))
+    }
+
+    pub fn new(map: HashMap<u64, UnlockInfo>) -> Arc<BlockInfo> {
         Arc::new(BlockInfo(map))
     }
 
+    pub fn write(&self) -> Box<[u8]> {
+        let mut entries = self.0.iter().map(|(&start, info)| (start, match info {
+            UnlockInfo::Current => start,
+            &UnlockInfo::Addr(end) => end,
+        })).collect::<Vec<_>>();
+
+        entries.sort_unstable();
+
+        let mut buffer = Vec::with_capacity(8 + entries.len() * 16);
+
+        buffer.extend_from_slice(&(entries.len() as u64).to_ne_bytes());
+
+        for (start, end) in entries {
+            buffer.extend_from_slice(&start.to_ne_bytes());
+            buffer.extend_from_slice(&end.to_ne_bytes());
+        }
+
+        buffer.into_boxed_slice()
+    }
+
     pub fn get(&self, inst: u64) -> Option<&UnlockInfo> {
         self.0.get(&inst)
     }
-}
\ No newline at end of file
+}
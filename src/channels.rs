@@ -0,0 +1,114 @@
+use std::{sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, collections::{HashMap, BTreeMap}, fmt::{self, Display}, error::Error};
+
+use tokio::sync::{mpsc::{self, UnboundedSender, UnboundedReceiver}, broadcast};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChannelError {
+    UnknownChannel(u64),
+}
+
+impl Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::UnknownChannel(id) => write!(f, "Unknown channel {id}."),
+        }
+    }
+}
+
+impl Error for ChannelError {}
+
+// Values are raw registers, a sent var string belongs to the receiver
+struct Channel {
+    tx: UnboundedSender<u64>,
+    rx: tokio::sync::Mutex<UnboundedReceiver<u64>>,
+}
+
+struct Signal {
+    // Code offsets spawned as new threads on every broadcast
+    handlers: Vec<u64>,
+    tx: broadcast::Sender<()>,
+}
+
+impl Signal {
+    fn new() -> Signal {
+        Signal { handlers: Vec::new(), tx: broadcast::channel(1).0 }
+    }
+}
+
+// Channels between virtual threads and named broadcast signals
+#[derive(Default)]
+pub struct Channels {
+    channels: Mutex<HashMap<u64, Arc<Channel>>>,
+    signals: Mutex<BTreeMap<String, Signal>>,
+    next_id: AtomicU64,
+}
+
+impl Channels {
+    pub fn new() -> Channels {
+        Channels::default()
+    }
+
+    fn get(&self, id: u64) -> Result<Arc<Channel>, ChannelError> {
+        self.channels.lock().unwrap().get(&id).cloned().ok_or(ChannelError::UnknownChannel(id))
+    }
+
+    pub fn create(&self) -> u64 {
+        // Handles start at 1
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        self.channels.lock().unwrap().insert(id, Arc::new(Channel { tx, rx: tokio::sync::Mutex::new(rx) }));
+
+        id
+    }
+
+    pub fn send(&self, id: u64, value: u64) -> Result<(), ChannelError> {
+        // The channel holds its own receiver, so it can't be closed
+        let _ = self.get(id)?.tx.send(value);
+
+        Ok(())
+    }
+
+    pub async fn recv(&self, id: u64) -> Result<u64, ChannelError> {
+        let channel = self.get(id)?;
+        let value = channel.rx.lock().await.recv().await.unwrap();
+
+        Ok(value)
+    }
+
+    // None if the channel is empty or another thread is receiving from it
+    pub fn try_recv(&self, id: u64) -> Result<Option<u64>, ChannelError> {
+        let channel = self.get(id)?;
+        let value = channel.rx.try_lock().ok().and_then(|mut rx| rx.try_recv().ok());
+
+        Ok(value)
+    }
+
+    pub fn listen(&self, name: &str, addr: u64) {
+        self.signals.lock().unwrap().entry(name.to_owned()).or_insert_with(Signal::new).handlers.push(addr);
+    }
+
+    pub async fn wait(&self, name: &str) {
+        let mut rx = self.signals.lock().unwrap().entry(name.to_owned()).or_insert_with(Signal::new).tx.subscribe();
+
+        let _ = rx.recv().await;
+    }
+
+    // Wakes the waiting threads and returns the handlers to spawn
+    pub fn broadcast(&self, name: &str) -> Vec<u64> {
+        match self.signals.lock().unwrap().get(name) {
+            Some(signal) => {
+                let _ = signal.tx.send(());
+
+                signal.handlers.clone()
+            }
+            None => Vec::new()
+        }
+    }
+
+    pub fn reset(&self) {
+        self.channels.lock().unwrap().clear();
+        self.signals.lock().unwrap().clear();
+        self.next_id.store(0, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,353 @@
+use std::path::Path;
+
+use crate::{vm_config::{VMConfig, ExecutorKind, ThreadingKind, ErrorPolicy}, trace::{TraceFormat, TraceFilter}, op_codes::OpCodes, asm::assembler::parse_uint};
+
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_ARCHIVE: i32 = 4;
+pub const EXIT_VERIFY: i32 = 5;
+pub const EXIT_EXTENSION: i32 = 6;
+
+pub const USAGE: &str = "\
+Usage: open-entry-vm [run] [OPTIONS] [CONFIG OPTIONS] <ARCHIVE>
+       open-entry-vm asm <SOURCE> [-o <OUTPUT>]
+       open-entry-vm disasm <INPUT> [-o <OUTPUT>]
+       open-entry-vm pack <MAIN.BIN> -o <OUTPUT> [--block-info <FILE>] [--debug-info <FILE>] [--assets <DIR>] [CONFIG OPTIONS]
+
+Commands:
+  run                            Run the .entx archive ARCHIVE (default)
+  asm                            Assemble SOURCE into Main.bin bytecode
+  disasm                         Disassemble INPUT (Main.bin or .entx archive)
+  pack                           Pack MAIN.BIN, BlockInfo.bin, DebugInfo.bin and assets in DIR into an .entx archive
+
+Options:
+      --ext <ID>=<PATH>          Load extension library PATH with extension id ID (repeatable)
+      --debug                    Stop at the first instruction and debug the program interactively
+      --dap                      Serve the Debug Adapter Protocol on standard input/output
+      --dap-port <PORT>          Serve the Debug Adapter Protocol to one client on 127.0.0.1:PORT
+      --trace <FILE>             Record every executed instruction to FILE
+      --trace-format <FORMAT>    Trace format [jsonl, binary] (default: jsonl)
+      --trace-range <START>:<END>
+                                 Only trace instructions at code offsets START..END
+      --trace-thread <ID>        Only trace virtual thread ID (repeatable)
+      --trace-op <OP>[,<OP>...]  Only trace the given opcodes (e.g. `call,ret`)
+      --profile <FILE>           Write a profile report of opcodes, functions, addresses and lock waits to FILE
+      --profile-folded <FILE>    Write the profiled call stacks to FILE as folded stacks for flamegraphs
+  -o, --output <OUTPUT>          Output path (asm: SOURCE with `.bin` extension, disasm: standard output)
+      --block-info <FILE>        BlockInfo.bin to include in the archive
+      --debug-info <FILE>        DebugInfo.bin to include in the archive
+      --assets <DIR>             Directory of asset files to include in the archive
+  -h, --help                     Print help
+  -V, --version                  Print version
+
+Config Options:
+      --executor-kind <KIND>     Executor kind
+                                 [atomic, sys-lock-inst, spin-lock-inst, sys-lock-block, spin-lock-block]
+      --threading-kind <KIND>    Threading kind [single, managed, unmanaged]
+      --max-threads <N>          Worker thread count (0 = number of CPUs)
+      --stack-size <BYTES>       Stack size of each virtual thread
+      --bounds-check             Validate every guest memory access (slower)
+      --max-call-depth <N>       Maximum nested CALL depth of each virtual thread (0 = unlimited)
+      --error-policy <POLICY>    What an uncaught error in a virtual thread stops
+                                 [shutdown (the whole VM), isolate (only the thread)]
+
+Exit Codes:
+  0  VM shut down gracefully
+  1  VM stopped with an error
+  2  Invalid command-line usage
+  3  VM stopped with unknown reason
+  4  Archive could not be loaded
+  5  Main.bin failed bytecode verification
+  6  Extension library could not be loaded";
+
+pub enum Command {
+    Run(RunOptions),
+    Assemble { source: String, output: String },
+    Disassemble { input: String, output: Option<String> },
+    Pack(PackOptions),
+    Help,
+    Version,
+}
+
+#[derive(Default)]
+pub struct ConfigOverrides {
+    pub executor_kind: Option<ExecutorKind>,
+    pub threading_kind: Option<ThreadingKind>,
+    pub max_threads: Option<u16>,
+    pub stack_size: Option<u64>,
+    pub bounds_check: Option<bool>,
+    pub max_call_depth: Option<u32>,
+    pub error_policy: Option<ErrorPolicy>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugMode {
+    Repl,
+    DapStdio,
+    DapTcp(u16),
+}
+
+pub struct TraceOptions {
+    pub output: String,
+    pub format: TraceFormat,
+    pub filter: TraceFilter,
+}
+
+pub struct RunOptions {
+    pub archive: String,
+    pub extensions: Vec<(u32, String)>,
+    pub conf: ConfigOverrides,
+    pub debug: Option<DebugMode>,
+    pub trace: Option<TraceOptions>,
+    pub profile: Option<String>,
+    pub profile_folded: Option<String>,
+}
+
+pub struct PackOptions {
+    pub code: String,
+    pub output: String,
+    pub block_info: Option<String>,
+    pub debug_info: Option<String>,
+    pub assets: Option<String>,
+    pub conf: ConfigOverrides,
+}
+
+type ValueFn<'a> = &'a mut dyn FnMut() -> Result<String, String>;
+
+// Help and version stop parsing where they appear as an option, not as the value of one
+enum ParseError {
+    Stop(Command),
+    Invalid(String),
+}
+
+impl From<String> for ParseError {
+    fn from(message: String) -> ParseError {
+        ParseError::Invalid(message)
+    }
+}
+
+impl ConfigOverrides {
+    pub fn apply(&self, conf: &mut VMConfig) {
+        if let Some(executor_kind) = self.executor_kind {
+            conf.executor_kind = executor_kind;
+        }
+
+        if let Some(threading_kind) = self.threading_kind {
+            conf.threading_kind = threading_kind;
+        }
+
+        if let Some(max_threads) = self.max_threads {
+            conf.max_threads = max_threads;
+        }
+
+        if let Some(stack_size) = self.stack_size {
+            conf.stack_size = stack_size;
+        }
+
+        if let Some(bounds_check) = self.bounds_check {
+            conf.bounds_check = bounds_check;
+        }
+
+        if let Some(max_call_depth) = self.max_call_depth {
+            conf.max_call_depth = max_call_depth;
+        }
+
+        if let Some(error_policy) = self.error_policy {
+            conf.error_policy = error_policy;
+        }
+    }
+
+    // Returns false if `option` is not a config option
+    fn parse(&mut self, option: &str, value: ValueFn) -> Result<bool, String> {
+        match option {
+            "--executor-kind" => self.executor_kind = Some(value()?.parse()?),
+            "--threading-kind" => self.threading_kind = Some(value()?.parse()?),
+            "--max-threads" => self.max_threads = Some(parse_number(option, &value()?)?),
+            "--stack-size" => {
+                let stack_size = parse_number::<u64>(option, &value()?)?;
+
+                if stack_size == 0 || stack_size % 8 != 0 {
+                    return Err(format!("Stack size {stack_size} must be a non-zero multiple of 8."));
+                }
+
+                self.stack_size = Some(stack_size);
+            }
+            "--bounds-check" => self.bounds_check = Some(true),
+            "--max-call-depth" => self.max_call_depth = Some(parse_number(option, &value()?)?),
+            "--error-policy" => self.error_policy = Some(value()?.parse()?),
+            _ => return Ok(false)
+        }
+
+        Ok(true)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value.parse::<T>().map_err(|_| format!("Invalid value `{value}` for `{option}`."))
+}
+
+fn parse_extension(value: &str) -> Result<(u32, String), String> {
+    if let Some(i) = value.find('=') && i + 1 < value.len() {
+        Ok((parse_number("--ext", &value[0..i])?, value[(i + 1)..].to_owned()))
+    } else {
+        Err(format!("Invalid extension `{value}`, expected `<ID>=<PATH>`."))
+    }
+}
+
+// Feeds every option to `handle` and returns the positional arguments
+fn parse_args(args: Vec<String>, mut handle: impl FnMut(&str, ValueFn) -> Result<bool, String>) -> Result<Vec<String>, ParseError> {
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let (option, inline_value) = match arg.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => (option.to_owned(), Some(value.to_owned())),
+            _ => (arg.clone(), None)
+        };
+
+        if !option.starts_with('-') || option.len() == 1 {
+            positional.push(arg);
+
+            continue;
+        }
+
+        match option.as_str() {
+            "-h" | "--help" => return Err(ParseError::Stop(Command::Help)),
+            "-V" | "--version" => return Err(ParseError::Stop(Command::Version)),
+            _ => {}
+        }
+
+        let mut used = false;
+        let mut value = || {
+            used = true;
+
+            inline_value.clone().or_else(|| args.next()).ok_or_else(|| format!("Missing value for `{option}`."))
+        };
+
+        if !handle(&option, &mut value)? {
+            return Err(format!("Unknown option `{option}`.").into());
+        }
+
+        // e.g. `--bounds-check=false` would otherwise turn bounds checking on
+        if inline_value.is_some() && !used {
+            return Err(format!("Option `{option}` does not take a value.").into());
+        }
+    }
+
+    Ok(positional)
+}
+
+fn parse_trace_range(value: &str) -> Result<(u64, u64), String> {
+    match value.split_once(':').map(|(start, end)| (parse_uint(start), parse_uint(end))) {
+        Some((Some(start), Some(end))) if start < end => Ok((start, end)),
+        _ => Err(format!("Invalid trace range `{value}`, expected `<START>:<END>`."))
+    }
+}
+
+fn parse_opcodes(value: &str) -> Result<Vec<u8>, String> {
+    value.split(',').map(|x| OpCodes::from_name(x.trim()).ok_or_else(|| format!("Unknown opcode `{x}`."))).collect()
+}
+
+fn single(positional: Vec<String>, name: &str) -> Result<String, String> {
+    let mut positional = positional.into_iter();
+
+    match (positional.next(), positional.next()) {
+        (Some(x), None) => Ok(x),
+        (None, _) => Err(format!("Missing {name}.")),
+        (_, Some(x)) => Err(format!("Unexpected argument `{x}`.")),
+    }
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    match parse_command(args.into_iter().collect()) {
+        Ok(command) | Err(ParseError::Stop(command)) => Ok(command),
+        Err(ParseError::Invalid(message)) => Err(message)
+    }
+}
+
+fn parse_command(mut args: Vec<String>) -> Result<Command, ParseError> {
+    let command = match args.first().map(String::as_str) {
+        Some("run" | "asm" | "disasm" | "pack") => args.remove(0),
+        _ => String::from("run")
+    };
+
+    let mut output = None;
+    let mut output_option = |option: &str, value: ValueFn| Ok(match option {
+        "-o" | "--output" => {
+            output = Some(value()?);
+
+            true
+        }
+        _ => false
+    });
+
+    match command.as_str() {
+        "asm" => {
+            let source = single(parse_args(args, &mut output_option)?, "source path")?;
+
+            Ok(Command::Assemble {
+                output: output.unwrap_or_else(|| Path::new(&source).with_extension("bin").to_string_lossy().into_owned()),
+                source,
+            })
+        }
+        "disasm" => {
+            let input = single(parse_args(args, &mut output_option)?, "input path")?;
+
+            Ok(Command::Disassemble { input, output })
+        }
+        "pack" => {
+            let mut conf = ConfigOverrides::default();
+            let mut block_info = None;
+            let mut debug_info = None;
+            let mut assets = None;
+
+            let code = single(parse_args(args, |option, value| Ok(match option {
+                "--block-info" => { block_info = Some(value()?); true }
+                "--debug-info" => { debug_info = Some(value()?); true }
+                "--assets" => { assets = Some(value()?); true }
+                _ => output_option(option, value)? || conf.parse(option, value)?
+            }))?, "Main.bin path")?;
+
+            Ok(Command::Pack(PackOptions {
+                output: output.ok_or_else(|| String::from("Missing output path (`-o <OUTPUT>`)."))?,
+                code,
+                block_info,
+                debug_info,
+                assets,
+                conf,
+            }))
+        }
+        _ => {
+            let mut conf = ConfigOverrides::default();
+            let mut extensions = Vec::new();
+            let mut debug = None;
+            let mut trace_output = None;
+            let mut trace_format = None;
+            let mut filter = TraceFilter::default();
+            let mut profile = None;
+            let mut profile_folded = None;
+
+            let archive = single(parse_args(args, |option, value| Ok(match option {
+                "--ext" => { extensions.push(parse_extension(&value()?)?); true }
+                "--debug" => { debug = Some(DebugMode::Repl); true }
+                "--dap" => { debug = Some(DebugMode::DapStdio); true }
+                "--dap-port" => { debug = Some(DebugMode::DapTcp(parse_number(option, &value()?)?)); true }
+                "--trace" => { trace_output = Some(value()?); true }
+                "--trace-format" => { trace_format = Some(value()?.parse()?); true }
+                "--trace-range" => { filter.range = Some(parse_trace_range(&value()?)?); true }
+                "--trace-thread" => { filter.threads.insert(parse_number(option, &value()?)?); true }
+                "--trace-op" => { filter.opcodes.extend(parse_opcodes(&value()?)?); true }
+                "--profile" => { profile = Some(value()?); true }
+                "--profile-folded" => { profile_folded = Some(value()?); true }
+                _ => conf.parse(option, value)?
+            }))?, "archive path")?;
+
+            let trace = match trace_output {
+                Some(output) => Some(TraceOptions { output, format: trace_format.unwrap_or(TraceFormat::JsonLines), filter }),
+                None if trace_format.is_some() || filter != TraceFilter::default() => return Err(String::from("Trace options require `--trace <FILE>`.").into()),
+                None => None
+            };
+
+            Ok(Command::Run(RunOptions { archive, extensions, conf, debug, trace, profile, profile_folded }))
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::{sync::Arc, fmt::{self, Display}, error::Error, collections::HashMap};
+
+// DebugInfo.bin layout (native endian)
+// u64 string count, per string: u64 length, UTF-8 bytes
+// u64 range count, per range: u64 start, u64 end, u32 block id, u32 function name (string index, u32::MAX = none)
+// u64 variable count, per variable: u64 start, u64 end, u8 kind (0 = register, 1 = stack slot), u8 (reserved), i16 location, u32 name (string index)
+const NO_FUNCTION: u32 = u32::MAX;
+const KIND_REGISTER: u8 = 0;
+const KIND_STACK: u8 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DebugInfoError {
+    Truncated(usize),
+    InvalidString(u64),
+    UnknownString(u32),
+    UnknownKind(u8),
+}
+
+impl Display for DebugInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DebugInfoError::Truncated(offset) => write!(f, "Unexpected end of file at byte {offset}."),
+            DebugInfoError::InvalidString(index) => write!(f, "String {index} is not valid UTF-8."),
+            DebugInfoError::UnknownString(index) => write!(f, "String index {index} is out of range."),
+            DebugInfoError::UnknownKind(kind) => write!(f, "Unknown variable kind {kind}."),
+        }
+    }
+}
+
+impl Error for DebugInfoError {}
+
+// Instructions in `start..end` belong to a source block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceRange {
+    pub start: u64,
+    pub end: u64,
+    pub block: u32,
+    pub function: Option<String>,
+}
+
+impl Display for SourceRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(function) => write!(f, "in function `{function}`, block #{}", self.block),
+            None => write!(f, "in block #{}", self.block),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarLocation {
+    Register(u8),
+    // Word at FUNC - slot * 8, negative slots are arguments pushed by the caller
+    Stack(i16),
+}
+
+// A named register or stack slot, live while the instruction is in `start..end`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub start: u64,
+    pub end: u64,
+    pub location: VarLocation,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DebugInfo {
+    ranges: Vec<SourceRange>,
+    variables: Vec<Variable>,
+}
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DebugInfoError> {
+        let bytes = self.offset.checked_add(len).and_then(|end| self.buffer.get(self.offset..end)).ok_or(DebugInfoError::Truncated(self.offset))?;
+
+        self.offset += len;
+
+        Ok(bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, DebugInfoError> {
+        Ok(u64::from_ne_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, DebugInfoError> {
+        Ok(u32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn i16(&mut self) -> Result<i16, DebugInfoError> {
+        Ok(i16::from_ne_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u8(&mut self) -> Result<u8, DebugInfoError> {
+        Ok(self.bytes(1)?[0])
+    }
+}
+
+impl DebugInfo {
+    pub fn read(buffer: Box<[u8]>) -> Result<Arc<DebugInfo>, DebugInfoError> {
+        let mut reader = Reader { buffer: &buffer, offset: 0 };
+        let mut strings = Vec::new();
+
+        for i in 0..reader.u64()? {
+            let len = reader.u64()?;
+            let bytes = reader.bytes(usize::try_from(len).map_err(|_| DebugInfoError::Truncated(reader.offset))?)?;
+
+            strings.push(String::from_utf8(bytes.to_vec()).map_err(|_| DebugInfoError::InvalidString(i))?);
+        }
+
+        let string = |index: u32| strings.get(index as usize).cloned().ok_or(DebugInfoError::UnknownString(index));
+        let mut ranges = Vec::new();
+
+        for _ in 0..reader.u64()? {
+            let (start, end, block) = (reader.u64()?, reader.u64()?, reader.u32()?);
+            let function = match reader.u32()? {
+                NO_FUNCTION => None,
+                index => Some(string(index)?)
+            };
+
+            ranges.push(SourceRange { start, end, block, function });
+        }
+
+        let mut variables = Vec::new();
+
+        for _ in 0..reader.u64()? {
+            let (start, end, kind) = (reader.u64()?, reader.u64()?, reader.u8()?);
+
+            reader.u8()?;
+
+            let location = match (kind, reader.i16()?) {
+                (KIND_REGISTER, reg) => VarLocation::Register(reg as u8),
+                (KIND_STACK, slot) => VarLocation::Stack(slot),
+                (kind, _) => return Err(DebugInfoError::UnknownKind(kind))
+            };
+
+            variables.push(Variable { start, end, location, name: string(reader.u32()?)? });
+        }
+
+        if reader.offset != buffer.len() {
+            return Err(DebugInfoError::Truncated(reader.offset));
+        }
+
+        Ok(DebugInfo::new(ranges, variables))
+    }
+
+    pub fn new(mut ranges: Vec<SourceRange>, variables: Vec<Variable>) -> Arc<DebugInfo> {
+        ranges.sort_by_key(|x| x.start);
+
+        Arc::new(DebugInfo { ranges, variables })
+    }
+
+    pub fn write(&self) -> Box<[u8]> {
+        let mut strings = Vec::new();
+        let mut indices = HashMap::new();
+        let mut index = |name: &str| *indices.entry(name.to_owned()).or_insert_with(|| {
+            strings.push(name.to_owned());
+
+            strings.len() as u32 - 1
+        });
+
+        let ranges = self.ranges.iter().map(|x| (x, x.function.as_deref().map_or(NO_FUNCTION, &mut index))).collect::<Vec<_>>();
+        let variables = self.variables.iter().map(|x| (x, index(&x.name))).collect::<Vec<_>>();
+
+        let mut buffer = Vec::new();
+
+        buffer.extend_from_slice(&(strings.len() as u64).to_ne_bytes());
+
+        for string in &strings {
+            buffer.extend_from_slice(&(string.len() as u64).to_ne_bytes());
+            buffer.extend_from_slice(string.as_bytes());
+        }
+
+        buffer.extend_from_slice(&(ranges.len() as u64).to_ne_bytes());
+
+        for (range, function) in ranges {
+            buffer.extend_from_slice(&range.start.to_ne_bytes());
+            buffer.extend_from_slice(&range.end.to_ne_bytes());
+            buffer.extend_from_slice(&range.block.to_ne_bytes());
+            buffer.extend_from_slice(&function.to_ne_bytes());
+        }
+
+        buffer.extend_from_slice(&(variables.len() as u64).to_ne_bytes());
+
+        for (variable, name) in variables {
+            let (kind, location) = match variable.location {
+                VarLocation::Register(reg) => (KIND_REGISTER, reg as i16),
+                VarLocation::Stack(slot) => (KIND_STACK, slot),
+            };
+
+            buffer.extend_from_slice(&variable.start.to_ne_bytes());
+            buffer.extend_from_slice(&variable.end.to_ne_bytes());
+            buffer.extend_from_slice(&[kind, 0]);
+            buffer.extend_from_slice(&location.to_ne_bytes());
+            buffer.extend_from_slice(&name.to_ne_bytes());
+        }
+
+        buffer.into_boxed_slice()
+    }
+
+    // Source range containing the instruction at `inst`
+    pub fn range(&self, inst: u64) -> Option<&SourceRange> {
+        let idx = self.ranges.partition_point(|x| x.start <= inst);
+
+        self.ranges[..idx].iter().rev().find(|x| inst < x.end)
+    }
+
+    // First instruction of a block
+    pub fn block_start(&self, block: u32) -> Option<u64> {
+        self.ranges.iter().filter(|x| x.block == block).map(|x| x.start).min()
+    }
+
+    pub fn ranges(&self) -> &[SourceRange] {
+        &self.ranges
+    }
+
+    pub fn variables(&self, inst: u64) -> impl Iterator<Item = &Variable> {
+        self.variables.iter().filter(move |x| x.start <= inst && inst < x.end)
+    }
+}
@@ -0,0 +1,515 @@
+use std::{sync::{Arc, Mutex, Condvar, atomic::{AtomicI64, Ordering}}, io::{self, BufRead, BufReader, Write}, net::TcpListener, thread};
+
+use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::{debug_info::{DebugInfo, VarLocation}, asm::{Instruction, sections, assembler::{parse_immediate, parse_uint}, disassembler::format_instruction}, register::REGISTER_NAMES, virtual_thread::VThread};
+
+use super::{Debugger, DebugEvent, StopReason, Frame, frames, read_memory, format_value};
+
+// Frame ids are `thread << 16 | index`, variable references are `frame id << 2 | scope`
+const SCOPE_REGISTERS: i64 = 1;
+const SCOPE_STACK: i64 = 2;
+const SCOPE_LOCALS: i64 = 3;
+// Debug info is presented as one source whose line N is block #N
+const BLOCKS_SOURCE: i64 = 1;
+const MAX_STACK_SLOTS: u64 = 64;
+
+pub enum Transport {
+    Stdio,
+    Tcp(u16),
+}
+
+pub(crate) struct Session {
+    debugger: Arc<Debugger>,
+    code: Box<[u8]>,
+    insts: Vec<Instruction>,
+    debug_info: Option<Arc<DebugInfo>>,
+    // Offsets of the instruction and source breakpoints set by the client
+    breakpoints: Mutex<(Vec<u64>, Vec<u64>)>,
+    output: Mutex<Box<dyn Write + Send>>,
+    seq: AtomicI64,
+    // (configurationDone received, stop on entry), events wait for the configuration to finish
+    configured: Mutex<(bool, bool)>,
+    configured_cond: Condvar,
+}
+
+pub struct DapHandle(Arc<Session>);
+
+// Sends what the program writes to the client as `output` events
+pub struct DapOutput {
+    session: Arc<Session>,
+    category: &'static str,
+}
+
+pub(crate) fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+
+    loop {
+        let mut line = String::new();
+
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') && name.eq_ignore_ascii_case("Content-Length") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header"))?;
+    let mut body = vec![0; length];
+
+    input.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body).map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn parse_address(value: &str) -> Option<u64> {
+    parse_uint(value.trim())
+}
+
+impl Session {
+    pub(crate) fn new(debugger: Arc<Debugger>, code: Box<[u8]>, debug_info: Option<Arc<DebugInfo>>, output: Box<dyn Write + Send>) -> Arc<Session> {
+        let mut insts = Vec::new();
+        let mut offset = sections(&code).map_or(code.len(), |x| x.1);
+
+        while let Some(inst) = Instruction::decode(&code, offset) {
+            offset += inst.size();
+            insts.push(inst);
+        }
+
+        Arc::new(Session {
+            debugger,
+            code,
+            insts,
+            debug_info,
+            breakpoints: Mutex::new((Vec::new(), Vec::new())),
+            output: Mutex::new(output),
+            seq: AtomicI64::new(1),
+            configured: Mutex::new((false, false)),
+            configured_cond: Condvar::new(),
+        })
+    }
+
+    pub(crate) fn output(self: &Arc<Self>, category: &'static str) -> DapOutput {
+        DapOutput { session: self.clone(), category }
+    }
+
+    // Forwards the debugger events to the client from a background thread
+    pub(crate) fn forward_events(self: &Arc<Self>, mut events: UnboundedReceiver<DebugEvent>) {
+        let session = self.clone();
+
+        thread::spawn(move || {
+            while let Some(event) = events.blocking_recv() {
+                session.forward(event);
+            }
+        });
+    }
+
+    fn send(&self, mut message: Value) {
+        message["seq"] = json!(self.seq.fetch_add(1, Ordering::SeqCst));
+
+        let body = message.to_string();
+        let mut output = self.output.lock().unwrap();
+
+        let _ = write!(output, "Content-Length: {}\r\n\r\n{body}", body.len());
+        let _ = output.flush();
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn instruction(&self, ip: u64) -> String {
+        Instruction::decode(&self.code, ip as usize).and_then(|x| format_instruction(&x)).unwrap_or_else(|| String::from("??"))
+    }
+
+    fn forward(&self, event: DebugEvent) {
+        let stop_on_entry = {
+            let configured = self.configured_cond.wait_while(self.configured.lock().unwrap(), |x| !x.0).unwrap();
+
+            configured.1
+        };
+
+        match event {
+            DebugEvent::Stopped { reason: StopReason::Entry, .. } if !stop_on_entry => {}
+            DebugEvent::Stopped { thread, reason, .. } => self.event("stopped", json!({
+                "reason": match reason {
+                    StopReason::Entry => "entry",
+                    StopReason::Breakpoint => "breakpoint",
+                    StopReason::Step => "step",
+                    StopReason::Pause => "pause",
+                },
+                "threadId": thread,
+                "allThreadsStopped": true,
+            })),
+            DebugEvent::ThreadStarted(id) => self.event("thread", json!({ "reason": "started", "threadId": id })),
+            DebugEvent::ThreadExited(id) => self.event("thread", json!({ "reason": "exited", "threadId": id })),
+        }
+    }
+
+    fn source(&self) -> Value {
+        json!({ "name": "Blocks", "sourceReference": BLOCKS_SOURCE })
+    }
+
+    // Replaces one kind of breakpoints, keeping those of the other kind
+    fn set_breakpoints(&self, source: bool, offsets: Vec<u64>) {
+        let mut breakpoints = self.breakpoints.lock().unwrap();
+
+        if source { breakpoints.1 = offsets } else { breakpoints.0 = offsets }
+
+        for offset in self.debugger.breakpoints() {
+            self.debugger.clear_breakpoint(offset);
+        }
+
+        for &offset in breakpoints.0.iter().chain(&breakpoints.1) {
+            self.debugger.set_breakpoint(offset);
+        }
+    }
+
+    fn frame(&self, frame_id: i64) -> Result<(Frame, Option<Frame>, VThread), String> {
+        let thread = self.debugger.stopped_thread((frame_id >> 16) as u64)?;
+        let frames = frames(&thread);
+        let index = (frame_id & 0xFFFF) as usize;
+        let frame = *frames.get(index).ok_or_else(|| format!("Unknown frame {frame_id}."))?;
+
+        Ok((frame, frames.get(index + 1).copied(), thread))
+    }
+
+    fn variables(&self, reference: i64) -> Result<Value, String> {
+        let (frame, caller, thread) = self.frame(reference >> 2)?;
+        let variable = |name: String, value: u64| json!({ "name": name, "value": format_value(&thread, value), "variablesReference": 0 });
+
+        let variables = match reference & 3 {
+            SCOPE_REGISTERS => REGISTER_NAMES.iter().zip(frame.registers).map(|(name, value)| variable(name.to_string(), value)).collect::<Vec<_>>(),
+            SCOPE_STACK => {
+                // Slots pushed by this frame, up to the frame of its caller
+                let top = frame.registers[4] + 8;
+                let end = caller.map_or(thread.stack.ptr() + thread.stack_size() as u64, |_| frame.registers[2] + 8);
+                let count = (end.saturating_sub(top) / 8).min(MAX_STACK_SLOTS) as usize;
+
+                read_memory(&thread, top, count)?.into_iter().enumerate().map(|(i, value)| variable(format!("[top+{}]", i + 1), value)).collect()
+            }
+            SCOPE_LOCALS => {
+                let debug_info = self.debug_info.as_ref().ok_or("No debug info.")?;
+
+                debug_info.variables(frame.registers[0]).map(|x| match x.location {
+                    VarLocation::Register(reg) => Ok(variable(x.name.clone(), *frame.registers.get(reg as usize).ok_or("Invalid register.")?)),
+                    VarLocation::Stack(slot) => Ok(variable(x.name.clone(), read_memory(&thread, (frame.registers[2] as i64 - slot as i64 * 8) as u64, 1)?[0])),
+                }).collect::<Result<Vec<_>, String>>()?
+            }
+            _ => return Err(format!("Unknown variables reference {reference}."))
+        };
+
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn disassemble(&self, args: &Value) -> Result<Value, String> {
+        let address = args["memoryReference"].as_str().and_then(parse_address).ok_or("Invalid memory reference.")?;
+        let offset = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_i64().unwrap_or(0);
+
+        // Index of the instruction at `address`, or of the first one after it
+        let base = self.insts.partition_point(|x| (x.offset as u64) < address) as i64 + offset;
+
+        let instructions = (base..(base + count)).map(|i| match usize::try_from(i).ok().and_then(|i| self.insts.get(i)) {
+            Some(inst) => json!({
+                "address": format!("0x{:04X}", inst.offset),
+                "instructionBytes": self.code[inst.offset..(inst.offset + inst.size())].iter().map(|x| format!("{x:02X}")).collect::<Vec<_>>().join(" "),
+                "instruction": format_instruction(inst).unwrap_or_else(|| String::from("??")),
+            }),
+            None => {
+                // Padding before or after the code section, in 8-byte steps
+                let address = match self.insts.last() {
+                    Some(last) if i >= 0 => (last.offset + last.size()) as i64 + (i - self.insts.len() as i64) * 8,
+                    _ => self.insts.first().map_or(0, |x| x.offset as i64) + i * 8,
+                };
+
+                json!({ "address": format!("0x{:04X}", address.max(0)), "instruction": "??", "presentationHint": "invalid" })
+            }
+        }).collect::<Vec<_>>();
+
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    fn handle(&self, command: &str, args: &Value) -> Result<Value, String> {
+        Ok(match command {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsDisassembleRequest": true,
+                "supportsSteppingGranularity": true,
+                "supportsSetVariable": true,
+                "supportsReadMemoryRequest": true,
+                "supportsTerminateRequest": true,
+            }),
+            "launch" | "attach" => {
+                self.configured.lock().unwrap().1 = args["stopOnEntry"].as_bool().unwrap_or(false);
+
+                Value::Null
+            }
+            "configurationDone" => {
+                if !self.configured.lock().unwrap().1 {
+                    self.debugger.resume();
+                }
+
+                Value::Null
+            }
+            // Lines of the blocks source are block ids, a breakpoint stops at the first instruction of the block
+            "setBreakpoints" => {
+                let mut offsets = Vec::new();
+
+                let breakpoints = args["breakpoints"].as_array().cloned().unwrap_or_default().iter().map(|x| {
+                    let line = x["line"].as_u64().unwrap_or(0);
+                    let start = self.debug_info.as_ref().and_then(|info| u32::try_from(line).ok().and_then(|block| info.block_start(block)));
+
+                    match start {
+                        Some(start) => {
+                            offsets.push(start);
+
+                            json!({ "verified": true, "line": line, "source": self.source(), "instructionReference": format!("0x{start:04X}") })
+                        }
+                        None if self.debug_info.is_none() => json!({ "verified": false, "message": "No debug info" }),
+                        None => json!({ "verified": false, "message": format!("Unknown block #{line}") })
+                    }
+                }).collect::<Vec<_>>();
+
+                self.set_breakpoints(true, offsets);
+
+                json!({ "breakpoints": breakpoints })
+            }
+            "setInstructionBreakpoints" => {
+                let mut offsets = Vec::new();
+
+                let breakpoints = args["breakpoints"].as_array().cloned().unwrap_or_default().iter().map(|x| {
+                    let address = x["instructionReference"].as_str().and_then(parse_address)
+                        .map(|address| (address as i64 + x["offset"].as_i64().unwrap_or(0)) as u64);
+
+                    match address {
+                        Some(address) if self.insts.iter().any(|x| x.offset as u64 == address) => {
+                            offsets.push(address);
+
+                            json!({ "verified": true, "instructionReference": format!("0x{address:04X}") })
+                        }
+                        _ => json!({ "verified": false, "message": "Not an instruction boundary" })
+                    }
+                }).collect::<Vec<_>>();
+
+                self.set_breakpoints(false, offsets);
+
+                json!({ "breakpoints": breakpoints })
+            }
+            "threads" => json!({
+                "threads": self.debugger.threads().iter().map(|x| json!({ "id": x.id, "name": format!("Thread {}", x.id) })).collect::<Vec<_>>(),
+            }),
+            "stackTrace" => {
+                let id = args["threadId"].as_u64().ok_or("Missing thread id.")?;
+                let thread = self.debugger.stopped_thread(id)?;
+
+                let frames = frames(&thread).iter().enumerate().map(|(i, frame)| {
+                    let ip = frame.registers[0];
+                    let mut value = json!({
+                        "id": (id << 16) | i as u64,
+                        "name": format!("0x{ip:04X}: {}", self.instruction(ip)),
+                        "instructionPointerReference": format!("0x{ip:04X}"),
+                        "line": 0,
+                        "column": 0,
+                    });
+
+                    if let Some(range) = self.debug_info.as_ref().and_then(|x| x.range(ip)) {
+                        value["name"] = json!(format!("{} (block #{})", range.function.as_deref().unwrap_or("<unknown>"), range.block));
+                        value["source"] = self.source();
+                        value["line"] = json!(range.block);
+                    }
+
+                    value
+                }).collect::<Vec<_>>();
+
+                json!({ "stackFrames": frames, "totalFrames": frames.len() })
+            }
+            "scopes" => {
+                let frame_id = args["frameId"].as_i64().ok_or("Missing frame id.")?;
+                let (frame, _, _) = self.frame(frame_id)?;
+
+                let mut scopes = vec![
+                    json!({ "name": "Registers", "presentationHint": "registers", "variablesReference": frame_id << 2 | SCOPE_REGISTERS, "expensive": false }),
+                    json!({ "name": "Stack", "variablesReference": frame_id << 2 | SCOPE_STACK, "expensive": false }),
+                ];
+
+                if self.debug_info.as_ref().map_or(false, |x| x.variables(frame.registers[0]).next().is_some()) {
+                    scopes.insert(0, json!({ "name": "Locals", "presentationHint": "locals", "variablesReference": frame_id << 2 | SCOPE_LOCALS, "expensive": false }));
+                }
+
+                json!({ "scopes": scopes })
+            }
+            "variables" => self.variables(args["variablesReference"].as_i64().ok_or("Missing variables reference.")?)?,
+            "setVariable" => {
+                let reference = args["variablesReference"].as_i64().ok_or("Missing variables reference.")?;
+                let name = args["name"].as_str().unwrap_or_default();
+                let reg = REGISTER_NAMES.iter().position(|x| *x == name);
+
+                // Only the registers of the innermost frame are live
+                let (reg, thread) = match (reg, reference & 3, reference >> 2 & 0xFFFF) {
+                    (Some(reg), SCOPE_REGISTERS, 0) => (reg as u8, self.debugger.stopped_thread((reference >> 18) as u64)?),
+                    _ => return Err(String::from("Only registers of the innermost frame can be modified."))
+                };
+
+                thread.set_reg(reg, parse_immediate(args["value"].as_str().unwrap_or_default().trim())?);
+
+                json!({ "value": format_value(&thread, thread.get_reg::<u64>(reg)) })
+            }
+            "disassemble" => self.disassemble(args)?,
+            "source" => {
+                let debug_info = self.debug_info.as_ref().ok_or("No debug info.")?;
+                let count = debug_info.ranges().iter().map(|x| x.block).max().unwrap_or(0);
+
+                // Line N describes block #N
+                let content = (1..=count).map(|block| match debug_info.ranges().iter().find(|x| x.block == block) {
+                    Some(range) => format!("0x{:04X}: {range}", range.start),
+                    None => String::new()
+                }).collect::<Vec<_>>().join("\n");
+
+                json!({ "content": content, "mimeType": "text/plain" })
+            }
+            "readMemory" => {
+                let thread = self.debugger.threads().iter().find_map(|x| self.debugger.stopped_thread(x.id).ok()).ok_or("No thread is stopped.")?;
+                let address = args["memoryReference"].as_str().and_then(parse_address).ok_or("Invalid memory reference.")? as i64 + args["offset"].as_i64().unwrap_or(0);
+                let words = read_memory(&thread, address as u64, (args["count"].as_u64().unwrap_or(0) as usize + 7) / 8)?;
+
+                json!({ "address": format!("0x{address:X}"), "data": words.iter().flat_map(|x| x.to_le_bytes()).map(|x| format!("{x:02x}")).collect::<String>() })
+            }
+            "continue" => {
+                self.debugger.resume();
+
+                json!({ "allThreadsContinued": true })
+            }
+            "next" | "stepIn" => {
+                self.debugger.step(args["threadId"].as_u64().ok_or("Missing thread id.")?)?;
+
+                Value::Null
+            }
+            "pause" => {
+                self.debugger.pause();
+
+                Value::Null
+            }
+            // The runtime shuts down and the caller reports `exited` and `terminated` once it returns
+            "terminate" => {
+                self.debugger.terminate();
+
+                Value::Null
+            }
+            "disconnect" if args["terminateDebuggee"].as_bool().unwrap_or(true) => {
+                self.debugger.terminate();
+
+                Value::Null
+            }
+            "disconnect" => {
+                self.detach();
+
+                Value::Null
+            }
+            _ => return Err(format!("Unsupported request `{command}`."))
+        })
+    }
+
+    pub(crate) fn serve(&self, mut input: impl BufRead) {
+        while let Ok(Some(request)) = read_message(&mut input) {
+            let command = request["command"].as_str().unwrap_or_default().to_owned();
+            let args = &request["arguments"];
+            let mut response = json!({ "type": "response", "request_seq": request["seq"], "command": command });
+
+            match self.handle(&command, args) {
+                Ok(body) => {
+                    response["success"] = json!(true);
+                    response["body"] = body;
+                }
+                Err(message) => {
+                    response["success"] = json!(false);
+                    response["message"] = json!(message);
+                }
+            }
+
+            self.send(response);
+
+            match command.as_str() {
+                "initialize" => self.event("initialized", json!({})),
+                "configurationDone" => {
+                    self.configured.lock().unwrap().0 = true;
+                    self.configured_cond.notify_all();
+                }
+                "disconnect" => return,
+                _ => {}
+            }
+        }
+
+        // Client went away, let the program run to completion
+        self.detach();
+    }
+
+    fn detach(&self) {
+        for offset in self.debugger.breakpoints() {
+            self.debugger.clear_breakpoint(offset);
+        }
+
+        self.debugger.resume();
+    }
+}
+
+impl DapHandle {
+    // Reports the end of the program to the client
+    pub fn exited(&self, exit_code: i32) {
+        self.0.event("exited", json!({ "exitCode": exit_code }));
+        self.0.event("terminated", json!({}));
+    }
+
+    // Sink for the program's output, `category` is `stdout` or `stderr`
+    pub fn output(&self, category: &'static str) -> DapOutput {
+        self.0.output(category)
+    }
+}
+
+impl Write for DapOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.session.event("output", json!({ "category": self.category, "output": String::from_utf8_lossy(buf) }));
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Serves the Debug Adapter Protocol over standard input/output or the first client on a local TCP port
+pub fn start(debugger: Arc<Debugger>, events: UnboundedReceiver<DebugEvent>, code: Box<[u8]>, debug_info: Option<Arc<DebugInfo>>, transport: Transport) -> io::Result<DapHandle> {
+    let (input, output): (Box<dyn BufRead + Send>, Box<dyn Write + Send>) = match transport {
+        Transport::Stdio => (Box::new(BufReader::new(io::stdin())), Box::new(io::stdout())),
+        Transport::Tcp(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+
+            eprintln!("Waiting for a debug adapter client on 127.0.0.1:{port}");
+
+            let (stream, _) = listener.accept()?;
+
+            (Box::new(BufReader::new(stream.try_clone()?)), Box::new(stream))
+        }
+    };
+
+    let session = Session::new(debugger, code, debug_info, output);
+
+    session.forward_events(events);
+
+    let serve_session = session.clone();
+
+    thread::spawn(move || serve_session.serve(input));
+
+    Ok(DapHandle(session))
+}
@@ -0,0 +1,337 @@
+use std::{sync::{Arc, Weak, Mutex, atomic::{AtomicBool, Ordering}}, collections::{BTreeMap, BTreeSet}};
+
+use tokio::sync::{watch, mpsc::{self, UnboundedReceiver, UnboundedSender}};
+
+use crate::{virtual_thread::VThread, vm_value::{VMValue, STR_SIGNATURE, NAN}, executor::executor::Lock, runtime::Runtime, thread_counter::ShutdownType};
+
+pub mod repl;
+pub mod dap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Entry,
+    Breakpoint,
+    Step,
+    Pause,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugEvent {
+    Stopped { thread: u64, reason: StopReason, ip: u64 },
+    ThreadStarted(u64),
+    ThreadExited(u64),
+}
+
+// Register values of a call frame, callers get INST, TOP and D0..R7 back from the frame `CALL` pushed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub registers: [u64; 16],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub id: u64,
+    // Instruction offset, None while the thread is running
+    pub ip: Option<u64>,
+}
+
+struct DebugState {
+    breakpoints: BTreeSet<u64>,
+    // All-stop mode: every thread parks before its next instruction
+    halted: bool,
+    // Reason reported by the first thread to park after `pause` or on entry
+    pending: Option<StopReason>,
+    // Thread allowed to run one instruction while halted, and the thread currently doing so
+    step: Option<u64>,
+    stepping: Option<u64>,
+    // Live threads, holding the thread while it is parked
+    threads: BTreeMap<u64, Option<VThread>>,
+}
+
+pub struct Debugger {
+    state: Mutex<DebugState>,
+    active: AtomicBool,
+    resume: watch::Sender<()>,
+    events: UnboundedSender<DebugEvent>,
+    // Runtime being debugged, set once it is created
+    runtime: Mutex<Weak<Runtime>>,
+}
+
+impl DebugState {
+    fn is_active(&self) -> bool {
+        self.halted || !self.breakpoints.is_empty()
+    }
+}
+
+impl Debugger {
+    pub fn new(stop_on_entry: bool) -> (Arc<Debugger>, UnboundedReceiver<DebugEvent>) {
+        let (events, rx) = mpsc::unbounded_channel();
+
+        let debugger = Debugger {
+            state: Mutex::new(DebugState {
+                breakpoints: BTreeSet::new(),
+                halted: stop_on_entry,
+                pending: if stop_on_entry { Some(StopReason::Entry) } else { None },
+                step: None,
+                stepping: None,
+                threads: BTreeMap::new(),
+            }),
+            active: AtomicBool::new(stop_on_entry),
+            resume: watch::channel(()).0,
+            events,
+            runtime: Mutex::new(Weak::new()),
+        };
+
+        (Arc::new(debugger), rx)
+    }
+
+    fn update(&self, state: &DebugState) {
+        self.active.store(state.is_active(), Ordering::SeqCst);
+
+        let _ = self.resume.send(());
+    }
+
+    pub fn attach(&self, runtime: &Arc<Runtime>) {
+        *self.runtime.lock().unwrap() = Arc::downgrade(runtime);
+    }
+
+    // Shuts the VM down gracefully, parked threads are woken by their cancellation
+    pub fn terminate(&self) {
+        let runtime = self.runtime.lock().unwrap().upgrade();
+
+        if let Some(runtime) = runtime {
+            runtime.shutdown(ShutdownType::Gracefully);
+        }
+    }
+
+    // Called by the executors before every instruction, returns whether `lock` was released while parked
+    pub async fn hook(&self, thread: &VThread, lock: &mut Lock) -> bool {
+        if !self.active.load(Ordering::SeqCst) || thread.handle.is_cancelled() {
+            return false;
+        }
+
+        let mut resume = self.resume.subscribe();
+
+        {
+            let mut state = self.state.lock().unwrap();
+            let ip = thread.get_reg::<u64>(0);
+
+            let reason = if state.stepping == Some(thread.id) {
+                state.stepping = None;
+
+                Some(StopReason::Step)
+            } else if state.halted {
+                state.pending.take()
+            } else if state.breakpoints.contains(&ip) {
+                Some(StopReason::Breakpoint)
+            } else {
+                return false;
+            };
+
+            state.halted = true;
+            state.threads.insert(thread.id, Some(thread.clone()));
+
+            if let Some(reason) = reason {
+                let _ = self.events.send(DebugEvent::Stopped { thread: thread.id, reason, ip });
+            }
+
+            self.update(&state);
+        }
+
+        // A thread parked inside a block must not hold the executor lock, or stepping another thread deadlocks
+        let released = lock.take().is_some();
+
+        loop {
+            // A cancelled thread, e.g. on shutdown, leaves even while halted
+            let cancelled = tokio::select! {
+                changed = resume.changed() => changed.is_err(),
+                _ = thread.handle.cancelled() => true
+            };
+
+            let mut state = self.state.lock().unwrap();
+
+            if cancelled {
+                // Falls through to leave
+            } else if state.step == Some(thread.id) {
+                state.step = None;
+                state.stepping = Some(thread.id);
+            } else if state.halted {
+                continue;
+            }
+
+            state.threads.insert(thread.id, None);
+
+            break;
+        }
+
+        released
+    }
+
+    pub fn thread_started(&self, id: u64) {
+        self.state.lock().unwrap().threads.insert(id, None);
+
+        let _ = self.events.send(DebugEvent::ThreadStarted(id));
+    }
+
+    pub fn thread_exited(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        state.threads.remove(&id);
+
+        if state.stepping == Some(id) {
+            state.stepping = None;
+        }
+
+        let _ = self.events.send(DebugEvent::ThreadExited(id));
+    }
+
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.halted = false;
+        state.pending = None;
+        state.step = None;
+
+        self.update(&state);
+    }
+
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.halted {
+            state.halted = true;
+            state.pending = Some(StopReason::Pause);
+
+            self.update(&state);
+        }
+    }
+
+    // Runs one instruction of a parked thread while the others stay parked
+    pub fn step(&self, id: u64) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+
+        if !matches!(state.threads.get(&id), Some(Some(_))) {
+            return Err(format!("Thread {id} is not stopped."));
+        }
+
+        state.step = Some(id);
+
+        self.update(&state);
+
+        Ok(())
+    }
+
+    pub fn set_breakpoint(&self, addr: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let inserted = state.breakpoints.insert(addr);
+
+        self.update(&state);
+
+        inserted
+    }
+
+    pub fn clear_breakpoint(&self, addr: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let removed = state.breakpoints.remove(&addr);
+
+        self.update(&state);
+
+        removed
+    }
+
+    pub fn breakpoints(&self) -> Vec<u64> {
+        self.state.lock().unwrap().breakpoints.iter().copied().collect()
+    }
+
+    pub fn threads(&self) -> Vec<ThreadInfo> {
+        self.state.lock().unwrap().threads.iter().map(|(&id, x)| ThreadInfo {
+            id,
+            ip: x.as_ref().map(|x| x.get_reg::<u64>(0)),
+        }).collect()
+    }
+
+    // Only parked threads can be inspected or modified
+    pub fn stopped_thread(&self, id: u64) -> Result<VThread, String> {
+        match self.state.lock().unwrap().threads.get(&id) {
+            Some(Some(thread)) => Ok(thread.clone()),
+            Some(None) => Err(format!("Thread {id} is running.")),
+            None => Err(format!("Thread {id} does not exist.")),
+        }
+    }
+}
+
+// Reads words of guest memory that belong to the thread's stack, the memory image or a live VMStr
+pub fn read_memory(thread: &VThread, addr: u64, count: usize) -> Result<Vec<u64>, String> {
+    let vmstrs = thread.runtime.temp_vmstr.blocking_lock();
+
+    if !thread.is_accessible(addr as usize, count * 8, &vmstrs) {
+        return Err(format!("Cannot read {count} words at 0x{addr:X}."));
+    }
+
+    Ok((0..count).map(|i| thread.get_mem_absolute::<u64>(addr as usize + i * 8)).collect())
+}
+
+// Walks the 11-word frames pushed by `CALL`, starting at FUNC. Callers are found through the FUNC
+// they pushed right before `CALL`, so the walk stops at the first caller that did not save it.
+pub fn frames(thread: &VThread) -> Vec<Frame> {
+    let mut frame = Frame { registers: [0; 16] };
+
+    for (idx, reg) in frame.registers.iter_mut().enumerate() {
+        *reg = thread.get_reg::<u64>(idx as u8);
+    }
+
+    let mut frames = vec![frame];
+
+    for _ in 0..thread.call_depth() {
+        let fp = frame.registers[2];
+
+        let words = match read_memory(thread, fp + 8, 11) {
+            Ok(words) => words,
+            Err(_) => break
+        };
+
+        let caller = read_memory(thread, fp + 12 * 8, 1).ok().map(|x| x[0]).filter(|&x| x > fp);
+
+        frame.registers[0] = words[0];
+        frame.registers[2] = caller.unwrap_or(0);
+        frame.registers[4] = fp + 11 * 8;
+
+        for reg in 6..16 {
+            frame.registers[reg] = words[16 - reg];
+        }
+
+        frames.push(frame);
+
+        if caller.is_none() {
+            break;
+        }
+    }
+
+    frames
+}
+
+fn is_str(value: u64) -> bool {
+    (value & STR_SIGNATURE) == STR_SIGNATURE && (value & 0x7fffffffffffffff) != NAN
+}
+
+// Address held by a value, without the string signature
+pub fn pointer(value: u64) -> u64 {
+    if is_str(value) { value & 0x3ffffffffffff } else { value }
+}
+
+// Formats like `VMValue`, without following string pointers that do not point to accessible memory
+pub fn format_value(thread: &VThread, value: u64) -> String {
+    let vmstrs = thread.runtime.temp_vmstr.blocking_lock();
+    let ptr = pointer(value) as usize;
+
+    let valid = !is_str(value) || ptr == 0
+        || thread.is_accessible(ptr, 8, &vmstrs) && thread.is_accessible(ptr, 8 + thread.get_mem_absolute::<u64>(ptr) as usize, &vmstrs);
+
+    drop(vmstrs);
+
+    if valid {
+        VMValue::from(value, thread.clone()).to_string()
+    } else {
+        format!("0x{value:016X} [invalid str]")
+    }
+}
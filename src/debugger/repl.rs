@@ -0,0 +1,310 @@
+use std::{sync::{Arc, Mutex, Condvar}, io::{self, BufRead, Write}, thread};
+
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::{debug_info::{DebugInfo, VarLocation}, asm::{Instruction, assembler::{parse_immediate, parse_uint}, disassembler::format_instruction}, register::{REGISTER_NAMES, register_id}};
+
+use super::{Debugger, DebugEvent, StopReason, frames, read_memory, format_value, pointer};
+
+const PROMPT: &str = "(oedb) ";
+
+const HELP: &str = "\
+Commands:
+  c, continue              Resume all threads
+  s, step                  Run one instruction of the current thread
+  p, pause                 Stop all threads
+  b, break <OFFSET>        Set a breakpoint at code offset OFFSET
+  d, delete <OFFSET>       Remove the breakpoint at OFFSET
+  bl, breakpoints          List breakpoints
+  t, threads               List live threads
+  thread <ID>              Select the current thread
+  r, regs                  Show registers and flags of the current thread
+  set <REG> <VALUE>        Set a register (VALUE is an assembler immediate)
+  flag <ID> <0|1>          Set a flag of the current thread
+  f, frames                List the call frames of the current thread
+  bt, stack [N]            Show N words from the top of the stack (default 8)
+  x <ADDR|REG> [N]         Show N words of memory at ADDR or the value of REG (default 4)
+  l, list [N]              Disassemble N instructions from the current one (default 5)
+  q, quit                  Stop the VM and exit
+  h, help                  Show this help";
+
+struct Repl {
+    debugger: Arc<Debugger>,
+    code: Box<[u8]>,
+    debug_info: Option<Arc<DebugInfo>>,
+    current: Mutex<Option<u64>>,
+    // Number of stops so far, commands that wait for the next stop block on `stopped`
+    stops: Mutex<u64>,
+    stopped: Condvar,
+}
+
+fn prompt() {
+    print!("{PROMPT}");
+
+    let _ = io::stdout().flush();
+}
+
+fn parse_count(value: Option<&str>, default: usize) -> Result<usize, String> {
+    value.map_or(Ok(default), |x| parse_uint(x).map(|x| x as usize).ok_or_else(|| format!("Invalid count `{x}`.")))
+}
+
+fn parse_offset(value: Option<&str>) -> Result<u64, String> {
+    let value = value.ok_or_else(|| String::from("Missing code offset."))?;
+
+    parse_uint(value).ok_or_else(|| format!("Invalid code offset `{value}`."))
+}
+
+impl Repl {
+    fn instruction(&self, ip: u64) -> String {
+        match Instruction::decode(&self.code, ip as usize) {
+            Some(inst) => format_instruction(&inst).unwrap_or_else(|| String::from("<invalid instruction>")),
+            None => String::from("<outside code>")
+        }
+    }
+
+    // Source location of an instruction, empty without debug info
+    fn location(&self, ip: u64) -> String {
+        match self.debug_info.as_ref().and_then(|x| x.range(ip)) {
+            Some(range) => format!(" {range}"),
+            None => String::new()
+        }
+    }
+
+    // Name given to a register or stack slot by the debug info
+    fn variable(&self, ip: u64, location: VarLocation) -> String {
+        match self.debug_info.as_ref().and_then(|x| x.variables(ip).find(|x| x.location == location)) {
+            Some(variable) => format!(" ({})", variable.name),
+            None => String::new()
+        }
+    }
+
+    fn wait_stop(&self, seen: u64) {
+        drop(self.stopped.wait_while(self.stops.lock().unwrap(), |x| *x == seen).unwrap());
+    }
+
+    fn notify_stop(&self) {
+        *self.stops.lock().unwrap() += 1;
+
+        self.stopped.notify_all();
+    }
+
+    fn current(&self) -> Result<u64, String> {
+        self.current.lock().unwrap().ok_or_else(|| String::from("No thread selected."))
+    }
+
+    fn event(&self, event: DebugEvent) {
+        match event {
+            DebugEvent::Stopped { thread, reason, ip } => {
+                let reason = match reason {
+                    StopReason::Entry => "entry",
+                    StopReason::Breakpoint => "breakpoint",
+                    StopReason::Step => "step",
+                    StopReason::Pause => "pause",
+                };
+
+                *self.current.lock().unwrap() = Some(thread);
+
+                println!("\nThread {thread} stopped ({reason}) at 0x{ip:04X}{}: {}", self.location(ip), self.instruction(ip));
+                prompt();
+
+                self.notify_stop();
+            }
+            DebugEvent::ThreadStarted(id) => {
+                println!("\nThread {id} started");
+                prompt();
+            }
+            DebugEvent::ThreadExited(id) => {
+                println!("\nThread {id} exited");
+                prompt();
+
+                if *self.current.lock().unwrap() == Some(id) {
+                    self.notify_stop();
+                }
+            }
+        }
+    }
+
+    // Returns whether the REPL keeps reading commands
+    fn execute(&self, line: &str) -> Result<bool, String> {
+        let mut args = line.split_whitespace();
+        let command = match args.next() {
+            Some(command) => command,
+            None => return Ok(true)
+        };
+
+        match command {
+            "c" | "continue" => self.debugger.resume(),
+            "s" | "step" => {
+                let seen = *self.stops.lock().unwrap();
+
+                self.debugger.step(self.current()?)?;
+                self.wait_stop(seen);
+            }
+            "p" | "pause" => self.debugger.pause(),
+            "b" | "break" => {
+                let offset = parse_offset(args.next())?;
+
+                if self.debugger.set_breakpoint(offset) {
+                    println!("Breakpoint at 0x{offset:04X}: {}", self.instruction(offset));
+                }
+            }
+            "d" | "delete" => {
+                let offset = parse_offset(args.next())?;
+
+                if !self.debugger.clear_breakpoint(offset) {
+                    return Err(format!("No breakpoint at 0x{offset:04X}."));
+                }
+            }
+            "bl" | "breakpoints" => {
+                for offset in self.debugger.breakpoints() {
+                    println!("0x{offset:04X}: {}", self.instruction(offset));
+                }
+            }
+            "t" | "threads" => {
+                let current = *self.current.lock().unwrap();
+
+                for info in self.debugger.threads() {
+                    let marker = if Some(info.id) == current { '*' } else { ' ' };
+
+                    match info.ip {
+                        Some(ip) => println!("{marker} {:<4} stopped at 0x{ip:04X}: {}", info.id, self.instruction(ip)),
+                        None => println!("{marker} {:<4} running", info.id),
+                    }
+                }
+            }
+            "thread" => {
+                let id = parse_uint(args.next().unwrap_or_default()).ok_or_else(|| String::from("Invalid thread id."))?;
+
+                self.debugger.stopped_thread(id)?;
+
+                *self.current.lock().unwrap() = Some(id);
+            }
+            "r" | "regs" => {
+                let thread = self.debugger.stopped_thread(self.current()?)?;
+                let ip = thread.get_reg::<u64>(0);
+
+                for (idx, name) in REGISTER_NAMES.iter().enumerate() {
+                    println!("{:<5} {}{}", format!("{name}:"), format_value(&thread, thread.get_reg::<u64>(idx as u8)), self.variable(ip, VarLocation::Register(idx as u8)));
+                }
+
+                println!("FLAGS: {}", (0..8).map(|x| if thread.get_flag(x) { '1' } else { '0' }).collect::<String>());
+            }
+            "set" => {
+                let thread = self.debugger.stopped_thread(self.current()?)?;
+                let reg = args.next().and_then(register_id).ok_or_else(|| String::from("Unknown register."))?;
+                let value = parse_immediate(&args.collect::<Vec<_>>().join(" "))?;
+
+                thread.set_reg(reg, value);
+            }
+            "flag" => {
+                let thread = self.debugger.stopped_thread(self.current()?)?;
+                let id = parse_uint(args.next().unwrap_or_default()).filter(|&x| x < 64).ok_or_else(|| String::from("Invalid flag id."))?;
+                let value = match args.next() {
+                    Some("0") => false,
+                    Some("1") => true,
+                    _ => return Err(String::from("Flag value must be 0 or 1."))
+                };
+
+                thread.set_flag(id, value);
+            }
+            "f" | "frames" => {
+                let thread = self.debugger.stopped_thread(self.current()?)?;
+
+                for (i, frame) in frames(&thread).iter().enumerate() {
+                    let ip = frame.registers[0];
+
+                    println!("#{i:<3} 0x{ip:04X}{}: {}", self.location(ip), self.instruction(ip));
+                }
+            }
+            "bt" | "stack" => {
+                let thread = self.debugger.stopped_thread(self.current()?)?;
+                let (ip, func) = (thread.get_reg::<u64>(0), thread.get_reg::<u64>(2));
+                let top = thread.get_reg::<u64>(4) + 8;
+                let end = thread.stack.ptr() + thread.stack_size() as u64;
+                let count = parse_count(args.next(), 8)?.min(((end.saturating_sub(top)) / 8) as usize);
+
+                for (i, value) in read_memory(&thread, top, count)?.into_iter().enumerate() {
+                    let slot = (func as i64 - (top + i as u64 * 8) as i64) / 8;
+                    let name = i16::try_from(slot).map_or_else(|_| String::new(), |slot| self.variable(ip, VarLocation::Stack(slot)));
+
+                    println!("[top+{}] {}{name}", i + 1, format_value(&thread, value));
+                }
+            }
+            "x" => {
+                let thread = self.debugger.stopped_thread(self.current()?)?;
+                let target = args.next().ok_or_else(|| String::from("Missing address."))?;
+                let addr = match register_id(target) {
+                    Some(reg) => pointer(thread.get_reg::<u64>(reg)),
+                    None => parse_uint(target).ok_or_else(|| format!("Invalid address `{target}`."))?
+                };
+
+                for (i, value) in read_memory(&thread, addr, parse_count(args.next(), 4)?)?.into_iter().enumerate() {
+                    println!("0x{:016X} {}", addr + i as u64 * 8, format_value(&thread, value));
+                }
+            }
+            "l" | "list" => {
+                let thread = self.debugger.stopped_thread(self.current()?)?;
+                let mut offset = thread.get_reg::<u64>(0) as usize;
+
+                for _ in 0..parse_count(args.next(), 5)? {
+                    let inst = match Instruction::decode(&self.code, offset) {
+                        Some(inst) => inst,
+                        None => break
+                    };
+
+                    println!("0x{offset:04X}: {}", format_instruction(&inst).unwrap_or_else(|| String::from("<invalid instruction>")));
+
+                    offset += inst.size();
+                }
+            }
+            "q" | "quit" => {
+                self.debugger.terminate();
+
+                return Ok(false);
+            }
+            "h" | "help" => println!("{HELP}"),
+            _ => return Err(format!("Unknown command `{command}`, type `help` for a list of commands."))
+        }
+
+        Ok(true)
+    }
+}
+
+// Runs the debugger REPL on standard input next to the VM
+pub fn start(debugger: Arc<Debugger>, mut events: UnboundedReceiver<DebugEvent>, code: Box<[u8]>, debug_info: Option<Arc<DebugInfo>>) {
+    let repl = Arc::new(Repl { debugger, code, debug_info, current: Mutex::new(None), stops: Mutex::new(0), stopped: Condvar::new() });
+    let event_repl = repl.clone();
+
+    thread::spawn(move || {
+        while let Some(event) = events.blocking_recv() {
+            event_repl.event(event);
+        }
+    });
+
+    thread::spawn(move || {
+        // Wait for the entry stop
+        repl.wait_stop(0);
+
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break
+            };
+
+            match repl.execute(&line) {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(message) => println!("error: {message}")
+            }
+
+            prompt();
+        }
+
+        // Standard input closed, let the program run to completion
+        for offset in repl.debugger.breakpoints() {
+            repl.debugger.clear_breakpoint(offset);
+        }
+
+        repl.debugger.resume();
+    });
+}
@@ -0,0 +1,160 @@
+use std::{sync::Arc, io::Write, fmt::{self, Display}, error::Error, future::Future};
+
+use tokio::runtime::Handle;
+
+use crate::{
+    archive::{Archive, ArchiveError}, vm_config::{VMConfig, ExecutorKind}, extensions::{Extensions, Extension},
+    runtime::{Runtime, RuntimeOptions, RunOutcome}, thread_counter::ShutdownType, verifier::{self, VerifyError},
+    debugger::Debugger, trace::Tracer, profiler::Profiler,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BuildError {
+    MissingBlockInfo,
+    Verify(Vec<VerifyError>),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MissingBlockInfo => write!(f, "Block executors require `BlockInfo.bin` in the archive."),
+            BuildError::Verify(errors) => {
+                write!(f, "Main.bin failed verification:")?;
+
+                for err in errors {
+                    write!(f, "\n  {err}")?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+// Embedding entry point, e.g. `VmBuilder::open("Demo.entx")?.stderr(log).build()?.run()`
+pub struct VmBuilder {
+    archive: Archive,
+    extensions: Extensions,
+    options: RuntimeOptions,
+}
+
+impl VmBuilder {
+    pub fn new(archive: Archive) -> VmBuilder {
+        VmBuilder { archive, extensions: Extensions::empty(), options: RuntimeOptions::default() }
+    }
+
+    // Main.bin bytecode and config, without block info or assets
+    pub fn from_parts(code: Box<[u8]>, conf: VMConfig) -> VmBuilder {
+        VmBuilder::new(Archive::new(code, conf, None))
+    }
+
+    pub fn open(path: impl Into<String>) -> Result<VmBuilder, ArchiveError> {
+        Ok(VmBuilder::new(Archive::open(path)?))
+    }
+
+    pub fn archive(&self) -> &Archive {
+        &self.archive
+    }
+
+    pub fn config(mut self, f: impl FnOnce(&mut VMConfig)) -> VmBuilder {
+        f(&mut self.archive.conf);
+        self
+    }
+
+    // In-process `VmExtension` or a loaded `Extension`
+    pub fn extension(mut self, id: u32, extension: impl Into<Extension>) -> VmBuilder {
+        self.extensions.insert(id, extension);
+        self
+    }
+
+    // Loads an extension library, like `--ext ID=PATH`
+    pub fn load_extension(self, id: u32, path: impl Into<String>) -> Result<VmBuilder, libloading::Error> {
+        Ok(self.extension(id, Extension::new(path.into())?))
+    }
+
+    pub fn stdout(mut self, sink: impl Write + Send + 'static) -> VmBuilder {
+        self.options.stdout = Some(Box::new(sink));
+        self
+    }
+
+    pub fn stderr(mut self, sink: impl Write + Send + 'static) -> VmBuilder {
+        self.options.stderr = Some(Box::new(sink));
+        self
+    }
+
+    // Spawns the virtual threads on the host's tokio runtime instead of building one from the config
+    pub fn tokio_handle(mut self, handle: Handle) -> VmBuilder {
+        self.options.tokio_handle = Some(handle);
+        self
+    }
+
+    pub fn debugger(mut self, debugger: Arc<Debugger>) -> VmBuilder {
+        self.options.debugger = Some(debugger);
+        self
+    }
+
+    pub fn tracer(mut self, tracer: Arc<Tracer>) -> VmBuilder {
+        self.options.tracer = Some(tracer);
+        self
+    }
+
+    pub fn profiler(mut self, profiler: Arc<Profiler>) -> VmBuilder {
+        self.options.profiler = Some(profiler);
+        self
+    }
+
+    // Checks the config and Main.bin against the registered extensions
+    pub fn verify(&self) -> Result<(), BuildError> {
+        if matches!(self.archive.conf.executor_kind, ExecutorKind::SysLockBlock | ExecutorKind::SpinLockBlock) && self.archive.block_info.is_none() {
+            return Err(BuildError::MissingBlockInfo);
+        }
+
+        verifier::verify(&self.archive.code, |id| self.extensions.contains(id)).map_err(BuildError::Verify)
+    }
+
+    // Verifies the program and initializes the extensions
+    pub fn build(self) -> Result<Vm, BuildError> {
+        self.verify()?;
+
+        Ok(Vm { runtime: Runtime::with_options(self.archive, self.extensions, self.options) })
+    }
+}
+
+// Clones control the same VM, e.g. `stop` from another thread while `run` blocks
+#[derive(Clone)]
+pub struct Vm {
+    runtime: Arc<Runtime>,
+}
+
+impl Vm {
+    pub fn runtime(&self) -> &Arc<Runtime> {
+        &self.runtime
+    }
+
+    // Runs until the VM shuts down, blocking the current thread
+    pub fn run(&self) -> RunOutcome {
+        self.runtime.clone().run()
+    }
+
+    // Runs until the VM shuts down, from inside the host's tokio runtime, e.g. `tokio::spawn(vm.run_async())`
+    pub fn run_async(&self) -> impl Future<Output = RunOutcome> + Send + 'static {
+        self.runtime.clone().run_async()
+    }
+
+    // Like `run_async` but not Send, `ThreadingKind::Single` runs on the calling task instead of a dedicated runtime
+    pub async fn run_local(&self) -> RunOutcome {
+        self.runtime.clone().run_local().await
+    }
+
+    // Stops every virtual thread, `run` returns a graceful outcome
+    pub fn stop(&self) {
+        self.runtime.shutdown(ShutdownType::Gracefully);
+    }
+
+    // Stops every virtual thread and starts the program again with fresh memory
+    pub fn restart(&self) {
+        self.runtime.shutdown(ShutdownType::Restarting);
+    }
+}
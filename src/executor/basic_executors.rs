@@ -1,3 +1,5 @@
+use std::{future::Future, time::Instant};
+
 use crate::{virtual_thread::VThread, block_info::UnlockInfo};
 use super::{instructions, executor::{ExecutorBehaviour, Lock}};
 
@@ -7,9 +9,34 @@ pub struct SpinLockInstExecutor;
 pub struct SysLockBlockExecutor;
 pub struct SpinLockBlockExecutor;
 
+// Waits for the executor lock, recording the wait when profiling
+async fn acquire<T>(thread: &VThread, lock: impl Future<Output = T>) -> Box<T> {
+    match &thread.runtime.profiler {
+        Some(profiler) => {
+            let start = Instant::now();
+            let guard = lock.await;
+
+            profiler.lock_wait(start.elapsed());
+
+            Box::new(guard)
+        }
+        None => Box::new(lock.await)
+    }
+}
+
+// Runs before every instruction, returns whether the debugger released `lock` and it must be acquired again
+async fn before_instruction(thread: &VThread, lock: &mut Lock) -> bool {
+    match &thread.runtime.debugger {
+        Some(debugger) => debugger.hook(thread, lock).await,
+        None => false
+    }
+}
+
 impl AtomicExecutor {
     pub async fn run(thread: VThread) {
         loop {
+            before_instruction(&thread, &mut None).await;
+
             let (_, behaviour) = instructions::run::<true>(thread.clone(), None).await;
             
             if let ExecutorBehaviour::Shutdown(shutdown_type) = behaviour {
@@ -28,7 +55,9 @@ impl AtomicExecutor {
 impl SysLockInstExecutor {
     pub async fn run(thread: VThread) {
         loop {
-            let lock = Box::new(thread.lock.sys().clone().lock_owned().await);
+            before_instruction(&thread, &mut None).await;
+
+            let lock = acquire(&thread, thread.lock.sys().clone().lock_owned()).await;
 
             let (_, behaviour) = instructions::run::<true>(thread.clone(), Some(lock)).await;
             
@@ -48,7 +77,9 @@ impl SysLockInstExecutor {
 impl SpinLockInstExecutor {
     pub async fn run(thread: VThread) {
         loop {
-            let lock = Box::new(thread.lock.spin().clone().lock_owned().await);
+            before_instruction(&thread, &mut None).await;
+
+            let lock = acquire(&thread, thread.lock.spin().clone().lock_owned()).await;
 
             let (_, behaviour) = instructions::run::<true>(thread.clone(), Some(lock)).await;
             
@@ -70,12 +101,14 @@ impl SysLockBlockExecutor {
         let block_info = thread.get_block_info();
         
         'executor: loop {
+            before_instruction(&thread, &mut None).await;
+
             let inst = thread.get_reg::<u64>(0);
 
             if let Some(info) = block_info.get(inst) {
                 match info {
                     UnlockInfo::Current => {
-                        let lock = Box::new(thread.lock.sys().clone().lock_owned().await);
+                        let lock = acquire(&thread, thread.lock.sys().clone().lock_owned()).await;
                         let (_, behaviour) = instructions::run::<true>(thread.clone(), Some(lock)).await;
                 
                         if let ExecutorBehaviour::Shutdown(shutdown_type) = behaviour {
@@ -89,7 +122,7 @@ impl SysLockBlockExecutor {
                         }
                     }
                     &UnlockInfo::Addr(end) => {
-                        let lock = Box::new(thread.lock.sys().clone().lock_owned().await);
+                        let lock = acquire(&thread, thread.lock.sys().clone().lock_owned()).await;
                         let mut lock: Lock = Some(lock);
 
                         loop {
@@ -113,6 +146,10 @@ impl SysLockBlockExecutor {
                             }
 
                             lock = new_lock;
+
+                            if before_instruction(&thread, &mut lock).await {
+                                lock = Some(acquire(&thread, thread.lock.sys().clone().lock_owned()).await);
+                            }
                         }
                     }
                 }
@@ -138,12 +175,14 @@ impl SpinLockBlockExecutor {
         let block_info = thread.get_block_info();
         
         'executor: loop {
+            before_instruction(&thread, &mut None).await;
+
             let inst = thread.get_reg::<u64>(0);
 
             if let Some(info) = block_info.get(inst) {
                 match info {
                     UnlockInfo::Current => {
-                        let lock = Box::new(thread.lock.spin().clone().lock_owned().await);
+                        let lock = acquire(&thread, thread.lock.spin().clone().lock_owned()).await;
                         let (_, behaviour) = instructions::run::<true>(thread.clone(), Some(lock)).await;
                 
                         if let ExecutorBehaviour::Shutdown(shutdown_type) = behaviour {
@@ -157,7 +196,7 @@ impl SpinLockBlockExecutor {
                         }
                     }
                     &UnlockInfo::Addr(end) => {
-                        let lock = Box::new(thread.lock.spin().clone().lock_owned().await);
+                        let lock = acquire(&thread, thread.lock.spin().clone().lock_owned()).await;
                         let mut lock: Lock = Some(lock);
 
                         loop {
@@ -181,6 +220,10 @@ impl SpinLockBlockExecutor {
                             }
 
                             lock = new_lock;
+
+                            if before_instruction(&thread, &mut lock).await {
+                                lock = Some(acquire(&thread, thread.lock.spin().clone().lock_owned()).await);
+                            }
                         }
                     }
                 }
@@ -45,6 +45,8 @@ pub enum ExecutorBehaviour {
     Shutdown(ShutdownType)
 }
 
+// Shared by every virtual thread of a runtime
+#[derive(Clone)]
 pub enum ExecutorLock {
     None,
     Sys(Arc<Mutex<()>>),
@@ -52,7 +54,7 @@ pub enum ExecutorLock {
 }
 
 impl ExecutorLock {
-    pub fn from_archive(archive: &Arc<Archive>) -> ExecutorLock {
+    pub fn from_archive(archive: &Archive) -> ExecutorLock {
         match archive.conf.executor_kind {
             ExecutorKind::Atomic => ExecutorLock::None,
             ExecutorKind::SpinLockBlock | ExecutorKind::SpinLockInst => ExecutorLock::Spin(Arc::new(SpinMutex::new(()))),
@@ -3,9 +3,54 @@ use crate::{op_codes::{OpCodes, OpLayout}, vm_value::{VMValue, STR_SIGNATURE}, v
 use super::executor::{ExecutorBehaviour, Lock};
 
 pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, ExecutorBehaviour) {
+    thread.count_instruction();
+
+    if thread.runtime.tracer.is_none() && thread.runtime.profiler.is_none() {
+        return execute::<DROP>(thread, lock).await;
+    }
+
+    let (tracer, profiler) = (thread.runtime.tracer.clone(), thread.runtime.profiler.clone());
+    let entry = tracer.as_ref().and_then(|x| x.begin(&thread));
+    let sample = profiler.as_ref().map(|x| x.begin(&thread));
+    let result = execute::<DROP>(thread.clone(), lock).await;
+
+    if let (Some(profiler), Some(sample)) = (profiler, sample) {
+        profiler.end(sample, &thread);
+    }
+
+    if let (Some(tracer), Some(entry)) = (tracer, entry) {
+        tracer.end(entry, &thread);
+    }
+
+    result
+}
+
+async fn execute<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, ExecutorBehaviour) {
     let ip = thread.get_reg::<u64>(0) as usize;
     let op = thread.get_mem::<u8>(ip);
 
+    // Throws a VM error, shutting the VM down with its message when nothing catches it
+    macro_rules! raise {
+        ($message:expr) => {{
+            let behaviour = match thread.clone().raise($message).await {
+                true => ExecutorBehaviour::None,
+                false => ExecutorBehaviour::Shutdown(ShutdownType::Error)
+            };
+
+            return (handle_lock::<DROP>(lock), behaviour);
+        }};
+    }
+
+    // Unwraps a memory access, raising its message on failure
+    macro_rules! checked {
+        ($access:expr) => {
+            match $access {
+                Ok(x) => x,
+                Err(message) => raise!(message)
+            }
+        };
+    }
+
     let behaviour = match op {
         x if x == OpCodes::MOV | OpLayout::R_R => {
             let dest = thread.get_mem::<u8>(ip + 1);
@@ -20,7 +65,7 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
             let base = thread.get_reg::<u64>(thread.get_mem::<u8>(ip + 2)) as usize;
             let offset = thread.get_mem::<u32>(ip + 4) as usize;
 
-            thread.set_reg(dest, thread.get_mem_absolute::<u64>(base + offset * 8));
+            thread.set_reg(dest, checked!(thread.load::<u64>(base + offset * 8).await));
 
             ExecutorBehaviour::None
         }
@@ -29,7 +74,7 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
             let dest = thread.get_mem::<u8>(ip + 2);
             let offset = thread.get_mem::<u32>(ip + 4) as usize;
 
-            thread.set_mem_absolute(base + offset * 8, thread.get_reg::<u64>(dest));
+            checked!(thread.store(base + offset * 8, thread.get_reg::<u64>(dest)).await);
 
             ExecutorBehaviour::None
         }
@@ -48,7 +93,7 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
             let offset = thread.get_mem::<u32>(ip + 4) as usize;
             let immediate = thread.get_mem::<u64>(ip + 8);
 
-            thread.set_mem_absolute(base + offset * 8, immediate);
+            checked!(thread.store(base + offset * 8, immediate).await);
 
             thread.inc_inst(8); // Fat instruction
 
@@ -66,8 +111,8 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
         OpCodes::ADD => {
             let dest_reg = thread.get_mem::<u8>(ip + 1);
             let src_reg = thread.get_mem::<u8>(ip + 2);
-            let dest = VMValue::from(thread.get_reg::<u64>(dest_reg), thread.clone());
-            let src = VMValue::from(thread.get_reg::<u64>(src_reg), thread.clone());
+            let dest = checked!(thread.value(dest_reg).await);
+            let src = checked!(thread.value(src_reg).await);
 
             js_impl::add(&thread, dest_reg, src_reg, dest, src).await;
 
@@ -76,8 +121,8 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
         OpCodes::SUB => {
             let dest_reg = thread.get_mem::<u8>(ip + 1);
             let src_reg = thread.get_mem::<u8>(ip + 2);
-            let dest = VMValue::from(thread.get_reg::<u64>(dest_reg), thread.clone());
-            let src = VMValue::from(thread.get_reg::<u64>(src_reg), thread.clone());
+            let dest = checked!(thread.value(dest_reg).await);
+            let src = checked!(thread.value(src_reg).await);
 
             js_impl::sub(&thread, dest_reg, src_reg, dest, src).await;
 
@@ -86,8 +131,8 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
         OpCodes::MUL => {
             let dest_reg = thread.get_mem::<u8>(ip + 1);
             let src_reg = thread.get_mem::<u8>(ip + 2);
-            let dest = VMValue::from(thread.get_reg::<u64>(dest_reg), thread.clone());
-            let src = VMValue::from(thread.get_reg::<u64>(src_reg), thread.clone());
+            let dest = checked!(thread.value(dest_reg).await);
+            let src = checked!(thread.value(src_reg).await);
 
             js_impl::mul(&thread, dest_reg, src_reg, dest, src).await;
 
@@ -96,8 +141,8 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
         OpCodes::DIV => {
             let dest_reg = thread.get_mem::<u8>(ip + 1);
             let src_reg = thread.get_mem::<u8>(ip + 2);
-            let dest = VMValue::from(thread.get_reg::<u64>(dest_reg), thread.clone());
-            let src = VMValue::from(thread.get_reg::<u64>(src_reg), thread.clone());
+            let dest = checked!(thread.value(dest_reg).await);
+            let src = checked!(thread.value(src_reg).await);
 
             js_impl::div(&thread, dest_reg, src_reg, dest, src).await;
 
@@ -106,8 +151,8 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
         OpCodes::IDIV => {
             let dest_reg = thread.get_mem::<u8>(ip + 1);
             let src_reg = thread.get_mem::<u8>(ip + 2);
-            let dest = VMValue::from(thread.get_reg::<u64>(dest_reg), thread.clone());
-            let src = VMValue::from(thread.get_reg::<u64>(src_reg), thread.clone());
+            let dest = checked!(thread.value(dest_reg).await);
+            let src = checked!(thread.value(src_reg).await);
 
             js_impl::idiv(&thread, dest_reg, src_reg, dest, src).await;
 
@@ -116,54 +161,54 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
         OpCodes::REM => {
             let dest_reg = thread.get_mem::<u8>(ip + 1);
             let src_reg = thread.get_mem::<u8>(ip + 2);
-            let dest = VMValue::from(thread.get_reg::<u64>(dest_reg), thread.clone());
-            let src = VMValue::from(thread.get_reg::<u64>(src_reg), thread.clone());
+            let dest = checked!(thread.value(dest_reg).await);
+            let src = checked!(thread.value(src_reg).await);
             
             js_impl::rem(&thread, dest_reg, src_reg, dest, src).await;
 
             ExecutorBehaviour::None
         }
         OpCodes::CALL => {
+            // Frame: D0..R7, return address. FUNC is caller-saved.
             let addr = thread.get_mem::<u32>(ip + 4);
 
-            thread.push(thread.get_reg::<u64>(6));
-            thread.push(thread.get_reg::<u64>(7));
-            thread.push(thread.get_reg::<u64>(8));
-            thread.push(thread.get_reg::<u64>(9));
-            thread.push(thread.get_reg::<u64>(10));
-            thread.push(thread.get_reg::<u64>(11));
-            thread.push(thread.get_reg::<u64>(12));
-            thread.push(thread.get_reg::<u64>(13));
-            thread.push(thread.get_reg::<u64>(14));
-            thread.push(thread.get_reg::<u64>(15));
-            thread.push(ip as u64 + 8);
-
-            thread.set_reg(0, thread.get_reg::<u64>(1) + addr as u64 * 8);
+            checked!(thread.enter_call());
+
+            for reg in 6..16 {
+                checked!(thread.try_push(thread.get_reg::<u64>(reg)));
+            }
+
+            checked!(thread.try_push(ip as u64 + 8));
+
+            // Word index into Main.bin, not relative to BASE
+            thread.set_reg(0, addr as u64 * 8);
             thread.set_reg(2, thread.get_reg::<u64>(4));
 
-            ExecutorBehaviour::None
+            return (handle_lock::<DROP>(lock), ExecutorBehaviour::None);
         }
         OpCodes::JT => {
+            // Byte offset of the instruction before the target, execution continues 8 bytes after it
             let addr = thread.get_mem::<u32>(ip + 4);
 
             if thread.get_flag(0) {
-                thread.set_reg(0, addr);
+                thread.set_reg(0, addr as u64);
             }
 
             ExecutorBehaviour::None
         }
         OpCodes::JMP => {
+            // Byte offset of the instruction before the target, execution continues 8 bytes after it
             let addr = thread.get_mem::<u32>(ip + 4);
             
-            thread.set_reg(0, addr);
+            thread.set_reg(0, addr as u64);
             
             ExecutorBehaviour::None
         }
         OpCodes::CMP => {
             let v0_reg = thread.get_mem::<u8>(ip + 1);
             let v1_reg = thread.get_mem::<u8>(ip + 2);
-            let v0 = VMValue::from(thread.get_reg::<u64>(v0_reg), thread.clone());
-            let v1 = VMValue::from(thread.get_reg::<u64>(v1_reg), thread.clone());
+            let v0 = checked!(thread.value(v0_reg).await);
+            let v1 = checked!(thread.value(v1_reg).await);
             let cmp_type = thread.get_mem::<u8>(ip + 3);
 
             thread.set_flag(0, match cmp_type {
@@ -173,7 +218,7 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
                 3 => js_impl::lte(&thread, v0_reg, v1_reg, v0, v1).await,
                 4 => js_impl::gt(&thread, v0_reg, v1_reg, v0, v1).await,
                 5 => js_impl::gte(&thread, v0_reg, v1_reg, v0, v1).await,
-                _ => panic!("Unsupported Operation")
+                _ => raise!(format!("Unsupported comparison type {cmp_type}."))
             });
 
             ExecutorBehaviour::None
@@ -181,14 +226,14 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
         OpCodes::PUSHR => {
             let reg = thread.get_mem::<u8>(ip + 1);
 
-            thread.push(thread.get_reg::<u64>(reg));
+            checked!(thread.try_push(thread.get_reg::<u64>(reg)));
 
             ExecutorBehaviour::None
         }
         OpCodes::PUSHI => {
             let immediate = thread.get_mem::<u64>(ip + 8);
             
-            thread.push(immediate);
+            checked!(thread.try_push(immediate));
 
             thread.inc_inst(8); // Fat instruction
 
@@ -197,18 +242,24 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
         OpCodes::POP => {
             let reg = thread.get_mem::<u8>(ip + 1);
 
-            thread.set_reg(reg, thread.pop());
+            thread.set_reg(reg, checked!(thread.try_pop()));
 
             ExecutorBehaviour::None
         }
         OpCodes::INT => {
             let id = thread.get_mem::<u8>(ip + 1);
 
-            thread.inc_inst(8); // Pre-increase for moving `thread` variable
+            // Increased afterwards so errors, faults and register dumps report this instruction, unless a throw jumped to a handler
+            let (lock, behaviour) = vm_intrinsics::call::<DROP>(thread.clone(), id, lock).await;
 
-            return vm_intrinsics::call::<DROP>(thread, id, lock).await;
+            if thread.get_reg::<u64>(0) == ip as u64 && matches!(behaviour, ExecutorBehaviour::None) {
+                thread.inc_inst(8);
+            }
+
+            return (lock, behaviour);
         }
         OpCodes::ENV => {
+            // Extension id in bytes 0..3, including the opcode byte
             let extension_id = thread.get_mem::<u32>(ip) & 0x00FFFFFF;
             let function_id = thread.get_mem::<u32>(ip + 4);
 
@@ -217,6 +268,7 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
             return thread.get_extension(extension_id).function_call(thread, lock, function_id, DROP);
         }
         OpCodes::ENVJ => {
+            // Extension id in bytes 0..3, including the opcode byte
             let extension_id = thread.get_mem::<u32>(ip) & 0x00FFFFFF;
             let interrupt_id = thread.get_mem::<u32>(ip + 4);
 
@@ -225,33 +277,35 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
             return thread.get_extension(extension_id).interrupt_call(thread, lock, interrupt_id, DROP);
         }
         OpCodes::SPAWN => {
+            let reg = thread.get_mem::<u8>(ip + 1);
+            // Word index into Main.bin, not relative to BASE
             let addr = thread.get_mem::<u32>(ip + 4);
+            let id = thread.runtime.spawn_thread(addr as u64 * 8).await;
 
-            thread.spawn(thread.get_reg::<u64>(1) + addr as u64 * 8).await;
+            // INST means no register was given
+            if reg != 0 {
+                thread.set_reg(reg, (id as f64).to_bits());
+            }
 
             ExecutorBehaviour::None
         }
         OpCodes::RET => {
             let fp = thread.get_reg::<u64>(2);
-            
-            let addr = thread.get_mem_absolute::<u64>(fp as usize + 8);
-
-            thread.set_reg::<u64>(6, fp + 11 * 8);
-            thread.set_reg::<u64>(7, fp + 10 * 8);
-            thread.set_reg::<u64>(8, fp + 9 * 8);
-            thread.set_reg::<u64>(9, fp + 8 * 8);
-            thread.set_reg::<u64>(10, fp + 7 * 8);
-            thread.set_reg::<u64>(11, fp + 6 * 8);
-            thread.set_reg::<u64>(12, fp + 5 * 8);
-            thread.set_reg::<u64>(13, fp + 4 * 8);
-            thread.set_reg::<u64>(14, fp + 3 * 8);
-            thread.set_reg::<u64>(15, fp + 2 * 8);
+
+            checked!(thread.leave_call());
+
+            let addr = checked!(thread.load::<u64>(fp as usize + 8).await);
+
+            // Restores the saved values of D0..R7
+            for reg in 6..16 {
+                thread.set_reg::<u64>(reg, checked!(thread.load::<u64>(fp as usize + (17 - reg as usize) * 8).await));
+            }
 
             thread.set_reg(0, addr);
-            thread.set_reg(2, thread.get_reg::<u64>(4) + 88);
-            thread.set_reg(4, thread.get_reg::<u64>(2));
+            thread.set_reg(4, fp + 11 * 8);
+            thread.discard_handlers();
 
-            ExecutorBehaviour::None
+            return (handle_lock::<DROP>(lock), ExecutorBehaviour::None);
         }
         OpCodes::SUB32 => {
             let reg = thread.get_mem::<u8>(ip + 1);
@@ -274,12 +328,12 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
         }
         OpCodes::DROP => {
             let reg = thread.get_mem::<u8>(ip + 1);
-            let data = VMValue::from(thread.get_reg::<u64>(reg), thread.clone());
+            let data = checked!(thread.value(reg).await);
 
-            if let VMValue::VarStr(vmstr) = data {
-                vmstr.drop().await;
-            } else {
-                panic!("Invalid Operation");
+            match data {
+                VMValue::VarStr(vmstr) => vmstr.drop().await,
+                VMValue::ConstStr(_) => raise!("Cannot drop a constant string."),
+                VMValue::Float(_) => raise!("Cannot drop a non-string value.")
             }
 
             thread.set_reg::<u64>(reg, STR_SIGNATURE);
@@ -312,23 +366,45 @@ pub async fn run<const DROP: bool>(thread: VThread, lock: Lock) -> (Lock, Execut
             let offset = thread.get_mem::<u32>(ip + 4) as u64;
     
             if index < 1 {
-                thread.set_error_data(format!("Array Index {index} out of bound.")).await;
-                
-                ExecutorBehaviour::Shutdown(ShutdownType::Error)
-            } else {
-                let addr = (base + offset * 8) as usize;
-                let arrlen = thread.get_mem_absolute::<u64>(addr);
-    
-                if index > arrlen {
-                    thread.set_error_data(format!("Array Index {index} out of bound.")).await;
+                raise!(format!("Array Index {index} out of bound."));
+            }
 
-                    ExecutorBehaviour::Shutdown(ShutdownType::Error)
-                } else {
-                    thread.set_reg(reg, thread.get_mem_absolute::<u64>(addr + 8 * index as usize));
-            
-                    ExecutorBehaviour::None
-                }
+            let addr = (base + offset * 8) as usize;
+            let arrlen = checked!(thread.load::<u64>(addr).await);
+
+            if index > arrlen {
+                raise!(format!("Array Index {index} out of bound."));
             }
+
+            thread.set_reg(reg, checked!(thread.load::<u64>(addr + 8 * index as usize).await));
+
+            ExecutorBehaviour::None
+        }
+        OpCodes::TRY => {
+            let addr = thread.get_mem::<u32>(ip + 4);
+
+            checked!(thread.enter_try(addr as u64));
+
+            ExecutorBehaviour::None
+        }
+        OpCodes::ENDTRY => {
+            checked!(thread.leave_try());
+
+            ExecutorBehaviour::None
+        }
+        OpCodes::THROW => {
+            let reg = thread.get_mem::<u8>(ip + 1);
+            let payload = thread.get_reg::<u64>(reg);
+
+            if !thread.throw(payload) {
+                let payload = checked!(thread.value(reg).await);
+
+                thread.set_error_data(format!("Uncaught exception: {payload}")).await;
+
+                return (handle_lock::<DROP>(lock), ExecutorBehaviour::Shutdown(ShutdownType::Error));
+            }
+
+            return (handle_lock::<DROP>(lock), ExecutorBehaviour::None);
         }
         _ => panic!("Unsupported Instruction")
     };
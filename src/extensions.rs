@@ -1,4 +1,4 @@
-use std::{env, sync::Arc, collections::{HashMap, hash_map::{Values, Iter}}};
+use std::{sync::Arc, collections::{HashMap, hash_map::{Values, Iter}}};
 use libloading::Library;
 
 use crate::{virtual_thread::VThread, executor::executor::{Lock, ExecutorBehaviour}, runtime::Runtime, event::EventType};
@@ -6,27 +6,26 @@ use crate::{virtual_thread::VThread, executor::executor::{Lock, ExecutorBehaviou
 pub struct Extensions(HashMap<u32, Arc<Extension>>);
 
 impl Extensions {
-    pub fn parse_from_env() -> Extensions {
-        let iter = env::args().skip_while(|x| x != "--ext").skip(1);
-        let mut ext_paths = Vec::new();
-        
-        for x in iter {
-            if let Some(i) = x.find('=') {
-                let id = (&x[0..i]).parse::<u32>().unwrap();
-
-                ext_paths.push((id, (&x[(i + 1)..]).to_owned()));
-            } else {
-                break;
-            }
-        }
+    pub fn load(ext_paths: Vec<(u32, String)>) -> Result<Extensions, libloading::Error> {
+        Ok(Extensions(ext_paths.into_iter().map(|(id, x)| Ok((id, Arc::new(Extension::new(x)?)))).collect::<Result<HashMap<_, _>, libloading::Error>>()?))
+    }
 
-        Extensions(ext_paths.into_iter().map(|(id, x)| (id, Arc::new(Extension::new(x)))).collect::<HashMap<_, _>>())
+    pub fn empty() -> Extensions {
+        Extensions(HashMap::new())
     }
 
     pub fn get(&self, id: u32) -> Arc<Extension> {
         self.0.get(&id).unwrap().clone()
     }
 
+    pub fn insert(&mut self, id: u32, extension: impl Into<Extension>) {
+        self.0.insert(id, Arc::new(extension.into()));
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.0.contains_key(&id)
+    }
+
     pub fn iter<'a>(&self) -> Values<'_, u32, Arc<Extension>> {
         self.0.values()
     }
@@ -39,40 +38,105 @@ impl Extensions {
 pub type ExtensionCall = fn(VThread, Lock, u32, bool) -> (Lock, ExecutorBehaviour);
 pub type EventCall = fn(Arc<Runtime>, EventType);
 pub type InitCall = fn(Arc<Runtime>, u32);
-pub struct Extension {
-    _lib: Library, 
+pub type ThreadErrorCall = fn(Arc<Runtime>, u64, u64);
+
+// Hooks of an extension, `ENV` and `ENVJ` call `function_call` and `interrupt_call` with the function id
+pub trait VmExtension: Send + Sync {
+    fn init(&self, runtime: Arc<Runtime>, id: u32);
+
+    fn function_call(&self, _vthread: VThread, _lock: Lock, _id: u32, _drop: bool) -> (Lock, ExecutorBehaviour) {
+        panic!("This Extension does not support Function Call")
+    }
+
+    fn interrupt_call(&self, _vthread: VThread, _lock: Lock, _id: u32, _drop: bool) -> (Lock, ExecutorBehaviour) {
+        panic!("This Extension does not support Interrupt Call")
+    }
+
+    fn dispatch_event(&self, _runtime: Arc<Runtime>, _event: EventType) {}
+
+    // A thread was disposed after an uncaught error, see `Runtime::thread_faults`.
+    // Not an `EventType` variant, which would change the layout libraries built against it receive.
+    fn thread_error(&self, _runtime: Arc<Runtime>, _thread: u64, _inst: u64) {}
+}
+
+// Shared library exporting `vm_init`, `vm_event_recv` and optionally `vm_function_call`, `vm_interrupt`, `vm_thread_error`
+pub struct LibraryExtension {
+    _lib: Library,
     env_fn: Option<ExtensionCall>, 
     envj_fn: Option<ExtensionCall>,
     init_fn: InitCall,
     event: EventCall,
+    thread_error_fn: Option<ThreadErrorCall>,
 }
 
-impl Extension {
-    pub fn new(path: String) -> Extension {
+impl LibraryExtension {
+    pub fn new(path: String) -> Result<LibraryExtension, libloading::Error> {
         unsafe {
-            let lib = Library::new(path).unwrap();
+            let lib = Library::new(path)?;
 
-            Extension {
+            Ok(LibraryExtension {
                 env_fn: lib.get::<ExtensionCall>(b"vm_function_call").ok().map(|x| *x),
                 envj_fn: lib.get::<ExtensionCall>(b"vm_interrupt").ok().map(|x| *x),
-                event: *lib.get::<EventCall>(b"vm_event_recv").unwrap(),
-                init_fn: *lib.get::<InitCall>(b"vm_init").unwrap(),
+                event: *lib.get::<EventCall>(b"vm_event_recv")?,
+                init_fn: *lib.get::<InitCall>(b"vm_init")?,
+                thread_error_fn: lib.get::<ThreadErrorCall>(b"vm_thread_error").ok().map(|x| *x),
                 _lib: lib,
-            }
+            })
         }
     }
+}
 
-    pub fn init(&self, runtime: Arc<Runtime>, id: u32) { (self.init_fn)(runtime, id) }
+impl VmExtension for LibraryExtension {
+    fn init(&self, runtime: Arc<Runtime>, id: u32) { (self.init_fn)(runtime, id) }
 
-    pub fn function_call(&self, vthread: VThread, lock: Lock, id: u32, drop: bool) -> (Lock, ExecutorBehaviour) {
+    fn function_call(&self, vthread: VThread, lock: Lock, id: u32, drop: bool) -> (Lock, ExecutorBehaviour) {
         (self.env_fn.expect("This Library does not support Function Call or does not contain"))(vthread, lock, id, drop)
     }
 
-    pub fn interrupt_call(&self, vthread: VThread, lock: Lock, id: u32, drop: bool) -> (Lock, ExecutorBehaviour) {
+    fn interrupt_call(&self, vthread: VThread, lock: Lock, id: u32, drop: bool) -> (Lock, ExecutorBehaviour) {
         (self.envj_fn.expect("This Library does not support Interrupt Call or does not contain"))(vthread, lock, id, drop)
     }
 
-    pub fn dispatch_event(&self, runtime: Arc<Runtime>, event: EventType) {
+    fn dispatch_event(&self, runtime: Arc<Runtime>, event: EventType) {
         (self.event)(runtime, event)
     }
-}
\ No newline at end of file
+
+    fn thread_error(&self, runtime: Arc<Runtime>, thread: u64, inst: u64) {
+        if let Some(thread_error_fn) = self.thread_error_fn {
+            thread_error_fn(runtime, thread, inst)
+        }
+    }
+}
+
+// Registered extension, either a shared library or an in-process `VmExtension`
+pub struct Extension(Box<dyn VmExtension>);
+
+impl Extension {
+    pub fn new(path: String) -> Result<Extension, libloading::Error> {
+        LibraryExtension::new(path).map(Extension::from)
+    }
+
+    pub fn init(&self, runtime: Arc<Runtime>, id: u32) { self.0.init(runtime, id) }
+
+    pub fn function_call(&self, vthread: VThread, lock: Lock, id: u32, drop: bool) -> (Lock, ExecutorBehaviour) {
+        self.0.function_call(vthread, lock, id, drop)
+    }
+
+    pub fn interrupt_call(&self, vthread: VThread, lock: Lock, id: u32, drop: bool) -> (Lock, ExecutorBehaviour) {
+        self.0.interrupt_call(vthread, lock, id, drop)
+    }
+
+    pub fn dispatch_event(&self, runtime: Arc<Runtime>, event: EventType) {
+        self.0.dispatch_event(runtime, event)
+    }
+
+    pub fn thread_error(&self, runtime: Arc<Runtime>, thread: u64, inst: u64) {
+        self.0.thread_error(runtime, thread, inst)
+    }
+}
+
+impl<T: VmExtension + 'static> From<T> for Extension {
+    fn from(extension: T) -> Extension {
+        Extension(Box::new(extension))
+    }
+}
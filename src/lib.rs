@@ -0,0 +1,75 @@
+#![feature(core_intrinsics, let_chains)]
+
+//! OpenEntry VM as a library.
+//!
+//! `VmBuilder` is the embedding API: it builds a `Vm` from an `Archive` or raw Main.bin and config,
+//! with in-process extensions, output sinks and an optional host tokio runtime.
+
+pub mod embed;
+pub mod extensions;
+pub mod event;
+
+pub(crate) mod extension_data;
+pub(crate) mod virtual_thread;
+pub(crate) mod thread_counter;
+pub(crate) mod shared_memory;
+pub(crate) mod block_info;
+pub(crate) mod debug_info;
+pub(crate) mod vm_config;
+pub(crate) mod vm_value;
+pub(crate) mod op_codes;
+pub(crate) mod executor;
+pub(crate) mod archive;
+pub(crate) mod runtime;
+pub(crate) mod string;
+pub(crate) mod stack;
+pub(crate) mod cli;
+pub(crate) mod asm;
+pub(crate) mod verifier;
+pub(crate) mod debugger;
+pub(crate) mod trace;
+pub(crate) mod profiler;
+pub(crate) mod sync_objects;
+pub(crate) mod channels;
+pub(crate) mod ffi;
+
+mod vm_intrinsics;
+mod register;
+mod js_impl;
+mod utils;
+mod tests;
+
+pub use archive::{Archive, ArchiveError};
+pub use embed::{VmBuilder, Vm, BuildError};
+pub use extensions::{Extensions, Extension, VmExtension};
+pub use event::EventType;
+pub use executor::executor::{Lock, ExecutorBehaviour};
+pub use runtime::{Runtime, RuntimeOptions, RunOutcome};
+pub use thread_counter::{ShutdownType, ThreadFault};
+pub use verifier::VerifyError;
+pub use virtual_thread::VThread;
+pub use vm_config::{VMConfig, ExecutorKind, ThreadingKind, ErrorPolicy};
+
+// Used by the `open-entry-vm` binary, not part of the embedding API
+#[doc(hidden)]
+pub mod bin {
+    pub use crate::{block_info::BlockInfo, debug_info::DebugInfo, trace::Tracer, profiler::Profiler};
+
+    pub mod asm {
+        pub use crate::asm::{assemble, disassemble, sections};
+    }
+
+    pub mod cli {
+        pub use crate::cli::{parse, Command, RunOptions, PackOptions, DebugMode, USAGE, EXIT_USAGE, EXIT_ARCHIVE, EXIT_VERIFY, EXIT_EXTENSION};
+    }
+
+    pub mod debugger {
+        pub use crate::debugger::{Debugger, repl, dap};
+    }
+}
+
+#[cfg(target_pointer_width = "32")]
+compile_error!("This program is only for 64-bit or higher operating system.");
+
+#[cfg(all(not(unix), not(windows)))]
+compile_error!("Unsupported Operating System");
@@ -1,44 +1,233 @@
-#![feature(core_intrinsics, let_chains)]
-
-use archive::Archive;
-use runtime::Runtime;
-
-mod extension_data;
-mod virtual_thread;
-mod thread_counter;
-mod vm_intrinsics;
-mod shared_memory;
-mod block_info;
-mod extensions;
-mod vm_config;
-mod register;
-mod vm_value;
-mod op_codes;
-mod executor;
-mod archive;
-mod js_impl;
-mod runtime;
-mod string;
-mod stack;
-mod event;
-mod utils;
-mod tests;
-mod ffi;
-
-#[cfg(target_pointer_width = "32")]
-compile_error!("This program is only for 64-bit or higher operating system.");
-
-#[cfg(all(not(unix), not(windows)))]
-compile_error!("Unsupported Operating System");
+use std::{env, process, fs::{self, File}, io::{BufWriter, Write}, sync::Arc};
+
+use open_entry_vm::{
+    Archive, VmBuilder, BuildError, ShutdownType, VMConfig,
+    bin::{asm, cli::{self, Command, RunOptions, PackOptions, DebugMode}, BlockInfo, DebugInfo, Tracer, Profiler, debugger::{self, Debugger, dap::{self, Transport}}},
+};
+
+fn assemble(source: &str, output: &str) -> Result<(), String> {
+    let text = fs::read_to_string(source).map_err(|err| err.to_string())?;
+    let code = asm::assemble(&text).map_err(|err| err.to_string())?;
+
+    fs::write(output, code).map_err(|err| err.to_string())
+}
+
+fn disassemble(input: &str, output: Option<String>) -> Result<(), String> {
+    let bytes = fs::read(input).map_err(|err| err.to_string())?;
+    let code = if bytes.starts_with(&[0x1F, 0x8B]) {
+        Archive::read(bytes.as_slice()).map_err(|err| err.to_string())?.code.into_vec()
+    } else {
+        bytes
+    };
+
+    let text = asm::disassemble(&code)?;
+
+    match output {
+        Some(output) => fs::write(output, text).map_err(|err| err.to_string()),
+        None => {
+            print!("{text}");
+
+            Ok(())
+        }
+    }
+}
+
+fn pack(options: PackOptions) -> Result<(), String> {
+    let code = fs::read(&options.code).map_err(|err| format!("{}: {err}", options.code))?;
+
+    if asm::sections(&code).is_none() {
+        return Err(format!("{}: Main.bin is too short or its data length exceeds the file.", options.code));
+    }
+
+    let block_info = match &options.block_info {
+        Some(path) => {
+            let buffer = fs::read(path).map_err(|err| format!("{path}: {err}"))?;
+
+            Some(BlockInfo::read(buffer.into_boxed_slice()).map_err(|err| format!("{path}: {err}"))?)
+        }
+        None => None
+    };
+
+    let mut conf = VMConfig::default();
+
+    options.conf.apply(&mut conf);
+
+    let mut archive = Archive::new(code.into_boxed_slice(), conf, block_info);
+
+    if let Some(path) = &options.debug_info {
+        let buffer = fs::read(path).map_err(|err| format!("{path}: {err}"))?;
+
+        archive.debug_info = Some(DebugInfo::read(buffer.into_boxed_slice()).map_err(|err| format!("{path}: {err}"))?);
+    }
+
+    if let Some(assets) = &options.assets {
+        archive.add_dir(assets).map_err(|err| format!("{assets}: {err}"))?;
+    }
+
+    archive.save(&options.output).map_err(|err| format!("{}: {err}", options.output))
+}
+
+fn run(options: RunOptions) -> i32 {
+    let mut builder = match VmBuilder::open(&options.archive) {
+        Ok(builder) => builder.config(|conf| options.conf.apply(conf)),
+        Err(err) => {
+            eprintln!("error: Cannot load `{}`: {err}", options.archive);
+
+            return cli::EXIT_ARCHIVE;
+        }
+    };
+
+    for (id, path) in options.extensions {
+        builder = match builder.load_extension(id, path.clone()) {
+            Ok(builder) => builder,
+            Err(err) => {
+                eprintln!("error: Cannot load extension `{path}`: {err}");
+
+                return cli::EXIT_EXTENSION;
+            }
+        };
+    }
+
+    // Checked before a debugger or output file is set up
+    match builder.verify() {
+        Err(BuildError::MissingBlockInfo) => {
+            eprintln!("error: {}", BuildError::MissingBlockInfo);
+
+            return cli::EXIT_USAGE;
+        }
+        Err(BuildError::Verify(errors)) => {
+            eprintln!("error: `{}` failed verification:", options.archive);
+
+            for err in errors {
+                eprintln!("  {err}");
+            }
+
+            return cli::EXIT_VERIFY;
+        }
+        Ok(()) => {}
+    }
+
+    let mut dap_handle = None;
+    let dap_stdio = matches!(options.debug, Some(DebugMode::DapStdio));
+
+    if let Some(mode) = options.debug {
+        let (debugger, events) = Debugger::new(true);
+        let (code, debug_info) = (builder.archive().code.clone(), builder.archive().debug_info.clone());
+
+        match mode {
+            DebugMode::Repl => debugger::repl::start(debugger.clone(), events, code, debug_info),
+            DebugMode::DapStdio | DebugMode::DapTcp(_) => {
+                let transport = if let DebugMode::DapTcp(port) = mode { Transport::Tcp(port) } else { Transport::Stdio };
+
+                match dap::start(debugger.clone(), events, code, debug_info, transport) {
+                    Ok(handle) => {
+                        // Standard output carries the protocol, so the program's output goes to the client
+                        if dap_stdio {
+                            builder = builder.stdout(handle.output("stdout")).stderr(handle.output("stderr"));
+                        }
+
+                        dap_handle = Some(handle);
+                    }
+                    Err(err) => {
+                        eprintln!("error: Cannot start the debug adapter: {err}");
+
+                        return cli::EXIT_USAGE;
+                    }
+                }
+            }
+        }
+
+        builder = builder.debugger(debugger);
+    }
+
+    if let Some(trace) = options.trace {
+        let tracer = File::create(&trace.output).and_then(|file| Tracer::new(trace.format, trace.filter, Box::new(BufWriter::new(file))));
+
+        match tracer {
+            Ok(tracer) => builder = builder.tracer(Arc::new(tracer)),
+            Err(err) => {
+                eprintln!("error: Cannot create trace file `{}`: {err}", trace.output);
+
+                return cli::EXIT_USAGE;
+            }
+        }
+    }
+
+    if options.profile.is_some() || options.profile_folded.is_some() {
+        let create = |path: Option<String>| path.map(|path| match File::create(&path) {
+            Ok(file) => Ok(Box::new(BufWriter::new(file)) as Box<dyn Write + Send>),
+            Err(err) => Err(format!("Cannot create profile file `{path}`: {err}"))
+        }).transpose();
+
+        match create(options.profile).and_then(|report| Ok((report, create(options.profile_folded)?))) {
+            Ok((report, folded)) => builder = builder.profiler(Arc::new(Profiler::new(report, folded))),
+            Err(message) => {
+                eprintln!("error: {message}");
+
+                return cli::EXIT_USAGE;
+            }
+        }
+    }
+
+    let vm = match builder.build() {
+        Ok(vm) => vm,
+        Err(err) => {
+            eprintln!("error: {err}");
+
+            return cli::EXIT_VERIFY;
+        }
+    };
+
+    let outcome = vm.run();
+    let message = match (outcome.shutdown_type, &outcome.error_data) {
+        (ShutdownType::None, _) => Some(String::from("OpenEntry VM Stopped Running with Unknown Reason.")),
+        (ShutdownType::Error, Some(data)) => Some(format!("OpenEntry VM Stopped Running with Error: \n{data}")),
+        (ShutdownType::Error, None) => Some(String::from("OpenEntry VM Stopped Running with Error.\nExtra Error Details weren't provided.")),
+        _ => None
+    };
+
+    // Keeps standard output free for the protocol when serving DAP on it
+    match message {
+        Some(message) if dap_stdio => eprintln!("{message}"),
+        Some(message) => println!("{message}"),
+        None => {}
+    }
+
+    if let Some(handle) = dap_handle {
+        handle.exited(outcome.exit_code);
+    }
+
+    outcome.exit_code
+}
 
 fn main() {
     if std::mem::size_of::<usize>() < 8 { panic!("This program is only for 64-bit or higher operating system.") }
 
-    // Not implemented yet
+    let result = match cli::parse(env::args().skip(1)) {
+        Ok(Command::Run(options)) => process::exit(run(options)),
+        Ok(Command::Assemble { source, output }) => assemble(&source, &output).map_err(|err| format!("{source}: {err}")),
+        Ok(Command::Disassemble { input, output }) => disassemble(&input, output).map_err(|err| format!("{input}: {err}")),
+        Ok(Command::Pack(options)) => pack(options),
+        Ok(Command::Help) => {
+            println!("{}", cli::USAGE);
+
+            Ok(())
+        }
+        Ok(Command::Version) => {
+            println!("open-entry-vm {}", env!("CARGO_PKG_VERSION"));
+
+            Ok(())
+        }
+        Err(message) => {
+            eprintln!("error: {message}\n\n{}", cli::USAGE);
 
-    let archive = Archive::open("./Demo.entx");
+            process::exit(cli::EXIT_USAGE);
+        }
+    };
 
-    let runtime = Runtime::new(archive);
+    if let Err(message) = result {
+        eprintln!("error: {message}");
 
-    runtime.run();
-}
\ No newline at end of file
+        process::exit(1);
+    }
+}
@@ -28,6 +28,9 @@ utils::gen_enum!(OpCodes, u8, [
     LEA1  = 0x18,
     LEA2  = 0x19,
     ELEM  = 0x1A,
+    TRY   = 0x1B,
+    ENDTRY = 0x1C,
+    THROW = 0x1D,
 ]);
 
 utils::gen_enum!(OpLayout, u8, [
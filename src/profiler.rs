@@ -0,0 +1,208 @@
+use std::{sync::Mutex, collections::HashMap, io::Write, time::{Duration, Instant}, fmt::Write as _};
+
+use crate::{virtual_thread::VThread, op_codes::OpCodes, archive::Archive, asm::{Instruction, disassembler::format_instruction}};
+
+const HOT_ADDRESSES: usize = 20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Stat {
+    fn add(&mut self, time: Duration) {
+        self.count += 1;
+        self.time += time;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionStat {
+    pub calls: u64,
+    // Instructions executed by the function itself and the time they took
+    pub own: Stat,
+}
+
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub total: Stat,
+    pub lock_wait: Stat,
+    pub opcodes: HashMap<u8, Stat>,
+    pub addresses: HashMap<u64, Stat>,
+    // Keyed by entry point
+    pub functions: HashMap<u64, FunctionStat>,
+    pub extensions: HashMap<u32, Stat>,
+    // Own time of each call stack, outermost entry point first
+    pub stacks: HashMap<Vec<u64>, Duration>,
+    threads: HashMap<u64, Vec<u64>>,
+}
+
+// State of an instruction before it runs
+pub struct ProfileSample {
+    ip: u64,
+    op: u8,
+    depth: u64,
+    start: Instant,
+}
+
+pub struct Profiler {
+    profile: Mutex<Profile>,
+    report: Mutex<Option<Box<dyn Write + Send>>>,
+    folded: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+fn micros(time: Duration) -> f64 {
+    time.as_secs_f64() * 1e6
+}
+
+// Busiest entries first
+fn sorted<K: Copy + Ord>(map: &HashMap<K, Stat>) -> Vec<(K, Stat)> {
+    let mut entries = map.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>();
+
+    entries.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(&b.0)));
+    entries
+}
+
+impl Profiler {
+    pub fn new(report: Option<Box<dyn Write + Send>>, folded: Option<Box<dyn Write + Send>>) -> Profiler {
+        Profiler { profile: Mutex::new(Profile::default()), report: Mutex::new(report), folded: Mutex::new(folded) }
+    }
+
+    pub fn begin(&self, thread: &VThread) -> ProfileSample {
+        let ip = thread.get_reg::<u64>(0);
+
+        ProfileSample { ip, op: thread.get_mem::<u8>(ip as usize), depth: thread.call_depth(), start: Instant::now() }
+    }
+
+    pub fn end(&self, sample: ProfileSample, thread: &VThread) {
+        let time = sample.start.elapsed();
+        let op = if sample.op & 0b10000000 != 0 { OpCodes::MOV } else { sample.op };
+
+        let mut profile = self.profile.lock().unwrap();
+        let profile = &mut *profile;
+
+        profile.total.add(time);
+        profile.opcodes.entry(op).or_default().add(time);
+        profile.addresses.entry(sample.ip).or_default().add(time);
+
+        if op == OpCodes::ENV || op == OpCodes::ENVJ {
+            profile.extensions.entry(thread.get_mem::<u32>(sample.ip as usize) & 0x00FFFFFF).or_default().add(time);
+        }
+
+        // A thread starts in the function it was spawned at
+        let stack = profile.threads.entry(thread.id).or_insert_with(|| {
+            profile.functions.entry(sample.ip).or_default().calls += 1;
+
+            vec![sample.ip]
+        });
+
+        profile.functions.entry(*stack.last().unwrap_or(&sample.ip)).or_default().own.add(time);
+
+        match profile.stacks.get_mut(stack.as_slice()) {
+            Some(total) => *total += time,
+            None => { profile.stacks.insert(stack.clone(), time); }
+        }
+
+        let depth = thread.call_depth();
+
+        if depth > sample.depth {
+            let entry = thread.get_reg::<u64>(0);
+
+            profile.functions.entry(entry).or_default().calls += 1;
+            stack.push(entry);
+        } else {
+            // A throw can unwind several calls at once
+            for _ in depth..sample.depth {
+                if stack.len() > 1 {
+                    stack.pop();
+                }
+            }
+        }
+    }
+
+    pub fn lock_wait(&self, time: Duration) {
+        self.profile.lock().unwrap().lock_wait.add(time);
+    }
+
+    pub fn profile(&self) -> std::sync::MutexGuard<'_, Profile> {
+        self.profile.lock().unwrap()
+    }
+
+    fn function_name(archive: &Archive, entry: u64) -> String {
+        match archive.debug_info.as_ref().and_then(|x| x.range(entry)).and_then(|x| x.function.clone()) {
+            Some(name) => name,
+            None => format!("0x{entry:04X}")
+        }
+    }
+
+    pub fn report(&self, archive: &Archive) -> String {
+        let profile = self.profile();
+        let mut out = String::new();
+
+        let _ = writeln!(out, "----- Profile -----");
+        let _ = writeln!(out, "Executed {} instructions in {:.1} us, waited {:.1} us for the executor lock ({} times)\n",
+            profile.total.count, micros(profile.total.time), micros(profile.lock_wait.time), profile.lock_wait.count);
+
+        let _ = writeln!(out, "{:<24} {:>12} {:>14}", "OPCODE", "COUNT", "TIME (us)");
+
+        for (op, stat) in sorted(&profile.opcodes) {
+            let _ = writeln!(out, "{:<24} {:>12} {:>14.1}", OpCodes::name(op).unwrap_or("?").to_ascii_lowercase(), stat.count, micros(stat.time));
+        }
+
+        let mut functions = profile.functions.iter().collect::<Vec<_>>();
+
+        functions.sort_by(|a, b| b.1.own.time.cmp(&a.1.own.time).then(a.0.cmp(b.0)));
+
+        let _ = writeln!(out, "\n{:<24} {:>12} {:>14} {:>14}", "FUNCTION", "CALLS", "INSTRUCTIONS", "SELF (us)");
+
+        for (&entry, stat) in functions {
+            let _ = writeln!(out, "{:<24} {:>12} {:>14} {:>14.1}", Profiler::function_name(archive, entry), stat.calls, stat.own.count, micros(stat.own.time));
+        }
+
+        let _ = writeln!(out, "\n{:<40} {:>12} {:>14}", "ADDRESS", "COUNT", "TIME (us)");
+
+        for (ip, stat) in sorted(&profile.addresses).into_iter().take(HOT_ADDRESSES) {
+            let inst = Instruction::decode(&archive.code, ip as usize).and_then(|x| format_instruction(&x)).unwrap_or_default();
+
+            let _ = writeln!(out, "{:<40} {:>12} {:>14.1}", format!("0x{ip:04X} {inst}"), stat.count, micros(stat.time));
+        }
+
+        if !profile.extensions.is_empty() {
+            let _ = writeln!(out, "\n{:<24} {:>12} {:>14}", "EXTENSION", "CALLS", "TIME (us)");
+
+            for (id, stat) in sorted(&profile.extensions) {
+                let _ = writeln!(out, "{:<24} {:>12} {:>14.1}", id, stat.count, micros(stat.time));
+            }
+        }
+
+        out
+    }
+
+    // One `outer;inner <nanoseconds>` line per call stack, the input format of flamegraph tools
+    pub fn folded(&self, archive: &Archive) -> String {
+        let profile = self.profile();
+        let mut lines = profile.stacks.iter().map(|(stack, time)| {
+            let names = stack.iter().map(|&x| Profiler::function_name(archive, x)).collect::<Vec<_>>();
+
+            format!("{} {}", names.join(";"), time.as_nanos())
+        }).collect::<Vec<_>>();
+
+        lines.sort();
+
+        lines.into_iter().map(|x| x + "\n").collect()
+    }
+
+    // Writes the report and the folded stacks to their outputs
+    pub fn dump(&self, archive: &Archive) {
+        if let Some(output) = self.report.lock().unwrap().as_mut() {
+            let _ = output.write_all(self.report(archive).as_bytes());
+            let _ = output.flush();
+        }
+
+        if let Some(output) = self.folded.lock().unwrap().as_mut() {
+            let _ = output.write_all(self.folded(archive).as_bytes());
+            let _ = output.flush();
+        }
+    }
+}
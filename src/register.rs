@@ -1,3 +1,16 @@
+pub const REGISTER_NAMES: [&str; 16] = [
+    "INST", "BASE", "FUNC", "OBJ", "TOP", "RET0", "D0", "D1",
+    "R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7",
+];
+
+pub fn register_name(idx: u8) -> Option<&'static str> {
+    REGISTER_NAMES.get(idx as usize).copied()
+}
+
+pub fn register_id(name: &str) -> Option<u8> {
+    REGISTER_NAMES.iter().position(|x| x.eq_ignore_ascii_case(name)).map(|x| x as u8)
+}
+
 #[derive(Clone, Copy)]
 pub union Register {
     pub r64: u64,
@@ -1,29 +1,72 @@
-use tokio::{runtime::{Runtime as TokioRuntime, Builder as TokioBuilder}, sync::{mpsc::{self, UnboundedReceiver}, Mutex, RwLock}};
+use tokio::{runtime::{Runtime as TokioRuntime, Builder as TokioBuilder, Handle}, task::LocalSet, sync::{mpsc::{self, UnboundedReceiver, UnboundedSender}, Mutex, RwLock}};
 
 use crate::{
-    virtual_thread::VThread, executor::executor::{Executor, ExecutorExt}, 
-    thread_counter::{ThreadCounter, ShutdownType}, 
+    virtual_thread::VThread, executor::executor::{Executor, ExecutorExt, ExecutorLock}, 
+    thread_counter::{ThreadCounter, ShutdownType, ThreadFault, ThreadHandle}, 
     shared_memory::{SharedMemory, Memory}, 
     vm_config::ThreadingKind,
     archive::Archive, string::VMStr, extensions::Extensions, event::EventType, extension_data::ExtensionData, ffi::FfiBindings,
+    debugger::Debugger, trace::Tracer, profiler::Profiler, sync_objects::SyncObjects, channels::Channels,
 };
-use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, process, collections::HashSet};
+use std::{sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}}, collections::HashSet, io::{self, Write}};
+
+pub type Sink = Box<dyn Write + Send>;
+
+#[derive(Default)]
+pub struct RuntimeOptions {
+    pub debugger: Option<Arc<Debugger>>,
+    pub tracer: Option<Arc<Tracer>>,
+    pub profiler: Option<Arc<Profiler>>,
+    // Standard output/error when not set
+    pub stdout: Option<Sink>,
+    pub stderr: Option<Sink>,
+    // Host runtime to spawn on, one is built from the config when not set
+    pub tokio_handle: Option<Handle>,
+}
+
+// Result of `Runtime::run`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutcome {
+    pub shutdown_type: ShutdownType,
+    pub error_data: Option<String>,
+    pub exit_code: i32,
+    // Instructions executed by all virtual threads, across restarts
+    pub instructions: u64,
+    pub restarts: u64,
+    // Threads disposed under `ErrorPolicy::Isolate` since the last restart
+    pub faults: Vec<ThreadFault>,
+}
 
 pub struct Runtime {
     pub temp_vmstr: Arc<Mutex<HashSet<(u64, usize)>>>,
     pub memory: RwLock<SharedMemory>,
-    pub tokio_rt: Arc<TokioRuntime>,
+    pub tokio_rt: Option<Arc<TokioRuntime>>,
+    pub tokio_handle: Handle,
     pub extensions: Extensions,
     pub archive: Arc<Archive>,
     pub shutdown: AtomicBool,
     pub initial_inst: u64,
     pub base: u64,
 
+    pub debugger: Option<Arc<Debugger>>,
+    pub tracer: Option<Arc<Tracer>>,
+    pub profiler: Option<Arc<Profiler>>,
+
     pub ffi: FfiBindings,
     pub extension_data: ExtensionData,
+    pub lock: ExecutorLock,
+    pub sync: SyncObjects,
+    pub channels: Channels,
+
+    stdout: std::sync::Mutex<Sink>,
+    stderr: std::sync::Mutex<Sink>,
 
     shutdown_rx: Mutex<UnboundedReceiver<ShutdownType>>,
+    // `ThreadingKind::Single` threads, spawned onto the `LocalSet` by the task receiving them
+    local_tx: UnboundedSender<VThread>,
+    local_rx: Mutex<UnboundedReceiver<VThread>>,
     threads: ThreadCounter,
+    instructions: AtomicU64,
     stack_size: usize,
 
     executor: Executor
@@ -33,7 +76,7 @@ impl Runtime {
     fn tokio_rt(archive: &Archive) -> TokioRuntime {
         match archive.conf.threading_kind {
             ThreadingKind::Single => TokioBuilder::new_current_thread().enable_all().worker_threads(1).build().unwrap(),
-            _ => TokioBuilder::new_multi_thread().enable_all().worker_threads(archive.conf.max_threads as usize).build().unwrap()
+            _ => TokioBuilder::new_multi_thread().enable_all().worker_threads(archive.conf.worker_threads()).build().unwrap()
         }
     }
 
@@ -48,31 +91,60 @@ impl Runtime {
         self.extensions.get(target).dispatch_event(self.clone(), event);
     }
 
-    pub fn new(archive: Archive) -> Arc<Runtime> {
+    pub fn new(archive: Archive, extensions: Extensions) -> Arc<Runtime> {
+        Runtime::with_options(archive, extensions, RuntimeOptions::default())
+    }
+
+    pub fn with_options(archive: Archive, extensions: Extensions, options: RuntimeOptions) -> Arc<Runtime> {
         let channel = mpsc::unbounded_channel::<ShutdownType>();
+        let local = mpsc::unbounded_channel::<VThread>();
         let executor = Executor::from_archive(&archive);
+        let lock = ExecutorLock::from_archive(&archive);
         let memory = Memory::from_archive(&archive);
 
+        let tokio_rt = match options.tokio_handle {
+            Some(_) => None,
+            None => Some(Arc::new(Runtime::tokio_rt(&archive)))
+        };
+
         let runtime = Arc::new(Runtime {
             temp_vmstr: Arc::new(Mutex::new(HashSet::with_capacity(128))),
-            tokio_rt: Arc::new(Runtime::tokio_rt(&archive)),
+            tokio_handle: options.tokio_handle.unwrap_or_else(|| tokio_rt.as_ref().unwrap().handle().clone()),
+            tokio_rt,
             stack_size: archive.conf.stack_size as usize,
             threads: ThreadCounter::new(channel.0),
-            extensions: Extensions::parse_from_env(),
+            instructions: AtomicU64::new(0),
+            extensions,
             memory: RwLock::new(memory.clone()),
             shutdown_rx: Mutex::new(channel.1),
+            local_tx: local.0,
+            local_rx: Mutex::new(local.1),
             shutdown: AtomicBool::new(false),
             archive: Arc::new(archive),
 
             ffi: FfiBindings::new(),
             extension_data: ExtensionData::new(),
+            lock,
+            sync: SyncObjects::new(),
+            channels: Channels::new(),
             
             initial_inst: unsafe { *memory.ptr().cast::<u64>() } * 8 + 0x8,
             base: memory.ptr() as u64,
+
+            debugger: options.debugger,
+            tracer: options.tracer,
+            profiler: options.profiler,
+
+            stdout: std::sync::Mutex::new(options.stdout.unwrap_or_else(|| Box::new(io::stdout()))),
+            stderr: std::sync::Mutex::new(options.stderr.unwrap_or_else(|| Box::new(io::stderr()))),
             
             executor,
         });
         
+        if let Some(debugger) = &runtime.debugger {
+            debugger.attach(&runtime);
+        }
+
         for (&id, ext) in runtime.extensions.all() {
             ext.init(runtime.clone(), id);
         }
@@ -80,39 +152,106 @@ impl Runtime {
         runtime
     }
 
-    pub fn run(self: Arc<Self>) {
-        let tokio_rt = self.tokio_rt.clone();
+    // Runs on the host's tokio runtime instead of building one from the config
+    pub fn with_handle(archive: Archive, extensions: Extensions, handle: Handle) -> Arc<Runtime> {
+        Runtime::with_options(archive, extensions, RuntimeOptions { tokio_handle: Some(handle), ..Default::default() })
+    }
+
+    // Blocks the current thread, must not be called from inside a tokio runtime
+    pub fn run(self: Arc<Self>) -> RunOutcome {
+        match self.tokio_rt.clone() {
+            Some(tokio_rt) => tokio_rt.block_on(self.run_local()),
+            None => self.tokio_handle.clone().block_on(self.run_local())
+        }
+    }
+
+    // Send, so the host can spawn it, `ThreadingKind::Single` runs on a dedicated current-thread runtime
+    pub async fn run_async(self: Arc<Self>) -> RunOutcome {
+        match self.archive.conf.threading_kind {
+            ThreadingKind::Single => tokio::task::spawn_blocking(move || Runtime::tokio_rt(&self.archive).block_on(self.run_local())).await.unwrap(),
+            _ => self.run_loop().await
+        }
+    }
+
+    // Any threading kind, `ThreadingKind::Single` keeps every virtual thread on the task awaiting this
+    pub async fn run_local(self: Arc<Self>) -> RunOutcome {
+        LocalSet::new().run_until(self.run_loop()).await
+    }
 
-        tokio_rt.block_on(async move {
-            let runtime = self;
+    async fn run_loop(self: Arc<Self>) -> RunOutcome {
+        let mut restarts = 0;
 
-            runtime.dispatch_extension_event(EventType::VMRun);
+        let local = match self.archive.conf.threading_kind {
+            ThreadingKind::Single => {
+                let runtime = self.clone();
 
-            loop {
-                let shutdown_type = runtime.run_once().await;
+                Some(tokio::task::spawn_local(async move {
+                    let mut local_rx = runtime.local_rx.lock().await;
 
-                // Preventing Memory Leaks
-                for (ptr, len) in runtime.temp_vmstr.lock().await.drain() {
-                    VMStr::deallocate(ptr, len);
-                }
+                    while let Some(thread) = local_rx.recv().await {
+                        let executor = runtime.executor.clone();
 
-                if shutdown_type == ShutdownType::Restarting {
-                    runtime.threads.reset().await;
+                        tokio::task::spawn_local(async move {
+                            executor.call(thread).await;
+                        });
+                    }
+                }))
+            }
+            _ => None
+        };
 
-                    *runtime.memory.write().await = Memory::from_archive(&runtime.archive);
+        self.dispatch_extension_event(EventType::VMRun);
 
-                    runtime.shutdown.store(false, Ordering::SeqCst);
+        let shutdown_type = loop {
+            let shutdown_type = self.run_once().await;
 
-                    runtime.dispatch_extension_event(EventType::VMShutdown(shutdown_type));
-                } else {
-                    runtime.dispatch_extension_event(EventType::VMShutdown(shutdown_type));
+            // Preventing Memory Leaks
+            for (ptr, len) in self.temp_vmstr.lock().await.drain() {
+                VMStr::deallocate(ptr, len);
+            }
 
-                    break;
-                }
-            };
+            if shutdown_type == ShutdownType::Restarting {
+                self.threads.reset().await;
 
-            runtime.dispatch_extension_event(EventType::VMEnd);
-        });
+                *self.memory.write().await = Memory::from_archive(&self.archive);
+
+                self.sync.reset();
+                self.channels.reset();
+
+                self.shutdown.store(false, Ordering::SeqCst);
+
+                self.dispatch_extension_event(EventType::VMShutdown(shutdown_type));
+
+                restarts += 1;
+            } else {
+                self.dispatch_extension_event(EventType::VMShutdown(shutdown_type));
+
+                break shutdown_type;
+            }
+        };
+
+        if let Some(local) = local {
+            local.abort();
+        }
+
+        self.dispatch_extension_event(EventType::VMEnd);
+        self.flush_outputs();
+
+        let faults = self.threads.faults();
+        let error_data = match shutdown_type {
+            // Every thread faulted under `ErrorPolicy::Isolate` if there is no error data
+            ShutdownType::Error => self.threads.get_error_data().await.or_else(|| faults.last().map(ThreadFault::to_string)),
+            _ => None
+        };
+
+        RunOutcome {
+            exit_code: shutdown_type.exit_code(),
+            instructions: self.instructions.load(Ordering::SeqCst),
+            shutdown_type,
+            error_data,
+            restarts,
+            faults,
+        }
     }
 
     async fn run_once(self: &Arc<Self>) -> ShutdownType {
@@ -120,54 +259,118 @@ impl Runtime {
 
         self.spawn(self.initial_inst).await;
 
-        match self.shutdown_rx.lock().await.recv().await.unwrap() {
-            code => {
-                match code {
-                    ShutdownType::None => {
-                        println!("OpenEntry VM Stopped Running with Unknown Reason.");
-
-                        process::exit(1);
-                    }
-                    ShutdownType::Error => {
-                        if let Some(data) = self.threads.get_error_data().await {
-                            println!("OpenEntry VM Stopped Running with Error: \n{}", data);
-                        } else {
-                            println!("OpenEntry VM Stopped Running with Error.");
-                            println!("Extra Error Details weren't provided.");
-                        }
-
-                        process::exit(1);
-                    }
-                    x => x
-                }
-            }
-        }
+        self.shutdown_rx.lock().await.recv().await.unwrap()
     }
 
     pub async fn spawn(self: &Arc<Self>, addr: u64) {
+        self.spawn_thread(addr).await;
+    }
+
+    // Returns the id of the new thread
+    pub async fn spawn_thread(self: &Arc<Self>, addr: u64) -> u64 {
         let thread = self.threads.create(self.clone(), self.stack_size, addr).await;
+        let id = thread.id;
+
+        if let Some(debugger) = &self.debugger {
+            debugger.thread_started(thread.id);
+        }
+
         let executor = self.executor.clone();
 
-        if self.archive.conf.threading_kind == ThreadingKind::Managed {
-            tokio::task::spawn(async move {
-                executor.call(thread).await;
-            });
+        // Spawned on the VM's runtime even when called from another one, e.g. by an extension
+        if self.archive.conf.threading_kind == ThreadingKind::Single {
+            let _ = self.local_tx.send(thread);
         } else {
-            tokio::spawn(async move {
+            self.tokio_handle.spawn(async move {
                 executor.call(thread).await;
             });
         }
+
+        id
+    }
+
+    // None once the thread has ended
+    pub fn thread_handle(&self, id: u64) -> Option<Arc<ThreadHandle>> {
+        self.threads.handle(id)
+    }
+
+    pub fn is_thread_id(&self, id: u64) -> bool {
+        self.threads.is_id(id)
     }
 
     pub fn shutdown(&self, shutdown_type: ShutdownType) {
         self.threads.set_shutdown_type(shutdown_type);
         self.shutdown.store(true, Ordering::SeqCst);
+        // Wakes threads blocked in intrinsics or parked by the debugger
+        self.threads.cancel_others(0);
+    }
+
+    // StopSelf, the VM keeps running while another thread is alive
+    pub fn stop_thread(&self, handle: &ThreadHandle) {
+        self.threads.set_stopped();
+        handle.cancel();
+    }
+
+    // StopOthers, the VM keeps running
+    pub fn stop_other_threads(&self, id: u64) {
+        self.threads.set_stopped();
+        self.threads.cancel_others(id);
+    }
+
+    // Records the error of a disposed thread, the VM keeps running unless it was the last one
+    pub fn thread_faulted(self: &Arc<Self>, fault: ThreadFault) {
+        self.write_stderr(&format!("OpenEntry VM Thread Stopped Running with Error: \n{fault}\n"));
+
+        let (thread, inst) = (fault.thread, fault.inst);
+
+        self.threads.add_fault(fault);
+        self.threads.set_default_shutdown_type(ShutdownType::Error);
+
+        for ext in self.extensions.iter() {
+            ext.thread_error(self.clone(), thread, inst);
+        }
+    }
+
+    pub fn thread_faults(&self) -> Vec<ThreadFault> {
+        self.threads.faults()
     }
 
     pub fn dispose_thread(&self, thread: VThread) {
+        if let Some(debugger) = &self.debugger {
+            debugger.thread_exited(thread.id);
+        }
+
+        self.instructions.fetch_add(thread.instructions(), Ordering::SeqCst);
+        self.sync.release_thread(thread.id);
+
         self.threads.delete(thread);
     }
 
+    pub fn write_stdout(&self, text: &str) {
+        let mut stdout = self.stdout.lock().unwrap();
+
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    pub fn write_stderr(&self, text: &str) {
+        let mut stderr = self.stderr.lock().unwrap();
+
+        let _ = stderr.write_all(text.as_bytes());
+        let _ = stderr.flush();
+    }
+
+    // Writes out the trace and the profile
+    fn flush_outputs(&self) {
+        if let Some(tracer) = &self.tracer {
+            tracer.flush();
+        }
+
+        if let Some(profiler) = &self.profiler {
+            profiler.dump(&self.archive);
+        }
+    }
+
     pub async fn set_error_data(&self, data: String) {
         self.threads.set_error_data(data).await;
     }
@@ -17,4 +17,8 @@ impl Memory {
     pub fn ptr(&self) -> *const u8 {
         self.memory.as_ptr()
     }
+
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
 }
\ No newline at end of file
@@ -57,7 +57,7 @@ impl VMStr {
 
             let mut lock = self.1.get_temp_vmstrs().await;
 
-            lock.remove(&(self.0 as u64, len as usize));
+            lock.remove(&(self.0 as u64, len as usize + 8));
 
             self.0 = alloc::realloc(self.0 as _, Layout::from_size_align_unchecked(new_len as usize, 1), new_len as usize) as _;
 
@@ -83,7 +83,7 @@ impl VMStr {
 
             let mut lock = self.1.get_temp_vmstrs().await;
 
-            lock.remove(&(self.0 as u64, len as usize));
+            lock.remove(&(self.0 as u64, len as usize + 8));
 
             self.0 = alloc::realloc(self.0 as _, Layout::from_size_align_unchecked(new_len as usize, 1), new_len as usize) as _;
 
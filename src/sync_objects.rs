@@ -0,0 +1,225 @@
+use std::{sync::{Arc, Mutex}, collections::{BTreeMap, BTreeSet}, fmt::{self, Display}, error::Error};
+
+use tokio::sync::{Semaphore, Notify};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncError {
+    AlreadyOwned(String),
+    NotOwned(String),
+    TooManyPermits(String),
+}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::AlreadyOwned(name) => write!(f, "Mutex `{name}` is already locked by this thread."),
+            SyncError::NotOwned(name) => write!(f, "Mutex `{name}` is not locked by this thread."),
+            SyncError::TooManyPermits(name) => write!(f, "Semaphore `{name}` has too many permits."),
+        }
+    }
+}
+
+impl Error for SyncError {}
+
+// Ids of the threads blocked on an object
+#[derive(Default)]
+struct Waiters(Mutex<BTreeSet<u64>>);
+
+impl Waiters {
+    fn add(&self, thread: u64) {
+        self.0.lock().unwrap().insert(thread);
+    }
+
+    fn remove(&self, thread: u64) {
+        self.0.lock().unwrap().remove(&thread);
+    }
+}
+
+impl Display for Waiters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0.lock().unwrap().iter().collect::<Vec<_>>())
+    }
+}
+
+struct GuestMutex {
+    semaphore: Semaphore,
+    owner: Mutex<Option<u64>>,
+    waiters: Waiters,
+}
+
+struct GuestSemaphore {
+    semaphore: Semaphore,
+    waiters: Waiters,
+}
+
+struct GuestCondvar {
+    notify: Notify,
+    waiters: Waiters,
+}
+
+#[derive(Default)]
+struct SyncState {
+    mutexes: BTreeMap<String, Arc<GuestMutex>>,
+    semaphores: BTreeMap<String, Arc<GuestSemaphore>>,
+    condvars: BTreeMap<String, Arc<GuestCondvar>>,
+}
+
+// Named mutexes, semaphores and condition variables of the guest, created on first use
+#[derive(Default)]
+pub struct SyncObjects(Mutex<SyncState>);
+
+impl SyncObjects {
+    pub fn new() -> SyncObjects {
+        SyncObjects::default()
+    }
+
+    fn mutex(&self, name: &str) -> Arc<GuestMutex> {
+        self.0.lock().unwrap().mutexes.entry(name.to_owned()).or_insert_with(|| Arc::new(GuestMutex {
+            semaphore: Semaphore::new(1),
+            owner: Mutex::new(None),
+            waiters: Waiters::default(),
+        })).clone()
+    }
+
+    fn semaphore(&self, name: &str) -> Arc<GuestSemaphore> {
+        self.0.lock().unwrap().semaphores.entry(name.to_owned()).or_insert_with(|| Arc::new(GuestSemaphore {
+            semaphore: Semaphore::new(0),
+            waiters: Waiters::default(),
+        })).clone()
+    }
+
+    fn condvar(&self, name: &str) -> Arc<GuestCondvar> {
+        self.0.lock().unwrap().condvars.entry(name.to_owned()).or_insert_with(|| Arc::new(GuestCondvar {
+            notify: Notify::new(),
+            waiters: Waiters::default(),
+        })).clone()
+    }
+
+    pub async fn lock(&self, name: &str, thread: u64) -> Result<(), SyncError> {
+        let mutex = self.mutex(name);
+
+        if *mutex.owner.lock().unwrap() == Some(thread) {
+            return Err(SyncError::AlreadyOwned(name.to_owned()));
+        }
+
+        mutex.waiters.add(thread);
+        mutex.semaphore.acquire().await.unwrap().forget();
+        mutex.waiters.remove(thread);
+
+        *mutex.owner.lock().unwrap() = Some(thread);
+
+        Ok(())
+    }
+
+    pub fn unlock(&self, name: &str, thread: u64) -> Result<(), SyncError> {
+        let mutex = self.mutex(name);
+        let mut owner = mutex.owner.lock().unwrap();
+
+        if *owner != Some(thread) {
+            return Err(SyncError::NotOwned(name.to_owned()));
+        }
+
+        *owner = None;
+        mutex.semaphore.add_permits(1);
+
+        Ok(())
+    }
+
+    pub fn sem_init(&self, name: &str, permits: usize) -> Result<(), SyncError> {
+        let semaphore = self.semaphore(name);
+
+        if permits > Semaphore::MAX_PERMITS - semaphore.semaphore.available_permits() {
+            return Err(SyncError::TooManyPermits(name.to_owned()));
+        }
+
+        semaphore.semaphore.add_permits(permits);
+
+        Ok(())
+    }
+
+    pub async fn acquire(&self, name: &str, thread: u64) {
+        let semaphore = self.semaphore(name);
+
+        semaphore.waiters.add(thread);
+        semaphore.semaphore.acquire().await.unwrap().forget();
+        semaphore.waiters.remove(thread);
+    }
+
+    pub fn release(&self, name: &str) -> Result<(), SyncError> {
+        self.sem_init(name, 1)
+    }
+
+    // Unlocks the mutex while waiting for a notification, then locks it again
+    pub async fn wait(&self, name: &str, mutex: &str, thread: u64) -> Result<(), SyncError> {
+        let condvar = self.condvar(name);
+        // Created before unlocking so a notification in between isn't lost
+        let notified = condvar.notify.notified();
+
+        self.unlock(mutex, thread)?;
+
+        condvar.waiters.add(thread);
+        notified.await;
+        condvar.waiters.remove(thread);
+
+        self.lock(mutex, thread).await
+    }
+
+    pub fn notify(&self, name: &str, all: bool) {
+        let condvar = self.condvar(name);
+
+        match all {
+            true => condvar.notify.notify_waiters(),
+            false => condvar.notify.notify_one()
+        }
+    }
+
+    // Unlocks the mutexes held by a disposed thread, which may have been cancelled while waiting
+    pub fn release_thread(&self, thread: u64) {
+        let (mutexes, semaphores, condvars) = {
+            let state = self.0.lock().unwrap();
+
+            (state.mutexes.clone(), state.semaphores.clone(), state.condvars.clone())
+        };
+
+        mutexes.values().for_each(|x| x.waiters.remove(thread));
+        semaphores.values().for_each(|x| x.waiters.remove(thread));
+        condvars.values().for_each(|x| x.waiters.remove(thread));
+
+        for (name, _) in mutexes.iter().filter(|(_, mutex)| *mutex.owner.lock().unwrap() == Some(thread)) {
+            let _ = self.unlock(name, thread);
+        }
+    }
+
+    pub fn reset(&self) {
+        *self.0.lock().unwrap() = SyncState::default();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let state = self.0.lock().unwrap();
+
+        state.mutexes.is_empty() && state.semaphores.is_empty() && state.condvars.is_empty()
+    }
+}
+
+impl Display for SyncObjects {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.0.lock().unwrap();
+
+        for (name, mutex) in &state.mutexes {
+            match *mutex.owner.lock().unwrap() {
+                Some(owner) => writeln!(f, "Mutex     `{name}`: locked by thread {owner}, waiting {}", mutex.waiters)?,
+                None => writeln!(f, "Mutex     `{name}`: unlocked, waiting {}", mutex.waiters)?,
+            }
+        }
+
+        for (name, semaphore) in &state.semaphores {
+            writeln!(f, "Semaphore `{name}`: {} permits, waiting {}", semaphore.semaphore.available_permits(), semaphore.waiters)?;
+        }
+
+        for (name, condvar) in &state.condvars {
+            writeln!(f, "Condvar   `{name}`: waiting {}", condvar.waiters)?;
+        }
+
+        Ok(())
+    }
+}
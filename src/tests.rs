@@ -1,8 +1,12 @@
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashMap, thread, time::Duration};
+    use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex, atomic::{AtomicU32, Ordering}}, thread, time::{Duration, Instant}, io::{self, Write}, fs, env, process};
 
-    use crate::{thread_counter::ShutdownType, vm_config::{VMConfig, ExecutorKind, ThreadingKind}, runtime::Runtime, archive::Archive};
+    use flate2::{write::GzEncoder, Compression};
+    use tokio::runtime::{Builder as TokioBuilder, Handle};
+    use serde_json::json;
+
+    use crate::{thread_counter::{ShutdownType, ThreadFault}, vm_config::{VMConfig, ExecutorKind, ThreadingKind, ErrorPolicy, ConfigError}, block_info::{BlockInfo, BlockInfoError, UnlockInfo}, debug_info::{DebugInfo, DebugInfoError, SourceRange, Variable, VarLocation}, runtime::{Runtime, RuntimeOptions, RunOutcome}, archive::{Archive, ArchiveError}, extensions::{Extensions, VmExtension}, embed::{VmBuilder, BuildError}, event::EventType, virtual_thread::VThread, executor::executor::{Lock, ExecutorBehaviour}, cli::{self, Command, RunOptions, DebugMode}, asm::{self, AsmError}, verifier::{self, VerifyError}, debugger::{Debugger, DebugEvent, StopReason, frames, dap::{Session, read_message}}, trace::{Tracer, TraceFormat, TraceFilter}, profiler::Profiler, op_codes::OpCodes};
 
     #[test]
     pub fn basic() {
@@ -34,17 +38,21 @@ mod tests {
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
             ]),
             block_info: None,
+            debug_info: None,
             conf: VMConfig {
                 executor_kind: ExecutorKind::Atomic,
                 threading_kind: ThreadingKind::Managed,
                 max_threads: 12,
                 stack_size: 1024 * 1024,
+                bounds_check: false,
+                max_call_depth: 0,
+                error_policy: ErrorPolicy::Shutdown,
             }
         };
     
-        let runtime = Runtime::new(archive);
+        let runtime = Runtime::new(archive, Extensions::empty());
             
-        runtime.run();
+        assert_eq!(runtime.run().shutdown_type, ShutdownType::Gracefully);
     }
 
     #[test]
@@ -84,15 +92,19 @@ mod tests {
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
             ]),
             block_info: None,
+            debug_info: None,
             conf: VMConfig {
                 executor_kind: ExecutorKind::Atomic,
                 threading_kind: ThreadingKind::Managed,
                 max_threads: 12,
                 stack_size: 1024 * 1024,
+                bounds_check: false,
+                max_call_depth: 0,
+                error_policy: ErrorPolicy::Shutdown,
             }
         };
     
-        let runtime = Runtime::new(archive);
+        let runtime = Runtime::new(archive, Extensions::empty());
         
         {
             let runtime = runtime.clone();
@@ -106,7 +118,1673 @@ mod tests {
         {
             let runtime = runtime.clone();
                 
-            runtime.run();
+            assert!(runtime.run().restarts > 0);
+        }
+    }
+
+    #[test]
+    pub fn cli() {
+        let args = |x: &str| x.split(' ').map(String::from).collect::<Vec<_>>();
+
+        if let Ok(Command::Run(options)) = cli::parse(args("--ext 1=./gfx.so Demo.entx --ext=2=./snd.so --executor-kind sys-lock-inst --max-threads=4 --stack-size 65536 --bounds-check")) {
+            let mut conf = VMConfig {
+                executor_kind: ExecutorKind::Atomic,
+                threading_kind: ThreadingKind::Managed,
+                max_threads: 12,
+                stack_size: 1024 * 1024,
+                bounds_check: false,
+                max_call_depth: 0,
+                error_policy: ErrorPolicy::Shutdown,
+            };
+
+            options.conf.apply(&mut conf);
+
+            assert_eq!(options.archive, "Demo.entx");
+            assert_eq!(options.extensions, vec![(1, String::from("./gfx.so")), (2, String::from("./snd.so"))]);
+            assert_eq!(conf.executor_kind, ExecutorKind::SysLockInst);
+            assert_eq!(conf.threading_kind, ThreadingKind::Managed);
+            assert_eq!(conf.max_threads, 4);
+            assert_eq!(conf.stack_size, 65536);
+            assert!(conf.bounds_check);
+        } else {
+            panic!("Failed to parse run options");
+        }
+
+        if let Ok(Command::Pack(options)) = cli::parse(args("pack Main.bin -o Demo.entx --assets res --threading-kind single --error-policy isolate")) {
+            assert_eq!(options.code, "Main.bin");
+            assert_eq!(options.output, "Demo.entx");
+            assert_eq!(options.assets.as_deref(), Some("res"));
+            assert_eq!(options.block_info, None);
+            assert_eq!(options.conf.threading_kind, Some(ThreadingKind::Single));
+            assert_eq!(options.conf.error_policy, Some(ErrorPolicy::Isolate));
+        } else {
+            panic!("Failed to parse pack options");
+        }
+
+        assert!(matches!(cli::parse(args("asm Main.s")), Ok(Command::Assemble { output, .. }) if output == "Main.bin"));
+        assert!(cli::parse(args("pack Main.bin")).is_err());
+        assert!(matches!(cli::parse(args("Demo.entx --help")), Ok(Command::Help)));
+        assert!(matches!(cli::parse(args("-V")), Ok(Command::Version)));
+        assert!(matches!(cli::parse(args("Demo.entx --trace -h")), Ok(Command::Run(RunOptions { trace: Some(trace), .. })) if trace.output == "-h"));
+        assert!(cli::parse(args("Demo.entx --bounds-check=false")).is_err());
+        assert!(cli::parse(args("Demo.entx --dap=1")).is_err());
+        assert!(matches!(cli::parse(args("Demo.entx --dap-port 4711")), Ok(Command::Run(RunOptions { debug: Some(DebugMode::DapTcp(4711)), .. }))));
+
+        if let Ok(Command::Run(options)) = cli::parse(args("Demo.entx --trace t.bin --trace-format binary --trace-range 0x10:0x40 --trace-op call,RET --trace-thread 2")) {
+            let trace = options.trace.unwrap();
+
+            assert_eq!((trace.output.as_str(), trace.format), ("t.bin", TraceFormat::Binary));
+            assert_eq!(trace.filter, TraceFilter { range: Some((0x10, 0x40)), threads: HashSet::from([2]), opcodes: HashSet::from([OpCodes::CALL, OpCodes::RET]) });
+        } else {
+            panic!("Failed to parse trace options");
+        }
+
+        assert!(cli::parse(args("Demo.entx --trace-op call")).is_err());
+        assert!(cli::parse(args("Demo.entx --trace t.jsonl --trace-range 0x40:0x10")).is_err());
+        assert!(cli::parse(args("--threading-kind")).is_err());
+        assert!(cli::parse(args("--ext gfx.so Demo.entx")).is_err());
+        assert!(cli::parse(args("A.entx B.entx")).is_err());
+        assert!(cli::parse(Vec::new()).is_err());
+    }
+
+    fn gzip_tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+
+        for &(name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+
+            tar.append_data(&mut header, name, data).unwrap();
+        }
+
+        gzip(&tar.into_inner().unwrap())
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    pub fn archive_errors() {
+        let code: &[u8] = &[0x00; 16];
+        let conf: &[u8] = &[0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        let archive = Archive::read(gzip_tar(&[("Main.bin", code), ("Conf.bin", conf)]).as_slice()).unwrap();
+
+        assert_eq!(archive.conf.executor_kind, ExecutorKind::Atomic);
+        assert_eq!(archive.conf.threading_kind, ThreadingKind::Managed);
+        assert_eq!(archive.conf.max_threads, 4);
+        assert_eq!(archive.conf.stack_size, 0x100000);
+
+        assert!(matches!(Archive::open("./Missing.entx"), Err(ArchiveError::Io(_))));
+        assert!(matches!(Archive::read(&b"Not an archive"[..]), Err(ArchiveError::Gzip(_))));
+        assert!(matches!(Archive::read(gzip(&[0xFF; 512]).as_slice()), Err(ArchiveError::Tar(_))));
+        assert!(matches!(Archive::read(gzip_tar(&[("Conf.bin", conf)]).as_slice()), Err(ArchiveError::MissingEntry("Main.bin"))));
+        assert!(matches!(Archive::read(gzip_tar(&[("Main.bin", code)]).as_slice()), Err(ArchiveError::MissingEntry("Conf.bin"))));
+        assert!(matches!(
+            Archive::read(gzip_tar(&[("Main.bin", code), ("Conf.bin", &conf[..8])]).as_slice()),
+            Err(ArchiveError::InvalidConfig(ConfigError::InvalidSize(8)))
+        ));
+        assert!(matches!(
+            Archive::read(gzip_tar(&[("Main.bin", code), ("Conf.bin", &[&[0x05], &conf[1..]].concat())]).as_slice()),
+            Err(ArchiveError::InvalidConfig(ConfigError::InvalidExecutorKind(5)))
+        ));
+        assert!(matches!(
+            Archive::read(gzip_tar(&[("Main.bin", code), ("Conf.bin", &[&conf[..1], &[0x03], &conf[2..]].concat())]).as_slice()),
+            Err(ArchiveError::InvalidConfig(ConfigError::InvalidThreadingKind(3)))
+        ));
+        assert!(matches!(
+            Archive::read(gzip_tar(&[("Main.bin", code), ("Conf.bin", conf), ("BlockInfo.bin", &[0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])]).as_slice()),
+            Err(ArchiveError::InvalidBlockInfo(BlockInfoError::InvalidSize { expected: 24, found: 8 }))
+        ));
+    }
+
+    #[test]
+    pub fn config_format() {
+        let header = |major: u8, minor: u8, length: u16| [&b"OECF"[..], &[major, minor], &length.to_le_bytes()].concat();
+        let payload: &[u8] = &[0x02, 0x02, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        let conf = VMConfig::read([header(1, 0, 16), payload.to_vec()].concat().into()).unwrap();
+
+        assert_eq!(conf.executor_kind, ExecutorKind::SpinLockInst);
+        assert_eq!(conf.threading_kind, ThreadingKind::Unmanaged);
+        assert_eq!(conf.max_threads, 8);
+        assert_eq!(conf.stack_size, 0x20000);
+        assert!(!conf.bounds_check);
+        assert_eq!(conf.max_call_depth, 0);
+
+        assert_eq!(conf.error_policy, ErrorPolicy::Shutdown);
+
+        let conf = VMConfig { bounds_check: true, max_call_depth: 500, error_policy: ErrorPolicy::Isolate, ..conf };
+
+        assert_eq!(conf.write()[12], 0x01);
+        assert_eq!(conf.write()[13], 0x01);
+        assert_eq!(conf.write().len(), 32);
+        assert_eq!(VMConfig::read(conf.write()), Ok(conf));
+
+        // Fields appended by newer minor versions are skipped
+        assert!(VMConfig::read([header(1, 7, 20), payload.to_vec(), vec![0xAA; 4]].concat().into()).is_ok());
+        assert!(VMConfig::read(payload.into()).is_ok());
+
+        // Reserved bytes of older versions are ignored, including in the headerless legacy layout
+        let padded = [&payload[..4], &[0xFF, 0xFF], &payload[6..]].concat();
+
+        for conf in [VMConfig::read(padded.clone().into()), VMConfig::read([header(1, 0, 16), padded.clone()].concat().into())] {
+            assert_eq!(conf.as_ref().map(|x| (x.bounds_check, x.error_policy)), Ok((false, ErrorPolicy::Shutdown)));
+        }
+
+        assert_eq!(VMConfig::read([header(1, 1, 16), padded.clone()].concat().into()).map(|x| (x.bounds_check, x.error_policy)), Ok((true, ErrorPolicy::Shutdown)));
+        assert_eq!(VMConfig::read([header(1, 1, 20), padded, 500u32.to_le_bytes().to_vec()].concat().into()).map(|x| x.max_call_depth), Ok(0));
+
+        assert_eq!(VMConfig::read([header(2, 0, 16), payload.to_vec()].concat().into()).err(), Some(ConfigError::UnsupportedVersion(2, 0)));
+        assert_eq!(VMConfig::read([header(1, 0, 8), payload[..8].to_vec()].concat().into()).err(), Some(ConfigError::PayloadTooShort(8)));
+        assert_eq!(VMConfig::read([header(1, 0, 16), payload[..12].to_vec()].concat().into()).err(), Some(ConfigError::LengthMismatch { expected: 24, found: 20 }));
+        assert_eq!(VMConfig::read(b"OECF\x01".to_vec().into()).err(), Some(ConfigError::InvalidSize(5)));
+        assert_eq!(VMConfig::read([header(1, 0, 16), vec![0x09], payload[1..].to_vec()].concat().into()).err(), Some(ConfigError::InvalidExecutorKind(9)));
+        assert_eq!(VMConfig::read([header(1, 3, 16), payload[..5].to_vec(), vec![0x07], payload[6..].to_vec()].concat().into()).err(), Some(ConfigError::InvalidErrorPolicy(7)));
+        assert_eq!(VMConfig::read([header(1, 0, 16), payload[..8].to_vec(), vec![0x04; 8]].concat().into()).err(), Some(ConfigError::InvalidStackSize(0x0404040404040404)));
+    }
+
+    // Output sink the test can read back
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn test_conf() -> VMConfig {
+        VMConfig {
+            executor_kind: ExecutorKind::Atomic,
+            threading_kind: ThreadingKind::Managed,
+            max_threads: 4,
+            stack_size: 64 * 1024,
+            bounds_check: false,
+            max_call_depth: 0,
+            error_policy: ErrorPolicy::Shutdown,
+        }
+    }
+
+    fn run_code(code: Box<[u8]>, conf: VMConfig) -> RunOutcome {
+        let archive = Archive {
+            files: HashMap::new(),
+            code,
+            block_info: None,
+            debug_info: None,
+            conf,
+        };
+
+        Runtime::new(archive, Extensions::empty()).run()
+    }
+
+    #[test]
+    pub fn assembler() {
+        let code = asm::assemble(r#"
+            .data
+            hello: .str "Hello "
+            world: .str "World!"
+
+            .code
+                lstr d0, [base+hello]
+                lstr d1, [base+world]
+                add d0, d1          ; "Hello World!"
+                int Debug
+                end
+        "#).unwrap();
+
+        assert_eq!(&*code, &[
+            0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x00, 0x00,
+            0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x57, 0x6f, 0x72, 0x6c, 0x64, 0x21, 0x00, 0x00,
+            0x02, 0x06, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x02, 0x07, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00,
+            0x03, 0x06, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+
+        let code = asm::assemble("
+            mov r0, (f64) 1.0
+            mov [top+2], 0x10
+            env 0x020311, 7
+            cmp r0, r1, gte
+            elem d0, [r0+1], r1
+            lea2 d1, [r2+r3+4]
+        ").unwrap();
+
+        assert_eq!(&*code, &[
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xb1, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf0, 0x3f,
+            0xc1, 0x04, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+            0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x11, 0x03, 0x02, 0x00, 0x07, 0x00, 0x00, 0x00,
+            0x0c, 0x08, 0x09, 0x05, 0x00, 0x00, 0x00, 0x00,
+            0x1a, 0x06, 0x08, 0x09, 0x01, 0x00, 0x00, 0x00,
+            0x19, 0x07, 0x0a, 0x0b, 0x04, 0x00, 0x00, 0x00,
+        ]);
+
+        assert_eq!(asm::assemble("mov r8, r0").err(), Some(AsmError { line: 1, message: String::from("Unknown register `r8`.") }));
+        assert_eq!(asm::assemble("\n  jmp nowhere").err(), Some(AsmError { line: 2, message: String::from("Unknown label `nowhere`.") }));
+        assert!(asm::assemble("a:\na:").is_err());
+        assert!(asm::assemble("env 0x0712, 1").is_err());
+        assert!(asm::assemble("add d0").is_err());
+        assert!(asm::assemble(".data\nadd d0, d1").is_err());
+        assert!(asm::assemble(".data\n.str \"unterminated").is_err());
+    }
+
+    #[test]
+    pub fn control_flow() {
+        let code = asm::assemble(CONTROL_FLOW).unwrap();
+
+        assert_eq!(run_code(code.clone(), test_conf()).shutdown_type, ShutdownType::Gracefully);
+        assert_eq!(run_code(code.clone(), VMConfig { bounds_check: true, ..test_conf() }).shutdown_type, ShutdownType::Gracefully);
+        // The saved FUNC and one 11-word frame fill the stack exactly
+        assert_eq!(run_code(code, VMConfig { stack_size: 96, max_call_depth: 1, ..test_conf() }).shutdown_type, ShutdownType::Gracefully);
+    }
+
+    const CONTROL_FLOW: &str = "
+            .data
+            one: .f64 1.0
+            ten: .f64 10.0
+
+            .code
+                mov r0, (f64) 0.0
+                mov r1, [base+one]
+                mov r2, [base+ten]
+            loop:
+                add r0, r1
+                cmp r0, r2, lt
+                jt loop
+                mov d0, (f64) 5.0
+                pushr func
+                call double
+                pop func
+                mov d1, (f64) 10.0
+                cmp ret0, d1, eq
+                jt returned
+                int Throw
+            returned:
+                cmp r0, r2, eq      ; R0 is restored by `ret`
+                jt done
+                int Throw
+            done:
+                end
+
+            double:
+                mov ret0, d0
+                add ret0, d0
+                mov r0, (f64) 0.0
+                ret
+        ";
+
+    #[test]
+    pub fn stack_limits() {
+        let error = |source: &str, conf: VMConfig| {
+            let outcome = run_code(asm::assemble(source).unwrap(), conf);
+
+            assert_eq!(outcome.shutdown_type, ShutdownType::Error);
+
+            outcome.error_data.unwrap()
+        };
+
+        assert_eq!(error("loop:\npushi 1\njmp loop", VMConfig { stack_size: 64, ..test_conf() }), "Stack overflow at instruction 0x0008 (depth 0).");
+        assert_eq!(error("pushi 1\npop r0\npop r0", test_conf()), "Stack underflow at instruction 0x0020 (depth 0).");
+        assert_eq!(error("recurse:\ncall recurse", VMConfig { max_call_depth: 3, ..test_conf() }), "Stack overflow at instruction 0x0008 (depth 3).");
+    }
+
+    #[test]
+    pub fn bounds_check() {
+        let conf = VMConfig { bounds_check: true, ..test_conf() };
+        let error = |source: &str| {
+            let outcome = run_code(asm::assemble(source).unwrap(), conf.clone());
+
+            assert_eq!(outcome.shutdown_type, ShutdownType::Error);
+
+            outcome.error_data.unwrap()
+        };
+
+        assert!(error("mov [r0+4], 1").starts_with("Invalid memory write of 8 bytes at 0x20 (instruction 0x0008)."));
+        // The code section of the memory image is read-only, the data section isn't
+        assert!(error("mov [base+1], 1").starts_with("Invalid memory write of 8 bytes at 0x"));
+        assert_eq!(run_code(asm::assemble(".data\nvalue: .f64 1.0\n.code\nmov [base+value], 2\nend").unwrap(), conf.clone()).shutdown_type, ShutdownType::Gracefully);
+
+        assert_eq!(error("mov d0, 0x7FF4000000001000\nadd d0, d0"), "Invalid string pointer 0x1000 in D0 (instruction 0x0018).");
+        assert_eq!(error("mov d1, 0x7FFC000000001000\ncmp d1, d1, eq"), "Invalid string pointer 0x1000 in D1 (instruction 0x0018).");
+        assert_eq!(error("mov r0, 0x7FF4000000001000\ndrop r0"), "Invalid string pointer 0x1000 in R0 (instruction 0x0018).");
+
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let vm = VmBuilder::from_parts(asm::assemble("mov r0, 0x7FF4000000001000\nint Debug\nend").unwrap(), conf.clone()).stderr(Shared(stderr.clone())).build().unwrap();
+
+        assert_eq!(vm.run().shutdown_type, ShutdownType::Gracefully);
+        assert!(String::from_utf8(stderr.lock().unwrap().clone()).unwrap().contains("R0:   0x7FF4000000001000 [invalid str]"));
+
+        let code = asm::assemble(r#"
+            .data
+            hello: .str "Hello "
+
+            .code
+                lstr d0, [base+hello]
+                lstr d1, [base+hello]
+                add d0, d1
+                cmp d0, d1, eq
+                drop d0
+                end
+        "#).unwrap();
+
+        assert_eq!(run_code(code, conf).shutdown_type, ShutdownType::Gracefully);
+    }
+
+    #[test]
+    pub fn instruction_semantics() {
+        // Encoding of the control flow and extension instructions, any `int Throw` fails the test
+        let source = "
+                mov r0, (f64) 1.0
+                jmp over            ; Byte offset 8 bytes before the target
+                int Throw
+            over:
+                call func           ; Word index into Main.bin, not relative to BASE
+                mov d0, (f64) 2.0
+                cmp ret0, d0, eq
+                jt called
+                int Throw
+            called:
+                mov d0, (f64) 1.0
+                cmp r0, d0, eq      ; `ret` restores the values of D0..R7
+                jt restored
+                int Throw
+            restored:
+                env 0x0711, 21      ; Extension id in bytes 0..3, ending in the opcode byte
+                mov d0, (f64) 42.0
+                cmp ret0, d0, eq
+                jt extended
+                int Throw
+            extended:
+                spawn worker, r0    ; Word index into Main.bin, not relative to BASE
+                int ThreadJoin
+                end
+            func:
+                mov ret0, (f64) 2.0
+                mov r0, (f64) 0.0
+                ret
+            worker:
+                end
+                int Throw
+        ";
+
+        let code: Box<[u8]> = Box::new([
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xb1, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf0, 0x3f,
+            0x0b, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00,
+            0x10, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x09, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00,
+            0xb1, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40,
+            0x0c, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x0a, 0x00, 0x00, 0x00, 0x50, 0x00, 0x00, 0x00,
+            0x10, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xb1, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf0, 0x3f,
+            0x0c, 0x08, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x0a, 0x00, 0x00, 0x00, 0x78, 0x00, 0x00, 0x00,
+            0x10, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x11, 0x07, 0x00, 0x00, 0x15, 0x00, 0x00, 0x00,
+            0xb1, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x45, 0x40,
+            0x0c, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x0a, 0x00, 0x00, 0x00, 0xa8, 0x00, 0x00, 0x00,
+            0x10, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x13, 0x08, 0x00, 0x00, 0x1e, 0x00, 0x00, 0x00,
+            0x10, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xb1, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40,
+            0xb1, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x10, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+
+        assert_eq!(&*asm::assemble(source).unwrap(), &*code);
+
+        for executor_kind in [ExecutorKind::Atomic, ExecutorKind::SysLockInst] {
+            let vm = VmBuilder::from_parts(code.clone(), VMConfig { executor_kind, ..test_conf() }).extension(0x0711, Doubler { ended: Arc::new(AtomicU32::new(0)) }).build().unwrap();
+
+            assert_eq!(vm.run().shutdown_type, ShutdownType::Gracefully);
+        }
+    }
+
+    #[test]
+    pub fn host_runtime() {
+        let code = asm::assemble("
+            .data
+            wait: .f64 0.05
+
+            .code
+                spawn worker
+                mov r0, [base+wait]
+                int Sleep
+                int Sleep
+                end
+
+            worker:
+                mov r0, [base+wait]
+                int Sleep
+                jmp worker
+        ").unwrap();
+
+        let host = TokioBuilder::new_multi_thread().enable_all().worker_threads(2).build().unwrap();
+
+        for threading_kind in [ThreadingKind::Single, ThreadingKind::Managed, ThreadingKind::Unmanaged] {
+            let archive = || Archive::new(code.clone(), VMConfig { threading_kind, ..test_conf() }, None);
+
+            // `run` would panic inside the host runtime, `run_async` can be spawned on it
+            let outcome = host.block_on(async {
+                tokio::spawn(Runtime::with_handle(archive(), Extensions::empty(), Handle::current()).run_async()).await.unwrap()
+            });
+
+            assert_eq!(outcome.shutdown_type, ShutdownType::Gracefully);
+            // Main thread's 5 instructions plus the worker's
+            assert!(outcome.instructions > 5);
+
+            let outcome = host.block_on(Runtime::with_handle(archive(), Extensions::empty(), host.handle().clone()).run_local());
+
+            assert_eq!(outcome.shutdown_type, ShutdownType::Gracefully);
+            assert!(outcome.instructions > 5);
+
+            let outcome = Runtime::with_handle(archive(), Extensions::empty(), host.handle().clone()).run();
+
+            assert_eq!(outcome.shutdown_type, ShutdownType::Gracefully);
+            assert!(outcome.instructions > 5);
+        }
+    }
+
+    #[test]
+    pub fn run_async_send() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let code = asm::assemble(".code\n end").unwrap();
+        let vm = VmBuilder::from_parts(code, VMConfig { threading_kind: ThreadingKind::Managed, ..test_conf() }).build().unwrap();
+
+        assert_send(&vm.run_async());
+        assert_send(&vm.runtime().clone().run_async());
+
+        // A single threaded VM also runs when spawned on the host runtime
+        let vm = VmBuilder::from_parts(asm::assemble(".code\n end").unwrap(), VMConfig { threading_kind: ThreadingKind::Single, ..test_conf() }).build().unwrap();
+        let host = TokioBuilder::new_multi_thread().enable_all().worker_threads(2).build().unwrap();
+
+        assert_eq!(host.block_on(async { tokio::spawn(vm.run_async()).await.unwrap() }).shutdown_type, ShutdownType::Gracefully);
+
+        // Threads spawned from another OS thread and runtime, as an extension would, still run on the VM's
+        let code = asm::assemble("
+            .data
+            wait: .f64 0.01
+
+            .code
+                jmp main
+                end
+
+            main:
+                mov r0, [base+wait]
+                int Sleep
+                jmp main
+        ").unwrap();
+
+        let runtime = Runtime::new(Archive::new(code, VMConfig { threading_kind: ThreadingKind::Single, ..test_conf() }, None), Extensions::empty());
+
+        {
+            let runtime = runtime.clone();
+
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                TokioBuilder::new_current_thread().build().unwrap().block_on(runtime.spawn(runtime.initial_inst + 8));
+            });
+        }
+
+        assert_eq!(runtime.run().shutdown_type, ShutdownType::Gracefully);
+    }
+
+    #[test]
+    pub fn executor_lock() {
+        // Four workers increment `counter` 2000 times each, the main thread checks the total once they are done
+        let code = asm::assemble("
+            .data
+            counter: .f64 0.0
+            done: .f64 0.0
+            one: .f64 1.0
+            total: .f64 8000.0
+            workers: .f64 4.0
+            wait: .f64 0.01
+
+            .code
+                spawn worker
+                spawn worker
+                spawn worker
+                spawn worker
+                mov r2, [base+workers]
+            wait_workers:
+                mov r0, [base+wait]
+                int Sleep
+                mov r1, [base+done]
+                cmp r1, r2, eq
+                jt check
+                jmp wait_workers
+            check:
+                mov r1, [base+counter]
+                mov r2, [base+total]
+                cmp r1, r2, eq
+                jt ok
+                int Throw
+            ok:
+                end
+
+            worker:
+                mov r2, (f64) 0.0
+                mov r3, (f64) 2000.0
+                mov r4, [base+one]
+            increment:
+                mov r1, [base+counter]
+                add r1, r4
+                mov [base+counter], r1
+                add r2, r4
+                cmp r2, r3, lt
+                jt increment
+                mov r1, [base+done]
+                add r1, r4
+                mov [base+done], r1
+            idle:
+                mov r0, [base+wait]
+                int Sleep
+                jmp idle
+        ").unwrap();
+
+        // Read-modify-write of `counter` and `done`
+        let block_info = BlockInfo::new(HashMap::from([(0xE8, UnlockInfo::Addr(0xF8)), (0x118, UnlockInfo::Addr(0x128))]));
+
+        for executor_kind in [ExecutorKind::SysLockBlock, ExecutorKind::SpinLockBlock] {
+            let archive = Archive::new(code.clone(), VMConfig { executor_kind, ..test_conf() }, Some(block_info.clone()));
+            let outcome = Runtime::new(archive, Extensions::empty()).run();
+
+            assert_eq!(outcome.shutdown_type, ShutdownType::Gracefully, "{executor_kind:?} lost increments");
+        }
+    }
+
+    #[test]
+    pub fn sync_objects() {
+        let code = asm::assemble(r#"
+            .data
+            counter: .f64 0.0
+            flag: .f64 0.0
+            one: .f64 1.0
+            total: .f64 2000.0
+            wait: .f64 0.01
+            counter_name: .str "counter"
+            done: .str "done"
+            m: .str "m"
+            cv: .str "cv"
+
+            .code
+                spawn worker
+                spawn worker
+                spawn worker
+                spawn worker
+                lstr r0, [base+done]
+                int SemAcquire
+                int SemAcquire
+                int SemAcquire
+                int SemAcquire
+                mov r1, [base+counter]
+                mov r2, [base+total]
+                cmp r1, r2, eq
+                jt counted
+                int Throw
+            counted:
+                lstr r0, [base+m]
+                int MutexLock
+                spawn notifier
+            wait_flag:
+                mov r1, [base+flag]
+                mov r2, [base+one]
+                cmp r1, r2, eq
+                jt notified
+                lstr r0, [base+cv]
+                lstr r1, [base+m]
+                int CondWait
+                jmp wait_flag
+            notified:
+                lstr r0, [base+m]
+                int MutexUnlock
+                try unlock_failed
+                int MutexUnlock
+                endtry
+                int Throw
+            unlock_failed:
+                int Debug
+                end
+
+            worker:
+                lstr r0, [base+counter_name]
+                mov r2, (f64) 0.0
+                mov r3, (f64) 500.0
+                mov r4, [base+one]
+            increment:
+                int MutexLock
+                mov r1, [base+counter]
+                add r1, r4
+                mov [base+counter], r1
+                int MutexUnlock
+                add r2, r4
+                cmp r2, r3, lt
+                jt increment
+                lstr r0, [base+done]
+                int SemRelease
+            idle:
+                mov r0, [base+wait]
+                int Sleep
+                jmp idle
+
+            notifier:
+                lstr r0, [base+m]
+                int MutexLock
+                mov r1, [base+one]
+                mov [base+flag], r1
+                lstr r0, [base+cv]
+                int CondNotify
+                lstr r0, [base+m]
+                int MutexUnlock
+                jmp idle
+        "#).unwrap();
+
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        // The atomic executor has no lock of its own, `counter` is only guarded by the guest mutex
+        let outcome = VmBuilder::from_parts(code, test_conf()).stderr(Shared(stderr.clone())).build().unwrap().run();
+
+        assert_eq!(outcome.shutdown_type, ShutdownType::Gracefully, "{:?}", outcome.error_data);
+
+        let stderr = String::from_utf8(stderr.lock().unwrap().clone()).unwrap();
+
+        assert!(stderr.ends_with(concat!(
+            "\n----- Sync Objects -----\n",
+            "Mutex     `counter`: unlocked, waiting []\n",
+            "Mutex     `m`: unlocked, waiting []\n",
+            "Semaphore `done`: 0 permits, waiting []\n",
+            "Condvar   `cv`: waiting []\n",
+        )), "{stderr}");
+    }
+
+    #[test]
+    pub fn channels() {
+        let code = asm::assemble(r#"
+            .data
+            chan: .f64 0.0
+            wait: .f64 0.05
+            sum: .f64 49.0
+            tick: .str "tick"
+
+            .code
+                int ChanCreate
+                mov [base+chan], ret0
+                lstr r0, [base+tick]
+                lea1 r1, [base+on_tick]
+                int SignalListen
+                mov r0, [base+chan]
+                int ChanTryRecv
+                jt fail
+                spawn waiter
+                mov r0, [base+wait]
+                int Sleep
+                lstr r0, [base+tick]
+                int Broadcast
+                mov r0, [base+chan]
+                int ChanRecv
+                mov r2, ret0
+                int ChanRecv
+                add r2, ret0
+                mov r3, [base+sum]
+                cmp r2, r3, eq
+                jt received
+            fail:
+                int Throw
+            received:
+                mov r0, (f64) 9.0
+                try unknown
+                int ChanSend
+                endtry
+                int Throw
+            unknown:
+                end
+
+            on_tick:
+                mov r0, [base+chan]
+                mov r1, (f64) 42.0
+                int ChanSend
+                jmp idle
+
+            waiter:
+                lstr r0, [base+tick]
+                int SignalWait
+                mov r0, [base+chan]
+                mov r1, (f64) 7.0
+                int ChanSend
+            idle:
+                mov r0, [base+wait]
+                int Sleep
+                jmp idle
+        "#).unwrap();
+
+        for threading_kind in [ThreadingKind::Single, ThreadingKind::Managed] {
+            let outcome = run_code(code.clone(), VMConfig { threading_kind, ..test_conf() });
+
+            assert_eq!(outcome.shutdown_type, ShutdownType::Gracefully, "{:?}", outcome.error_data);
+        }
+    }
+
+    #[test]
+    pub fn thread_handles() {
+        let code = asm::assemble("
+            .data
+            wait: .f64 0.01
+            unknown: .f64 99.0
+
+            .code
+                spawn worker, r5
+                mov r1, (f64) 2.0
+                cmp r5, r1, eq
+                jt spawned
+                int Throw
+            spawned:
+                mov r0, r5
+                int ThreadAlive
+                jt alive
+            fail:
+                int Throw
+            alive:
+                int ThreadCancel
+                int ThreadJoin
+                int ThreadAlive
+                jt fail
+                ; Joining an ended thread returns immediately
+                int ThreadJoin
+                mov r0, [base+unknown]
+                try unknown_thread
+                int ThreadJoin
+                endtry
+                int Throw
+            unknown_thread:
+                end
+
+            worker:
+                mov r0, [base+wait]
+                int Sleep
+                jmp worker
+        ").unwrap();
+
+        assert!(asm::disassemble(&code).unwrap().contains("    spawn loc_00C0, r5"));
+
+        for threading_kind in [ThreadingKind::Single, ThreadingKind::Managed] {
+            let outcome = run_code(code.clone(), VMConfig { threading_kind, ..test_conf() });
+
+            assert_eq!(outcome.shutdown_type, ShutdownType::Gracefully, "{:?}", outcome.error_data);
+        }
+    }
+
+    #[test]
+    pub fn stop_threads() {
+        // The blocked threads are stopped by `StopOthers`, the VM ends once the main thread stops itself
+        let code = asm::assemble(r#"
+            .data
+            chan: .f64 0.0
+            wait: .f64 0.05
+            long: .f64 1000.0
+            m: .str "m"
+
+            .code
+                int ChanCreate
+                mov [base+chan], ret0
+                lstr r0, [base+m]
+                int MutexLock
+                spawn receiver
+                spawn sleeper
+                spawn locker
+                mov r0, [base+wait]
+                int Sleep
+                int StopOthers
+                int StopSelf
+                int Throw
+
+            receiver:
+                mov r0, [base+chan]
+                int ChanRecv
+                int Throw
+
+            sleeper:
+                mov r0, [base+long]
+                int Sleep
+                int Throw
+
+            locker:
+                lstr r0, [base+m]
+                int MutexLock
+                int Throw
+        "#).unwrap();
+
+        let start = Instant::now();
+        let outcome = run_code(code, test_conf());
+
+        assert_eq!(outcome.shutdown_type, ShutdownType::Gracefully, "{:?}", outcome.error_data);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // `end` also wakes a thread blocked in an intrinsic
+        let code = asm::assemble("
+            .data
+            chan: .f64 0.0
+            wait: .f64 0.05
+
+            .code
+                int ChanCreate
+                mov [base+chan], ret0
+                spawn receiver
+                mov r0, [base+wait]
+                int Sleep
+                end
+
+            receiver:
+                mov r0, [base+chan]
+                int ChanRecv
+                int Throw
+        ").unwrap();
+
+        assert_eq!(run_code(code, test_conf()).shutdown_type, ShutdownType::Gracefully);
+
+        // A thread cancelled by id isn't stopped by the program, the reason stays unknown
+        let code = asm::assemble("
+                mov r0, (f64) 1.0
+                int ThreadCancel
+                mov r0, (f64) 0.0
+                int ThreadJoin
+        ").unwrap();
+
+        assert_eq!(run_code(code, test_conf()).shutdown_type, ShutdownType::None);
+    }
+
+    #[test]
+    pub fn exceptions() {
+        let code = asm::assemble(EXCEPTIONS).unwrap();
+
+        assert_eq!(run_code(code.clone(), test_conf()).shutdown_type, ShutdownType::Gracefully);
+        // The unwound call frame no longer counts towards the depth limit
+        assert_eq!(run_code(code, VMConfig { bounds_check: true, max_call_depth: 1, ..test_conf() }).shutdown_type, ShutdownType::Gracefully);
+
+        // A missing throw or a handler that wasn't removed makes the test fail
+        assert_eq!(run_code(asm::assemble(&EXCEPTIONS.replace("mov r1, 3", "mov r1, 1")).unwrap(), test_conf()).shutdown_type, ShutdownType::Error);
+        assert_eq!(run_code(asm::assemble(&EXCEPTIONS.replacen("endtry\n", "\n", 1)).unwrap(), test_conf()).shutdown_type, ShutdownType::Error);
+
+        let text = asm::disassemble(&asm::assemble(EXCEPTIONS).unwrap()).unwrap();
+
+        assert!(text.contains("    try loc_"));
+        assert!(text.contains("    throw d0"));
+        assert!(text.contains("    endtry"));
+    }
+
+    // Every handler checks RET0, so a failure that an outer handler catches still fails the test
+    const EXCEPTIONS: &str = r#"
+            .data
+            arr: .u64 2
+                 .u64 0
+                 .u64 0
+            boom: .str "boom"
+            bound: .str "Array Index 3 out of bound."
+            inner: .str "inner"
+
+            .code
+                mov r0, (f64) 1.0
+                mov r1, 3
+                try caught_elem
+                mov r0, (f64) 2.0
+                elem r2, [base+arr], r1
+                int Throw
+            caught_elem:
+                lstr d1, [base+bound]
+                cmp ret0, d1, eq
+                jt elem_message
+                int Throw
+            elem_message:
+                mov d1, (f64) 1.0
+                cmp r0, d1, eq      ; R0 is restored by the unwinding
+                jt elem_ok
+                int Throw
+            elem_ok:
+                try caught_throw
+                pushr func
+                call thrower
+                int Throw
+            caught_throw:
+                lstr d1, [base+boom]
+                cmp ret0, d1, eq
+                jt throw_ok
+                int Throw
+            throw_ok:
+                pushr func
+                call thrower_caught
+                pop func
+                try caught_inner
+                try wrong
+                endtry
+                lstr d0, [base+inner]
+                throw d0
+            wrong:
+                endtry
+                int Throw
+            caught_inner:
+                lstr d1, [base+inner]
+                cmp ret0, d1, eq
+                jt done
+                int Throw
+            done:
+                end
+
+            thrower:
+                mov r0, (f64) 5.0
+                lstr d0, [base+boom]
+                throw d0
+
+            thrower_caught:
+                try handler
+                mov d0, (f64) 1.0
+                throw d0
+            handler:
+                ret
+        "#;
+
+    #[test]
+    pub fn intrinsic_offsets() {
+        // INST is only advanced after an intrinsic returns, unless it threw to a handler
+        let code = asm::assemble("
+                int Debug
+                try caught
+                int Throw
+                end
+            caught:
+                int Throw
+        ").unwrap();
+
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let vm = VmBuilder::from_parts(code, VMConfig { error_policy: ErrorPolicy::Isolate, ..test_conf() }).stderr(Shared(stderr.clone())).build().unwrap();
+        let outcome = vm.run();
+
+        assert!(String::from_utf8(stderr.lock().unwrap().clone()).unwrap().contains("INST: 0x0000000000000008 "));
+        assert_eq!(outcome.faults, vec![ThreadFault { thread: 1, inst: 0x28, message: String::from("Called Intrinsic::Throw") }]);
+    }
+
+    #[test]
+    pub fn error_isolation() {
+        let code = asm::assemble("
+            .data
+            arr: .u64 0
+            wait: .f64 0.2
+
+            .code
+                spawn worker
+                mov r0, [base+wait]
+                int Sleep
+                end
+
+            worker:
+                mov r1, 1
+                elem r2, [base+arr], r1
+                end
+        ").unwrap();
+
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let mut extensions = Extensions::empty();
+
+        extensions.insert(1, FaultRecorder(reported.clone()));
+
+        let runtime = Runtime::new(Archive::new(code, VMConfig { error_policy: ErrorPolicy::Isolate, ..test_conf() }, None), extensions);
+
+        // The worker is disposed before it reaches `end`, the main thread still shuts down gracefully
+        let outcome = runtime.clone().run();
+
+        assert_eq!(outcome.shutdown_type, ShutdownType::Gracefully);
+        assert_eq!(outcome.faults, vec![ThreadFault { thread: 2, inst: 0x48, message: String::from("Array Index 1 out of bound.") }]);
+        assert_eq!(runtime.thread_faults(), outcome.faults);
+        assert_eq!(*reported.lock().unwrap(), vec![(2, 0x48)]);
+    }
+
+    // Faults reported through `VmExtension::thread_error`
+    struct FaultRecorder(Arc<Mutex<Vec<(u64, u64)>>>);
+
+    impl VmExtension for FaultRecorder {
+        fn init(&self, _runtime: Arc<Runtime>, _id: u32) {}
+
+        fn thread_error(&self, _runtime: Arc<Runtime>, thread: u64, inst: u64) {
+            self.0.lock().unwrap().push((thread, inst));
         }
     }
+
+    #[test]
+    pub fn run_outcome() {
+        let outcome = run_code(asm::assemble(CONTROL_FLOW).unwrap(), test_conf());
+
+        assert_eq!((outcome.exit_code, outcome.error_data, outcome.restarts), (0, None, 0));
+        assert_eq!(outcome.instructions, 47);
+
+        let code = asm::assemble(r#"
+            .data
+            boom: .str "boom"
+
+            .code
+                lstr d0, [base+boom]
+                throw d0
+        "#).unwrap();
+
+        let outcome = run_code(code.clone(), test_conf());
+
+        assert_eq!((outcome.shutdown_type, outcome.exit_code, outcome.instructions), (ShutdownType::Error, 1, 2));
+        assert!(outcome.error_data.unwrap().starts_with("Uncaught exception: "));
+
+        // Without other threads, the faulting thread stops the VM under both policies
+        let outcome = run_code(code, VMConfig { error_policy: ErrorPolicy::Isolate, ..test_conf() });
+
+        assert_eq!(outcome.shutdown_type, ShutdownType::Error);
+        assert_eq!(outcome.faults.len(), 1);
+        assert_eq!(outcome.error_data, Some(outcome.faults[0].to_string()));
+    }
+
+    // `ENV` returns twice the function id in RET0
+    struct Doubler {
+        ended: Arc<AtomicU32>,
+    }
+
+    impl VmExtension for Doubler {
+        fn init(&self, _runtime: Arc<Runtime>, id: u32) {
+            assert_eq!(id, 0x0711);
+        }
+
+        fn function_call(&self, thread: VThread, lock: Lock, id: u32, drop: bool) -> (Lock, ExecutorBehaviour) {
+            thread.set_reg(5, (id as f64 * 2.0).to_bits());
+
+            (if drop { None } else { lock }, ExecutorBehaviour::None)
+        }
+
+        fn dispatch_event(&self, _runtime: Arc<Runtime>, event: EventType) {
+            if let EventType::VMEnd = event {
+                self.ended.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[test]
+    pub fn embedding() {
+        let code = asm::assemble("
+            .code
+                env 0x0711, 21
+                int Debug
+            loop:
+                jmp loop
+        ").unwrap();
+
+        let ended = Arc::new(AtomicU32::new(0));
+
+        assert!(matches!(VmBuilder::from_parts(code.clone(), test_conf()).build(), Err(BuildError::Verify(_))));
+        assert!(VmBuilder::from_parts(code.clone(), test_conf()).load_extension(0x0711, "/nonexistent/libdoubler.so").is_err());
+
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let vm = VmBuilder::from_parts(code, test_conf()).extension(0x0711, Doubler { ended: ended.clone() }).stderr(Shared(stderr.clone())).build().unwrap();
+
+        {
+            let vm = vm.clone();
+
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                vm.stop();
+            });
+        }
+
+        assert_eq!(vm.run().shutdown_type, ShutdownType::Gracefully);
+        assert_eq!(ended.load(Ordering::SeqCst), 1);
+
+        let stderr = String::from_utf8(stderr.lock().unwrap().clone()).unwrap();
+
+        assert!(stderr.starts_with("----- Register Dump -----\n"));
+        assert!(stderr.contains("RET0: 0x4045000000000000 [float     42.00000000]"));
+    }
+
+    #[test]
+    pub fn disassembler() {
+        let code = asm::assemble(CONTROL_FLOW).unwrap();
+        let text = asm::disassemble(&code).unwrap();
+
+        assert!(text.contains("num_1: .f64 1.0\n"));
+        assert!(text.contains("    mov r1, [base+num_1]"));
+        assert!(text.contains("loc_0038:\n    add r0, r1"));
+        assert!(text.contains("    jt loc_0038"));
+        assert!(text.contains("    call loc_00C0"));
+        assert!(text.contains("    int Throw"));
+        assert_eq!(asm::assemble(&text).unwrap(), code);
+
+        let code = asm::assemble(r#"
+            .data
+            greeting: .str "Tab\t\"quoted\"\n"
+            empty: .str ""
+            nan: .u64 0x7FF4000000001234
+            .code
+                lstr d0, [base+greeting]
+                lstr d1, [base+empty]
+                mov [top+1], 0x7FF4000000000010
+                envj 0x0312, 2
+                int 0x42
+                end
+        "#).unwrap();
+        let text = asm::disassemble(&code).unwrap();
+
+        assert!(text.contains(r#"str_1: .str "Tab\t\"quoted\"\n""#));
+        assert!(text.contains("envj 786, 2"));
+        assert_eq!(asm::assemble(&text).unwrap(), code);
+
+        assert!(asm::disassemble(&[0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).is_err());
+        assert!(asm::disassemble(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap().contains("invalid instruction"));
+    }
+
+    #[test]
+    pub fn packer() {
+        let assets = env::temp_dir().join(format!("open-entry-vm-packer-{}", process::id()));
+
+        fs::create_dir_all(assets.join("sprites")).unwrap();
+        fs::write(assets.join("sound.wav"), b"RIFF").unwrap();
+        fs::write(assets.join("sprites").join("cat.png"), b"PNG").unwrap();
+
+        let conf = VMConfig {
+            executor_kind: ExecutorKind::SpinLockBlock,
+            threading_kind: ThreadingKind::Single,
+            max_threads: 0,
+            stack_size: 0x8000,
+            bounds_check: false,
+            max_call_depth: 0,
+            error_policy: ErrorPolicy::Shutdown,
+        };
+        let block_info = BlockInfo::new(HashMap::from([(0x10, UnlockInfo::Current), (0x20, UnlockInfo::Addr(0x40))]));
+        let mut archive = Archive::new(asm::assemble(CONTROL_FLOW).unwrap(), conf.clone(), Some(block_info.clone()));
+        let mut buffer = Vec::new();
+
+        archive.add_dir(&assets).unwrap();
+        archive.write(&mut buffer).unwrap();
+
+        fs::write(assets.join("Main.bin"), b"").unwrap();
+
+        assert!(Archive::new(Box::new([]), VMConfig::default(), None).add_dir(&assets).is_err());
+
+        fs::remove_dir_all(&assets).unwrap();
+
+        let read = Archive::read(buffer.as_slice()).unwrap();
+
+        assert_eq!(read.code, archive.code);
+        assert_eq!(read.conf, conf);
+        assert_eq!(read.block_info, Some(block_info.clone()));
+        assert_eq!(read.files.len(), 2);
+        assert_eq!(&*read.files["sound.wav"], b"RIFF");
+        assert_eq!(&*read.files["sprites/cat.png"], b"PNG");
+
+        assert_eq!(VMConfig::read(conf.write()).unwrap(), conf);
+        assert_eq!(BlockInfo::read(block_info.write()).unwrap(), block_info);
+    }
+
+    #[test]
+    pub fn debug_info() {
+        let range = |start, end, block, function: Option<&str>| SourceRange { start, end, block, function: function.map(String::from) };
+        let debug_info = DebugInfo::new(vec![
+            range(0x30, 0x40, 12, Some("foo")),
+            range(0x08, 0x30, 1, None),
+            range(0x40, 0x60, 13, Some("foo")),
+        ], vec![
+            Variable { start: 0x30, end: 0x60, location: VarLocation::Register(8), name: String::from("count") },
+            Variable { start: 0x40, end: 0x60, location: VarLocation::Stack(-12), name: String::from("arg") },
+        ]);
+
+        assert_eq!(debug_info.range(0x38).unwrap().to_string(), "in function `foo`, block #12");
+        assert_eq!(debug_info.range(0x10).unwrap().to_string(), "in block #1");
+        assert_eq!(debug_info.range(0x60), None);
+        assert_eq!(debug_info.block_start(13), Some(0x40));
+        assert_eq!(debug_info.variables(0x38).map(|x| x.name.as_str()).collect::<Vec<_>>(), vec!["count"]);
+        assert_eq!(debug_info.variables(0x48).count(), 2);
+
+        let buffer = debug_info.write();
+
+        assert_eq!(DebugInfo::read(buffer.clone()).unwrap(), debug_info);
+        assert_eq!(DebugInfo::read(buffer[..buffer.len() - 1].into()), Err(DebugInfoError::Truncated(buffer.len() - 4)));
+        assert_eq!(DebugInfo::read(Box::new([0; 7])), Err(DebugInfoError::Truncated(0)));
+
+        // One range whose function name points past the empty string table
+        let mut buffer = vec![0; 8];
+
+        buffer.extend_from_slice(&1u64.to_ne_bytes());
+        buffer.extend_from_slice(&[0; 20]);
+        buffer.extend_from_slice(&3u32.to_ne_bytes());
+
+        assert_eq!(DebugInfo::read(buffer.into_boxed_slice()), Err(DebugInfoError::UnknownString(3)));
+
+        let mut archive = Archive::new(asm::assemble(CONTROL_FLOW).unwrap(), test_conf(), None);
+        let mut buffer = Vec::new();
+
+        archive.debug_info = Some(debug_info.clone());
+        archive.write(&mut buffer).unwrap();
+
+        assert_eq!(Archive::read(buffer.as_slice()).unwrap().debug_info, Some(debug_info));
+    }
+
+    #[test]
+    pub fn verifier() {
+        let code = asm::assemble(CONTROL_FLOW).unwrap();
+
+        assert_eq!(verifier::verify(&code, |_| false), Ok(()));
+
+        let code = asm::assemble("
+                cmp r0, r1, eq
+                env 0x0311, 1
+                int 0x42
+                jmp 0x14
+                call 100
+            target:
+                mov r0, 1
+                jt target
+        ").unwrap();
+
+        let errors = verifier::verify(&code, |_| false).unwrap_err();
+        let offsets = errors.iter().map(|x| x.offset).collect::<Vec<_>>();
+
+        assert_eq!(offsets, vec![0x10, 0x18, 0x20, 0x28]);
+        assert_eq!(errors[0], VerifyError { offset: 0x10, message: String::from("Extension 785 is not loaded.") });
+        assert_eq!(errors[1].message, "Unknown intrinsic 0x42.");
+        assert_eq!(errors[2].message, "Target 0x001C is not on an instruction boundary.");
+        assert_eq!(errors[3].message, "Target 0x0320 is outside the code section.");
+        assert_eq!(verifier::verify(&code, |id| id == 0x0311).unwrap_err().len(), 3);
+
+        let mut code = asm::assemble("mov r0, 1\nend").unwrap().into_vec();
+
+        code[8] = 0x0C;
+        code[9] = 0x10;
+        code[11] = 0x06;
+        code.truncate(code.len() - 4);
+
+        let errors = verifier::verify(&code, |_| true).unwrap_err();
+
+        assert_eq!(errors.iter().map(|x| x.to_string()).collect::<Vec<_>>(), vec![
+            "0x0008: Invalid register index 16.",
+            "0x0008: Invalid comparison type 6.",
+            "0x0010: Unsupported instruction 0x01.",
+            "0x0018: Truncated instruction.",
+        ]);
+        assert!(verifier::verify(&[0x01], |_| true).is_err());
+    }
+
+    #[test]
+    pub fn debugger() {
+        let (debugger, mut events) = Debugger::new(false);
+        let archive = Archive {
+            files: HashMap::new(),
+            code: asm::assemble(CONTROL_FLOW).unwrap(),
+            block_info: None,
+            debug_info: None,
+            conf: VMConfig { executor_kind: ExecutorKind::SysLockInst, ..test_conf() },
+        };
+
+        debugger.set_breakpoint(0x38);
+
+        let runtime = Runtime::with_options(archive, Extensions::empty(), RuntimeOptions { debugger: Some(debugger.clone()), ..Default::default() });
+        let vm = thread::spawn(move || runtime.run());
+        let mut next_stop = || loop {
+            if let DebugEvent::Stopped { thread, reason, ip } = events.blocking_recv().unwrap() {
+                break (thread, reason, ip);
+            }
+        };
+
+        let (id, reason, ip) = next_stop();
+        let thread = debugger.stopped_thread(id).unwrap();
+
+        assert_eq!((reason, ip), (StopReason::Breakpoint, 0x38));
+        assert_eq!(f64::from_bits(thread.get_reg::<u64>(8)), 0.0);
+        assert_eq!(debugger.threads()[0].ip, Some(0x38));
+
+        thread.set_reg(8, 4.0f64.to_bits());
+        debugger.resume();
+
+        assert_eq!(next_stop(), (id, StopReason::Breakpoint, 0x38));
+        assert_eq!(f64::from_bits(thread.get_reg::<u64>(8)), 5.0);
+
+        debugger.clear_breakpoint(0x38);
+        debugger.step(id).unwrap();
+
+        assert_eq!(next_stop(), (id, StopReason::Step, 0x40));
+        assert_eq!(f64::from_bits(thread.get_reg::<u64>(8)), 6.0);
+
+        // Stop at `ret` of `double`, the caller frame holds the return address and the saved R0
+        debugger.set_breakpoint(0xE0);
+        debugger.resume();
+
+        assert_eq!(next_stop(), (id, StopReason::Breakpoint, 0xE0));
+
+        let frames = frames(&thread);
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(f64::from_bits(frames[0].registers[8]), 0.0);
+        assert_eq!(frames[1].registers[0], 0x70);
+        assert_eq!(f64::from_bits(frames[1].registers[8]), 10.0);
+        assert_eq!(frames[1].registers[4], frames[0].registers[2] + 88);
+
+        debugger.clear_breakpoint(0xE0);
+        drop(thread);
+        debugger.resume();
+
+        assert_eq!(vm.join().unwrap().shutdown_type, ShutdownType::Gracefully);
+        assert!(debugger.stopped_thread(id).is_err());
+
+        // Stopping the VM releases a thread parked on entry
+        let (debugger, _events) = Debugger::new(true);
+        let vm = VmBuilder::from_parts(asm::assemble("loop: jmp loop").unwrap(), test_conf()).debugger(debugger.clone()).build().unwrap();
+        let run = {
+            let vm = vm.clone();
+
+            thread::spawn(move || vm.run())
+        };
+
+        while debugger.stopped_thread(1).is_err() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        vm.stop();
+
+        assert_eq!(run.join().unwrap().shutdown_type, ShutdownType::Gracefully);
+
+        // So does terminating through the debugger, as the REPL's `quit` does
+        let (debugger, _events) = Debugger::new(true);
+        let vm = VmBuilder::from_parts(asm::assemble("loop: jmp loop").unwrap(), test_conf()).debugger(debugger.clone()).build().unwrap();
+        let run = thread::spawn(move || vm.run());
+
+        while debugger.stopped_thread(1).is_err() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        debugger.terminate();
+
+        assert_eq!(run.join().unwrap().shutdown_type, ShutdownType::Gracefully);
+    }
+
+    #[test]
+    pub fn dap_session() {
+        let (debugger, events) = Debugger::new(true);
+        let code = asm::assemble("
+                mov r0, (f64) 1.0
+                int Debug
+                mov r0, (f64) 2.0
+                end
+        ").unwrap();
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let session = Session::new(debugger.clone(), code.clone(), None, Box::new(Shared(output.clone())));
+
+        session.forward_events(events);
+
+        let vm = VmBuilder::from_parts(code, test_conf()).debugger(debugger.clone()).stdout(session.output("stdout")).stderr(session.output("stderr")).build().unwrap();
+        let vm = thread::spawn(move || vm.run());
+
+        // Requests are only answered for a stopped thread, so wait for the stop on entry
+        while debugger.stopped_thread(1).is_err() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let script = [
+            json!({ "seq": 1, "type": "request", "command": "initialize", "arguments": { "adapterID": "open-entry-vm" } }),
+            json!({ "seq": 2, "type": "request", "command": "launch", "arguments": { "stopOnEntry": true } }),
+            json!({ "seq": 3, "type": "request", "command": "setInstructionBreakpoints", "arguments": { "breakpoints": [{ "instructionReference": "0x20" }, { "instructionReference": "0x21" }] } }),
+            json!({ "seq": 4, "type": "request", "command": "configurationDone" }),
+            json!({ "seq": 5, "type": "request", "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "seq": 6, "type": "request", "command": "variables", "arguments": { "variablesReference": (1 << 16) << 2 | 1 } }),
+            json!({ "seq": 7, "type": "request", "command": "continue", "arguments": { "threadId": 1 } }),
+        ].iter().map(|x| {
+            let body = x.to_string();
+
+            format!("Content-Length: {}\r\n\r\n{body}", body.len())
+        }).collect::<String>();
+
+        // Returns at the end of the script, detaching lets the program run to completion
+        session.serve(io::Cursor::new(script));
+
+        assert_eq!(vm.join().unwrap().shutdown_type, ShutdownType::Gracefully);
+
+        let output = output.lock().unwrap().clone();
+        let mut reader = io::Cursor::new(output);
+        let mut messages = Vec::new();
+
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+
+        let responses = messages.iter().filter(|x| x["type"] == "response").collect::<Vec<_>>();
+        let events = messages.iter().filter(|x| x["type"] == "event").map(|x| x["event"].as_str().unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(responses.iter().map(|x| x["command"].as_str().unwrap()).collect::<Vec<_>>(), vec!["initialize", "launch", "setInstructionBreakpoints", "configurationDone", "stackTrace", "variables", "continue"]);
+        assert!(responses.iter().all(|x| x["success"] == true));
+        assert_eq!(responses[2]["body"]["breakpoints"][0]["verified"], true);
+        assert_eq!(responses[2]["body"]["breakpoints"][1]["verified"], false);
+        assert_eq!(responses[4]["body"]["stackFrames"][0]["instructionPointerReference"], "0x0008");
+        assert!(responses[5]["body"]["variables"].as_array().unwrap().iter().any(|x| x["name"] == "R0"));
+        assert!(events.contains(&"initialized") && events.contains(&"stopped"));
+
+        // The program's output reaches the client as events instead of corrupting the stream
+        assert!(messages.iter().any(|x| x["event"] == "output" && x["body"]["category"] == "stderr" && x["body"]["output"].as_str().unwrap().starts_with("----- Register Dump -----")));
+
+        // `terminate` shuts a program down that would never end by itself
+        let (debugger, events) = Debugger::new(true);
+        let code = asm::assemble("loop: jmp loop").unwrap();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let session = Session::new(debugger.clone(), code.clone(), None, Box::new(Shared(output.clone())));
+
+        session.forward_events(events);
+
+        let vm = VmBuilder::from_parts(code, test_conf()).debugger(debugger.clone()).build().unwrap();
+        let vm = thread::spawn(move || vm.run());
+
+        while debugger.stopped_thread(1).is_err() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let script = [
+            json!({ "seq": 1, "type": "request", "command": "initialize", "arguments": { "adapterID": "open-entry-vm" } }),
+            json!({ "seq": 2, "type": "request", "command": "launch", "arguments": {} }),
+            json!({ "seq": 3, "type": "request", "command": "configurationDone" }),
+            json!({ "seq": 4, "type": "request", "command": "terminate" }),
+        ].iter().map(|x| {
+            let body = x.to_string();
+
+            format!("Content-Length: {}\r\n\r\n{body}", body.len())
+        }).collect::<String>();
+
+        session.serve(io::Cursor::new(script));
+
+        assert_eq!(vm.join().unwrap().shutdown_type, ShutdownType::Gracefully);
+    }
+
+    #[test]
+    pub fn flags() {
+        let (debugger, mut events) = Debugger::new(true);
+        let archive = Archive {
+            files: HashMap::new(),
+            code: asm::assemble("end").unwrap(),
+            block_info: None,
+            debug_info: None,
+            conf: test_conf(),
+        };
+
+        let runtime = Runtime::with_options(archive, Extensions::empty(), RuntimeOptions { debugger: Some(debugger.clone()), ..Default::default() });
+        let vm = thread::spawn(move || runtime.run());
+
+        let id = loop {
+            if let DebugEvent::Stopped { thread, .. } = events.blocking_recv().unwrap() {
+                break thread;
+            }
+        };
+
+        let thread = debugger.stopped_thread(id).unwrap();
+
+        // Setting a flag leaves the others unchanged
+        thread.set_flag(1, true);
+
+        assert!(!thread.get_flag(0) && thread.get_flag(1));
+
+        thread.set_flag(0, true);
+        thread.set_flag(1, false);
+
+        assert!(thread.get_flag(0) && !thread.get_flag(1));
+
+        drop(thread);
+        debugger.resume();
+
+        assert_eq!(vm.join().unwrap().shutdown_type, ShutdownType::Gracefully);
+    }
+
+    #[test]
+    pub fn block_debugger() {
+        let code = asm::assemble("
+                spawn worker
+                mov r0, (f64) 1.0
+                mov r0, (f64) 2.0
+                mov r0, (f64) 3.0
+                end
+            worker:
+                mov r1, (f64) 1.0
+                jmp worker
+        ").unwrap();
+
+        for executor_kind in [ExecutorKind::SysLockBlock, ExecutorKind::SpinLockBlock] {
+            let (debugger, mut events) = Debugger::new(false);
+            let archive = Archive {
+                files: HashMap::new(),
+                code: code.clone(),
+                block_info: Some(BlockInfo::new(HashMap::from([(0x10, UnlockInfo::Addr(0x30)), (0x48, UnlockInfo::Current), (0x58, UnlockInfo::Current)]))),
+                debug_info: None,
+                conf: VMConfig { executor_kind, ..test_conf() },
+            };
+
+            debugger.set_breakpoint(0x20);
+
+            let runtime = Runtime::with_options(archive, Extensions::empty(), RuntimeOptions { debugger: Some(debugger.clone()), ..Default::default() });
+            let vm = thread::spawn(move || runtime.run());
+            let mut next_stop = || {
+                let deadline = Instant::now() + Duration::from_secs(5);
+
+                loop {
+                    match events.try_recv() {
+                        Ok(DebugEvent::Stopped { thread, reason, ip }) => break (thread, reason, ip),
+                        Ok(_) => {}
+                        Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+                        Err(_) => panic!("The debugger didn't stop in time.")
+                    }
+                }
+            };
+
+            let (main, reason, ip) = next_stop();
+
+            assert_eq!((reason, ip), (StopReason::Breakpoint, 0x20));
+
+            // The worker parks before its next instruction, outside the block of the main thread
+            let worker = loop {
+                if let Some(info) = debugger.threads().into_iter().find(|x| x.id != main && x.ip.is_some()) {
+                    break info.id;
+                }
+
+                thread::sleep(Duration::from_millis(1));
+            };
+
+            // Stepping the worker needs the executor lock of the block the main thread is parked in
+            debugger.step(worker).unwrap();
+
+            assert_eq!(next_stop().0, worker);
+
+            debugger.step(main).unwrap();
+
+            assert_eq!(next_stop(), (main, StopReason::Step, 0x30));
+            assert_eq!(f64::from_bits(debugger.stopped_thread(main).unwrap().get_reg::<u64>(8)), 2.0);
+
+            debugger.clear_breakpoint(0x20);
+            debugger.resume();
+
+            assert_eq!(vm.join().unwrap().shutdown_type, ShutdownType::Gracefully);
+        }
+    }
+
+    #[test]
+    pub fn profiler() {
+        let profiler = Arc::new(Profiler::new(None, None));
+        let archive = Archive::new(asm::assemble(CONTROL_FLOW).unwrap(), VMConfig { executor_kind: ExecutorKind::SysLockInst, ..test_conf() }, None);
+        let runtime = Runtime::with_options(archive, Extensions::empty(), RuntimeOptions { profiler: Some(profiler.clone()), ..Default::default() });
+
+        assert_eq!(runtime.clone().run().shutdown_type, ShutdownType::Gracefully);
+
+        let profile = profiler.profile();
+
+        assert_eq!(profile.opcodes[&OpCodes::CMP].count, 12);
+        assert_eq!(profile.addresses[&0x38].count, 10);
+        assert_eq!(profile.lock_wait.count, profile.total.count);
+        assert_eq!(profile.functions[&0x18].calls, 1);
+        assert_eq!((profile.functions[&0xC0].calls, profile.functions[&0xC0].own.count), (1, 4));
+        assert_eq!(profile.functions[&0x18].own.count + 4, profile.total.count);
+        assert_eq!(profile.stacks.len(), 2);
+
+        drop(profile);
+
+        let folded = profiler.folded(&runtime.archive);
+
+        assert!(folded.starts_with("0x0018 ") && folded.lines().nth(1).unwrap().starts_with("0x0018;0x00C0 "));
+        assert!(profiler.report(&runtime.archive).contains("0x0038 add r0, r1"));
+    }
+
+    #[test]
+    pub fn trace() {
+        let run = |format, filter| {
+            let buffer = Arc::new(Mutex::new(Vec::new()));
+            let tracer = Tracer::new(format, filter, Box::new(Shared(buffer.clone()))).unwrap();
+            let archive = Archive::new(asm::assemble(CONTROL_FLOW).unwrap(), test_conf(), None);
+            let runtime = Runtime::with_options(archive, Extensions::empty(), RuntimeOptions { tracer: Some(Arc::new(tracer)), ..Default::default() });
+
+            assert_eq!(runtime.run().shutdown_type, ShutdownType::Gracefully);
+
+            buffer.lock().map(|x| x.clone()).unwrap()
+        };
+
+        let json = String::from_utf8(run(TraceFormat::JsonLines, TraceFilter { opcodes: HashSet::from([OpCodes::CMP]), ..Default::default() })).unwrap();
+        let lines = json.lines().map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap()).collect::<Vec<_>>();
+
+        // 10 loop iterations and the two checks after `call double`
+        assert_eq!(lines.len(), 12);
+        assert_eq!(lines[0]["thread"], 1);
+        assert_eq!(lines[0]["ip"], "0x0040");
+        assert_eq!(lines[0]["op"], "cmp");
+        assert_eq!(lines[0]["regs"][0]["reg"], "R0");
+        assert_eq!(lines[0]["regs"][0]["after"], format!("0x{:016X}", 1.0f64.to_bits()));
+        assert_eq!(lines[0]["flag0"], true);
+        assert_eq!(lines[1].get("flag0"), None);
+        assert_eq!(lines[9]["flag0"], false);
+
+        // Only `add r0, r1` at 0x38: header, then 10 records of 19 bytes plus 2 operands of 17 bytes
+        let binary = run(TraceFormat::Binary, TraceFilter { range: Some((0x38, 0x40)), ..Default::default() });
+
+        assert_eq!(binary.len(), 8 + 10 * (19 + 2 * 17));
+        assert_eq!(&binary[..8], b"OETRACE1");
+        assert_eq!(&binary[16..27], &[0x38, 0, 0, 0, 0, 0, 0, 0, OpCodes::ADD, 0b00, 2]);
+    }
 }
\ No newline at end of file
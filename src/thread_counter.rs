@@ -1,6 +1,6 @@
-use std::{sync::{Arc, atomic::{AtomicU32, Ordering, AtomicU8}}, mem, pin::Pin};
+use std::{sync::{Arc, atomic::{AtomicU32, AtomicU64, Ordering, AtomicU8, AtomicBool}}, mem, pin::Pin, fmt::{self, Display}, collections::HashMap};
 
-use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use tokio::sync::{mpsc::UnboundedSender, Mutex, Notify};
 
 use crate::{virtual_thread::{VirtualThread, VThread}, runtime::Runtime};
 
@@ -12,19 +12,99 @@ pub enum ShutdownType {
     Error      = 3,
 }
 
+impl ShutdownType {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ShutdownType::Gracefully | ShutdownType::Restarting => 0,
+            ShutdownType::Error => 1,
+            ShutdownType::None => 3,
+        }
+    }
+}
+
+// Uncaught error of a virtual thread that was disposed under `ErrorPolicy::Isolate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadFault {
+    pub thread: u64,
+    pub inst: u64,
+    pub message: String,
+}
+
+impl Display for ThreadFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Thread {} at 0x{:04X}: {}", self.thread, self.inst, self.message)
+    }
+}
+
+// Cancellation token of a virtual thread, shared with the threads joining or cancelling it by id
+#[derive(Default)]
+pub struct ThreadHandle {
+    cancelled: AtomicBool,
+    ended: AtomicBool,
+    cancel_notify: Notify,
+    end_notify: Notify,
+}
+
+impl ThreadHandle {
+    // Observed by `VirtualThread::should_stop` before the next instruction, and by blocking intrinsics
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.cancel_notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub async fn cancelled(&self) {
+        let notified = self.cancel_notify.notified();
+
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended.load(Ordering::SeqCst)
+    }
+
+    fn end(&self) {
+        self.ended.store(true, Ordering::SeqCst);
+        self.end_notify.notify_waiters();
+    }
+
+    pub async fn join(&self) {
+        let notified = self.end_notify.notified();
+
+        if !self.is_ended() {
+            notified.await;
+        }
+    }
+}
+
 pub struct ThreadCounter {
     error_data: Arc<Mutex<Option<String>>>,
+    faults: std::sync::Mutex<Vec<ThreadFault>>,
+    // Threads that haven't ended yet
+    handles: std::sync::Mutex<HashMap<u64, Arc<ThreadHandle>>>,
     ch: UnboundedSender<ShutdownType>,
     shutdown_type: AtomicU8,
+    // Set by StopSelf and StopOthers
+    stopped: AtomicBool,
     counter: AtomicU32,
+    next_id: AtomicU64,
 }
 
 impl ThreadCounter {
     pub fn new(tx: UnboundedSender<ShutdownType>) -> ThreadCounter {
         ThreadCounter {
             shutdown_type: AtomicU8::new(ShutdownType::None as u8),
+            stopped: AtomicBool::new(false),
             error_data: Arc::new(Mutex::new(None)),
+            faults: std::sync::Mutex::new(Vec::new()),
+            handles: std::sync::Mutex::new(HashMap::new()),
             counter: AtomicU32::new(0),
+            next_id: AtomicU64::new(1),
             ch: tx,
         }
     }
@@ -33,7 +113,16 @@ impl ThreadCounter {
         let arc_thread = unsafe { Pin::into_inner_unchecked(thread) };
 
         if Arc::strong_count(&arc_thread) == 1 {
+            if let Some(handle) = self.handles.lock().unwrap().remove(&arc_thread.id) {
+                handle.end();
+            }
+
             if self.counter.fetch_sub(1, Ordering::SeqCst) == 1 {
+                // Threads stopped by the program end the VM gracefully, otherwise the shutdown type stays unknown
+                if self.stopped.load(Ordering::SeqCst) {
+                    self.set_default_shutdown_type(ShutdownType::Gracefully);
+                }
+
                 self.ch.send(unsafe { mem::transmute(self.shutdown_type.load(Ordering::SeqCst)) }).unwrap();
             }
         }
@@ -42,13 +131,51 @@ impl ThreadCounter {
     pub async fn create(&self, runtime: Arc<Runtime>, stack_size: usize, addr: u64) -> VThread {
         self.counter.fetch_add(1, Ordering::SeqCst);
 
-        VirtualThread::new(runtime, stack_size, addr).await
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let handle = Arc::new(ThreadHandle::default());
+
+        self.handles.lock().unwrap().insert(id, handle.clone());
+
+        VirtualThread::new(runtime, id, handle, stack_size, addr).await
+    }
+
+    pub fn set_stopped(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    // Cancels every live thread except `id`
+    pub fn cancel_others(&self, id: u64) {
+        for (_, handle) in self.handles.lock().unwrap().iter().filter(|(other, _)| **other != id) {
+            handle.cancel();
+        }
+    }
+
+    pub fn handle(&self, id: u64) -> Option<Arc<ThreadHandle>> {
+        self.handles.lock().unwrap().get(&id).cloned()
+    }
+
+    // Any thread created so far, ended or not
+    pub fn is_id(&self, id: u64) -> bool {
+        id != 0 && id < self.next_id.load(Ordering::SeqCst)
     }
 
     pub fn set_shutdown_type(&self, code: ShutdownType) {
         self.shutdown_type.store(code as u8, Ordering::SeqCst);
     }
 
+    // Keeps a shutdown type that was already set, e.g. by END
+    pub fn set_default_shutdown_type(&self, code: ShutdownType) {
+        let _ = self.shutdown_type.compare_exchange(ShutdownType::None as u8, code as u8, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    pub fn add_fault(&self, fault: ThreadFault) {
+        self.faults.lock().unwrap().push(fault);
+    }
+
+    pub fn faults(&self) -> Vec<ThreadFault> {
+        self.faults.lock().unwrap().clone()
+    }
+
     pub async fn set_error_data(&self, data: String) {
         *self.error_data.lock().await = Some(data);
     }
@@ -59,6 +186,8 @@ impl ThreadCounter {
 
     pub async fn reset(&self) {
         *self.error_data.lock().await = None;
+        self.faults.lock().unwrap().clear();
+        self.stopped.store(false, Ordering::SeqCst);
         self.set_shutdown_type(ShutdownType::None);
     }
 }
\ No newline at end of file
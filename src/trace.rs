@@ -0,0 +1,136 @@
+use std::{sync::Mutex, collections::HashSet, io::{self, Write}, str::FromStr};
+
+use serde_json::json;
+
+use crate::{virtual_thread::VThread, op_codes::OpCodes, register::REGISTER_NAMES, verifier, asm::{Instruction, disassembler::format_instruction}};
+
+// Binary records (little endian): u64 thread id, u64 instruction offset, u8 opcode, u8 flag 0 (bit 0 before, bit 1 after),
+// u8 operand count, then per operand: u8 register, u64 value before, u64 value after
+pub const BINARY_MAGIC: &[u8; 8] = b"OETRACE1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    JsonLines,
+    Binary,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "json" | "jsonl" => Ok(TraceFormat::JsonLines),
+            "bin" | "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("Unknown trace format `{value}`."))
+        }
+    }
+}
+
+// Empty sets and a missing range match everything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub range: Option<(u64, u64)>,
+    pub threads: HashSet<u64>,
+    pub opcodes: HashSet<u8>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, thread: u64, ip: u64, op: u8) -> bool {
+        self.range.map_or(true, |(start, end)| start <= ip && ip < end)
+            && (self.threads.is_empty() || self.threads.contains(&thread))
+            && (self.opcodes.is_empty() || self.opcodes.contains(&op))
+    }
+}
+
+// State of an instruction before it runs
+pub struct TraceEntry {
+    inst: Instruction,
+    ip: u64,
+    regs: Vec<(u8, u64)>,
+    flag: bool,
+}
+
+pub struct Tracer {
+    format: TraceFormat,
+    filter: TraceFilter,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat, filter: TraceFilter, mut output: Box<dyn Write + Send>) -> io::Result<Tracer> {
+        if format == TraceFormat::Binary {
+            output.write_all(BINARY_MAGIC)?;
+        }
+
+        Ok(Tracer { format, filter, output: Mutex::new(output) })
+    }
+
+    pub fn begin(&self, thread: &VThread) -> Option<TraceEntry> {
+        let ip = thread.get_reg::<u64>(0);
+        let inst = Instruction::decode(&thread.runtime.archive.code, ip as usize)?;
+
+        if !self.filter.matches(thread.id, ip, inst.opcode()) {
+            return None;
+        }
+
+        let mut regs = verifier::registers(&inst).unwrap_or_default();
+
+        regs.retain(|&x| (x as usize) < REGISTER_NAMES.len());
+        regs.dedup();
+
+        Some(TraceEntry {
+            regs: regs.into_iter().map(|x| (x, thread.get_reg::<u64>(x))).collect(),
+            flag: thread.get_flag(0),
+            inst,
+            ip,
+        })
+    }
+
+    pub fn end(&self, entry: TraceEntry, thread: &VThread) {
+        let after = entry.regs.iter().map(|&(reg, _)| thread.get_reg::<u64>(reg)).collect::<Vec<_>>();
+        let flag = thread.get_flag(0);
+        let op = entry.inst.opcode();
+
+        let mut record = Vec::new();
+
+        match self.format {
+            TraceFormat::JsonLines => {
+                let mut value = json!({
+                    "thread": thread.id,
+                    "ip": format!("0x{:04X}", entry.ip),
+                    "op": OpCodes::name(op).unwrap_or("?").to_ascii_lowercase(),
+                    "inst": format_instruction(&entry.inst),
+                    "regs": entry.regs.iter().zip(&after).map(|(&(reg, before), after)| json!({
+                        "reg": REGISTER_NAMES[reg as usize],
+                        "before": format!("0x{before:016X}"),
+                        "after": format!("0x{after:016X}"),
+                    })).collect::<Vec<_>>(),
+                });
+
+                if flag != entry.flag {
+                    value["flag0"] = json!(flag);
+                }
+
+                record.extend_from_slice(value.to_string().as_bytes());
+                record.push(b'\n');
+            }
+            TraceFormat::Binary => {
+                record.extend_from_slice(&thread.id.to_le_bytes());
+                record.extend_from_slice(&entry.ip.to_le_bytes());
+                record.extend_from_slice(&[op, entry.flag as u8 | (flag as u8) << 1, entry.regs.len() as u8]);
+
+                for (&(reg, before), after) in entry.regs.iter().zip(&after) {
+                    record.push(reg);
+                    record.extend_from_slice(&before.to_le_bytes());
+                    record.extend_from_slice(&after.to_le_bytes());
+                }
+            }
+        }
+
+        let _ = self.output.lock().unwrap().write_all(&record);
+    }
+
+    pub fn flush(&self) {
+        let _ = self.output.lock().unwrap().flush();
+    }
+}
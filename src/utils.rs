@@ -1,8 +1,18 @@
 macro_rules! gen_enum {
     ($name: ident, $type: ty, [$($key: ident = $value: literal,)*]) => {
-        #[allow(non_snake_case, non_upper_case_globals)]
+        #[allow(non_snake_case, non_upper_case_globals, dead_code)]
         pub mod $name {
             $(pub const $key: $type = $value;)*
+
+            pub const ENTRIES: &[(&str, $type)] = &[$((stringify!($key), $value),)*];
+
+            pub fn name(value: $type) -> Option<&'static str> {
+                ENTRIES.iter().find(|x| x.1 == value).map(|x| x.0)
+            }
+
+            pub fn from_name(name: &str) -> Option<$type> {
+                ENTRIES.iter().find(|x| x.0.eq_ignore_ascii_case(name)).map(|x| x.1)
+            }
         }
     }
 }
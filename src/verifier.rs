@@ -0,0 +1,111 @@
+use std::{collections::HashSet, fmt::{self, Display}, error::Error};
+
+use crate::{op_codes::{OpCodes, OpLayout}, asm::{Instruction, sections}, register::REGISTER_NAMES, vm_intrinsics::intrinsics::Intrinsic};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct VerifyError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:04X}: {}", self.offset, self.message)
+    }
+}
+
+impl Error for VerifyError {}
+
+// Register operands of a valid instruction, None if the opcode or layout is unknown
+pub fn registers(inst: &Instruction) -> Option<Vec<u8>> {
+    Some(match inst.op {
+        x if x == OpCodes::MOV | OpLayout::R_R => vec![inst.a, inst.b],
+        x if x == OpCodes::MOV | OpLayout::R_RO => vec![inst.a, inst.b],
+        x if x == OpCodes::MOV | OpLayout::RO_R => vec![inst.a, inst.b],
+        x if x == OpCodes::MOV | OpLayout::R_I => vec![inst.a],
+        x if x == OpCodes::MOV | OpLayout::RO_I => vec![inst.a],
+        OpCodes::LSTR | OpCodes::LEA1 => vec![inst.a, inst.b],
+        OpCodes::LEA2 | OpCodes::ELEM => vec![inst.a, inst.b, inst.c],
+        OpCodes::ADD | OpCodes::SUB | OpCodes::MUL | OpCodes::DIV | OpCodes::IDIV | OpCodes::REM | OpCodes::CMP => vec![inst.a, inst.b],
+        OpCodes::PUSHR | OpCodes::POP | OpCodes::DROP | OpCodes::SUB32 | OpCodes::ADD32 | OpCodes::THROW | OpCodes::SPAWN => vec![inst.a],
+        OpCodes::END | OpCodes::RET | OpCodes::CALL | OpCodes::JT | OpCodes::JMP
+            | OpCodes::PUSHI | OpCodes::INT | OpCodes::ENV | OpCodes::ENVJ | OpCodes::TRY | OpCodes::ENDTRY => vec![],
+        _ => return None
+    })
+}
+
+// Checks every instruction of Main.bin before it is executed and reports all problems found
+pub fn verify(code: &[u8], is_loaded: impl Fn(u32) -> bool) -> Result<(), Vec<VerifyError>> {
+    let start = match sections(code) {
+        Some((_, start)) => start,
+        None => return Err(vec![VerifyError { offset: 0, message: String::from("Main.bin is too short or its data length exceeds the file.") }])
+    };
+
+    let mut errors = Vec::new();
+    let mut insts = Vec::new();
+    let mut offset = start;
+
+    while offset < code.len() {
+        match Instruction::decode(code, offset) {
+            Some(inst) => {
+                offset += inst.size();
+                insts.push(inst);
+            }
+            None => {
+                let message = match code.get(offset) {
+                    Some(&op) if Instruction::is_fat(op) && code.len() - offset >= 8 => "Fat instruction is missing its 8-byte immediate.",
+                    _ => "Truncated instruction."
+                };
+
+                errors.push(VerifyError { offset, message: String::from(message) });
+
+                break;
+            }
+        }
+    }
+
+    if insts.is_empty() && errors.is_empty() {
+        errors.push(VerifyError { offset: start, message: String::from("Code section is empty.") });
+    }
+
+    let boundaries = insts.iter().map(|x| x.offset as u64).collect::<HashSet<_>>();
+
+    for inst in &insts {
+        let mut error = |message: String| errors.push(VerifyError { offset: inst.offset, message });
+
+        let regs = match registers(inst) {
+            Some(regs) => regs,
+            None => {
+                error(format!("Unsupported instruction 0x{:02X}.", inst.op));
+
+                continue;
+            }
+        };
+
+        for reg in regs {
+            if reg as usize >= REGISTER_NAMES.len() {
+                error(format!("Invalid register index {reg}."));
+            }
+        }
+
+        match inst.op {
+            OpCodes::CMP if inst.c > 5 => error(format!("Invalid comparison type {}.", inst.c)),
+            OpCodes::INT if Intrinsic::name(inst.a).is_none() => error(format!("Unknown intrinsic 0x{:02X}.", inst.a)),
+            OpCodes::ENV | OpCodes::ENVJ if !is_loaded(inst.extension_id()) => error(format!("Extension {} is not loaded.", inst.extension_id())),
+            _ => {}
+        }
+
+        if let Some(target) = inst.target() {
+            if target < start as u64 || target >= code.len() as u64 {
+                error(format!("Target 0x{target:04X} is outside the code section."));
+            } else if !boundaries.contains(&target) {
+                error(format!("Target 0x{target:04X} is not on an instruction boundary."));
+            }
+        }
+    }
+
+    // Stable, so the errors of one instruction keep their order
+    errors.sort_by_key(|x| x.offset);
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
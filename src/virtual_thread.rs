@@ -2,11 +2,16 @@ use std::{sync::{Arc, atomic::{AtomicU64, Ordering}}, intrinsics, mem, pin::Pin,
 
 use tokio::sync::MutexGuard;
 
-use crate::{register::Register, shared_memory::SharedMemory, runtime::Runtime, thread_counter::ShutdownType, stack::Stack, executor::executor::ExecutorLock, block_info::BlockInfo, extensions::Extension, extension_data::ExtensionData};
+use crate::{register::{Register, register_name}, shared_memory::SharedMemory, runtime::Runtime, thread_counter::{ShutdownType, ThreadFault, ThreadHandle}, vm_config::ErrorPolicy, stack::Stack, executor::executor::ExecutorLock, block_info::BlockInfo, debug_info::SourceRange, extensions::Extension, extension_data::ExtensionData, string::VMStr, vm_value::VMValue};
+
+// Words of a handler frame pushed by TRY: D0..R7, FUNC, call depth, previous frame, handler address
+pub const HANDLER_FRAME_WORDS: u64 = 14;
 
 pub type VThread = Pin<Arc<VirtualThread>>;
 
 pub struct VirtualThread {
+    pub id: u64,
+    pub handle: Arc<ThreadHandle>,
     pub runtime: Arc<Runtime>,
     pub memory: SharedMemory,
     pub lock: ExecutorLock,
@@ -16,13 +21,20 @@ pub struct VirtualThread {
 
     registers: [Register; 16],
     stack_size: usize,
+    bounds_check: bool,
+    call_depth: AtomicU64,
+    instructions: AtomicU64,
+    // Error data of this thread under `ErrorPolicy::Isolate`
+    error_data: std::sync::Mutex<Option<String>>,
+    // TOP after the newest handler frame was pushed, 0 when no handler is installed
+    handler: AtomicU64,
     flags: AtomicU64,
 
     _phantom: PhantomPinned
 }
 
 impl VirtualThread {
-    pub async fn new(runtime: Arc<Runtime>, stack_size: usize, addr: u64) -> VThread {
+    pub async fn new(runtime: Arc<Runtime>, id: u64, handle: Arc<ThreadHandle>, stack_size: usize, addr: u64) -> VThread {
         let mut registers: [Register; 16] = Default::default();
 
         registers[0] = Register { r64: addr };
@@ -34,13 +46,20 @@ impl VirtualThread {
         drop(memory_lock);
 
         let vthread = Arc::pin(VirtualThread {
-            lock: ExecutorLock::from_archive(&runtime.archive),
+            lock: runtime.lock.clone(),
+            bounds_check: runtime.archive.conf.bounds_check,
             
             extension_data: ExtensionData::new(),
             stack: Stack::new(stack_size),
+            call_depth: AtomicU64::new(0),
+            instructions: AtomicU64::new(0),
+            error_data: std::sync::Mutex::new(None),
+            handler: AtomicU64::new(0),
             flags: AtomicU64::new(0),
             registers: registers,
             runtime: runtime,
+            id,
+            handle,
             stack_size,
             memory,
 
@@ -52,6 +71,10 @@ impl VirtualThread {
         vthread
     }
 
+    pub fn stack_size(&self) -> usize {
+        self.stack_size
+    }
+
     pub fn get_extension(&self, id: u32) -> Arc<Extension> {
         self.runtime.extensions.get(id)
     }
@@ -61,7 +84,20 @@ impl VirtualThread {
     }
 
     pub async fn set_error_data(&self, data: impl Into<String>) {
-        self.runtime.set_error_data(data.into()).await;
+        let data = match self.source_range() {
+            Some(range) => format!("{}\n  at 0x{:04X} {range}", data.into(), self.get_reg::<u64>(0)),
+            None => data.into()
+        };
+
+        match self.runtime.archive.conf.error_policy {
+            ErrorPolicy::Isolate => *self.error_data.lock().unwrap() = Some(data),
+            ErrorPolicy::Shutdown => self.runtime.set_error_data(data).await
+        }
+    }
+
+    // Source block of the current instruction, if the archive has debug info
+    pub fn source_range(&self) -> Option<&SourceRange> {
+        self.runtime.archive.debug_info.as_ref()?.range(self.get_reg::<u64>(0))
     }
 
     pub fn get_block_info(&self) -> Arc<BlockInfo> {
@@ -69,7 +105,7 @@ impl VirtualThread {
     }
 
     pub fn should_stop(&self) -> bool {
-        self.runtime.shutdown.load(Ordering::SeqCst)
+        self.runtime.shutdown.load(Ordering::SeqCst) || self.handle.is_cancelled()
     }
 
     pub async fn spawn(&self, addr: u64) {
@@ -77,7 +113,7 @@ impl VirtualThread {
     }
 
     pub fn set_flag(&self, id: u64, value: bool) {
-        self.flags.store((self.flags.load(Ordering::SeqCst) & !(1u64 << id)) | value as u64, Ordering::SeqCst);
+        self.flags.store((self.flags.load(Ordering::SeqCst) & !(1u64 << id)) | ((value as u64) << id), Ordering::SeqCst);
     }
 
     pub fn get_flag(&self, id: u64) -> bool {
@@ -124,8 +160,236 @@ impl VirtualThread {
         }
     }
 
+    // `push` and `pop` that fail instead of leaving the stack region
+    pub fn try_push(&self, data: u64) -> Result<(), String> {
+        if !self.in_stack(self.get_reg::<u64>(4) as usize, 8) {
+            return Err(self.stack_error("overflow"));
+        }
+
+        self.push(data);
+
+        Ok(())
+    }
+
+    pub fn try_pop(&self) -> Result<u64, String> {
+        if !self.in_stack(self.get_reg::<u64>(4) as usize + 8, 8) {
+            return Err(self.stack_error("underflow"));
+        }
+
+        Ok(self.pop())
+    }
+
+    // Instructions executed by this thread, only counted by the thread itself and read when it is disposed
+    pub fn instructions(&self) -> u64 {
+        self.instructions.load(Ordering::Relaxed)
+    }
+
+    pub fn count_instruction(&self) {
+        self.instructions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn call_depth(&self) -> u64 {
+        self.call_depth.load(Ordering::SeqCst)
+    }
+
+    pub fn enter_call(&self) -> Result<(), String> {
+        let max = self.runtime.archive.conf.max_call_depth as u64;
+
+        if max != 0 && self.call_depth() >= max {
+            return Err(self.stack_error("overflow"));
+        }
+
+        self.call_depth.fetch_add(1, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    pub fn leave_call(&self) -> Result<(), String> {
+        self.call_depth.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| x.checked_sub(1)).map(|_| ()).map_err(|_| self.stack_error("underflow"))
+    }
+
+    // Pushes a handler frame that catches exceptions by jumping to `addr`
+    pub fn enter_try(&self, addr: u64) -> Result<(), String> {
+        for reg in 6..16 {
+            self.try_push(self.get_reg::<u64>(reg))?;
+        }
+
+        self.try_push(self.get_reg::<u64>(2))?;
+        self.try_push(self.call_depth())?;
+        self.try_push(self.handler.load(Ordering::SeqCst))?;
+        self.try_push(addr)?;
+
+        self.handler.store(self.get_reg::<u64>(4), Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    // Pops the newest handler frame and everything pushed after it
+    pub fn leave_try(&self) -> Result<(), String> {
+        let fp = self.handler_frame().ok_or_else(|| format!("No exception handler to remove at instruction 0x{:04X}.", self.get_reg::<u64>(0)))?;
+
+        self.handler.store(self.get_mem_absolute::<u64>(fp + 16), Ordering::SeqCst);
+        self.set_reg(4, fp as u64 + HANDLER_FRAME_WORDS * 8);
+
+        Ok(())
+    }
+
+    // Forgets handler frames that were popped with the stack, e.g. by RET
+    pub fn discard_handlers(&self) {
+        let top = self.get_reg::<u64>(4);
+        let mut fp = self.handler.load(Ordering::SeqCst);
+
+        while fp != 0 && fp < top && self.in_stack(fp as usize + 8, HANDLER_FRAME_WORDS as usize * 8 - 8) {
+            fp = self.get_mem_absolute::<u64>(fp as usize + 16);
+        }
+
+        self.handler.store(fp, Ordering::SeqCst);
+    }
+
+    fn handler_frame(&self) -> Option<usize> {
+        let fp = self.handler.load(Ordering::SeqCst) as usize;
+
+        if fp != 0 && self.in_stack(fp + 8, HANDLER_FRAME_WORDS as usize * 8 - 8) { Some(fp) } else { None }
+    }
+
+    // Unwinds to the newest handler frame, restoring the registers like RET, and jumps to its handler with `payload` in RET0.
+    // Returns false when no handler is installed.
+    pub fn throw(&self, payload: u64) -> bool {
+        let fp = match self.handler_frame() {
+            Some(fp) => fp,
+            None => return false
+        };
+
+        let word = |idx: usize| self.get_mem_absolute::<u64>(fp + idx * 8);
+
+        for reg in 6..16 {
+            self.set_reg(reg, word(20 - reg as usize));
+        }
+
+        self.set_reg(2, word(4));
+        self.call_depth.store(word(3), Ordering::SeqCst);
+        self.handler.store(word(2), Ordering::SeqCst);
+
+        self.set_reg(0, word(1));
+        self.set_reg(4, fp as u64 + HANDLER_FRAME_WORDS * 8);
+        self.set_reg(5, payload);
+
+        true
+    }
+
+    // Throws a VM error as a temp string, or records it as the error data when nothing catches it
+    pub async fn raise(self: VThread, message: impl Into<String>) -> bool {
+        let message = message.into();
+
+        if self.handler_frame().is_none() {
+            self.set_error_data(message).await;
+
+            return false;
+        }
+
+        let payload = VMStr::from_str(message, self.clone()).await.as_vm_value();
+
+        self.throw(payload)
+    }
+
+    fn stack_error(&self, kind: &str) -> String {
+        format!("Stack {kind} at instruction 0x{:04X} (depth {}).", self.get_reg::<u64>(0), self.call_depth())
+    }
+
+    fn in_stack(&self, addr: usize, size: usize) -> bool {
+        within(addr, size, self.stack.ptr() as usize, self.stack_size)
+    }
+
+    fn access_error(&self, addr: usize, size: usize, write: bool) -> String {
+        let kind = if write { "write" } else { "read" };
+
+        format!("Invalid memory {kind} of {size} bytes at 0x{addr:X} (instruction 0x{:04X}).", self.get_reg::<u64>(0))
+    }
+
+    // Whether the access stays inside this thread's stack, the memory image or a live VMStr
+    pub fn is_accessible(&self, addr: usize, size: usize, vmstrs: &HashSet<(u64, usize)>) -> bool {
+        self.in_stack(addr, size)
+            || within(addr, size, self.memory.ptr() as usize, self.memory.len())
+            || vmstrs.iter().any(|&(ptr, len)| within(addr, size, ptr as usize, len))
+    }
+
+    // Whether the access overlaps the code section of the memory image
+    fn in_code(&self, addr: usize, size: usize) -> bool {
+        let start = self.memory.ptr() as usize + self.runtime.initial_inst as usize;
+        let end = self.memory.ptr() as usize + self.memory.len();
+
+        addr < end && addr.saturating_add(size) > start
+    }
+
+    async fn check_access(&self, addr: usize, size: usize, write: bool) -> Result<(), String> {
+        if !self.bounds_check {
+            return Ok(());
+        }
+
+        if write && self.in_code(addr, size) {
+            return Err(self.access_error(addr, size, write));
+        }
+
+        if self.in_stack(addr, size) || self.is_accessible(addr, size, &*self.get_temp_vmstrs().await) {
+            return Ok(());
+        }
+
+        Err(self.access_error(addr, size, write))
+    }
+
+    // Whether a string pointer is a live VMStr or, for a constant string, lies inside readable memory
+    async fn is_valid_str(&self, ptr: usize, constant: bool) -> bool {
+        let vmstrs = self.get_temp_vmstrs().await;
+
+        if !constant {
+            return vmstrs.iter().any(|&(start, len)| start as usize == ptr && len >= 8 && self.get_mem_absolute::<u64>(ptr).checked_add(8) == Some(len as u64));
+        }
+
+        self.is_accessible(ptr, 8, &vmstrs)
+            && (self.get_mem_absolute::<u64>(ptr) as usize).checked_add(8).map_or(false, |size| self.is_accessible(ptr, size, &vmstrs))
+    }
+
+    // Value of a register, checking string pointers before they are dereferenced
+    pub async fn value(self: &VThread, reg: u8) -> Result<VMValue, String> {
+        let value = VMValue::from(self.get_reg::<u64>(reg), self.clone());
+
+        let string = match &value {
+            VMValue::ConstStr(vm_str) => Some((vm_str.ptr() as usize, true)),
+            VMValue::VarStr(vm_str) => Some((vm_str.ptr() as usize, false)),
+            VMValue::Float(_) => None
+        };
+
+        match string {
+            Some((ptr, constant)) if self.bounds_check && !self.is_valid_str(ptr, constant).await => {
+                Err(format!("Invalid string pointer 0x{ptr:X} in {} (instruction 0x{:04X}).", register_name(reg).unwrap(), self.get_reg::<u64>(0)))
+            }
+            _ => Ok(value)
+        }
+    }
+
+    pub async fn load<T: Copy>(&self, addr: usize) -> Result<T, String> {
+        self.check_access(addr, mem::size_of::<T>(), false).await?;
+
+        Ok(self.get_mem_absolute(addr))
+    }
+
+    pub async fn store<T: Copy>(&self, addr: usize, value: T) -> Result<(), String> {
+        self.check_access(addr, mem::size_of::<T>(), true).await?;
+
+        self.set_mem_absolute(addr, value);
+
+        Ok(())
+    }
+
     pub fn shutdown(self: VThread, shutdown_type: ShutdownType) {
-        self.runtime.shutdown(shutdown_type);
+        if shutdown_type == ShutdownType::Error && self.runtime.archive.conf.error_policy == ErrorPolicy::Isolate {
+            let message = self.error_data.lock().unwrap().take().unwrap_or_else(|| String::from("Extra Error Details weren't provided."));
+
+            self.runtime.thread_faulted(ThreadFault { thread: self.id, inst: self.get_reg::<u64>(0), message });
+        } else {
+            self.runtime.shutdown(shutdown_type);
+        }
+
         self.dispose();
     }
 
@@ -169,5 +433,9 @@ impl VirtualThread {
     }
 }
 
+fn within(addr: usize, size: usize, start: usize, len: usize) -> bool {
+    addr >= start && addr.checked_add(size).map_or(false, |end| end <= start + len)
+}
+
 unsafe impl Send for VirtualThread {}
 unsafe impl Sync for VirtualThread {}
\ No newline at end of file
@@ -1,7 +1,33 @@
-use crate::utils::ReadBuffer;
+use std::{str::FromStr, fmt::{self, Display}, error::Error};
 
-#[allow(dead_code)] // Constructed with ptr::read::<ExecutorKind>()
-#[derive(Clone, Copy)]
+// Conf.bin Layout
+//
+// 0x00  [u8; 4]  Magic (`OECF`)
+// 0x04  u8       Major Version
+// 0x05  u8       Minor Version
+// 0x06  u16      Payload Length
+// 0x08  Payload
+//       0x00  u8       Executor Kind
+//       0x01  u8       Threading Kind
+//       0x02  u16      Max Threads (0 = Number of CPUs)
+//       0x04  u8       Flags (since 1.1, bit 0 = Bounds-Checked Memory)
+//       0x05  u8       Error Policy (since 1.3, 0 = Shut Down the VM, 1 = Dispose the Faulting Thread)
+//       0x06  [u8; 2]  Reserved
+//       0x08  u64      Stack Size
+//       0x10  u32      Max Call Depth (since 1.2, 0 = Unlimited)
+//       0x14  [u8; 4]  Reserved
+//
+// Newer minor versions may append fields to the payload, which are ignored.
+// Headerless 16-byte files are read as a bare version 1.0 payload.
+
+pub const CONFIG_MAGIC: [u8; 4] = *b"OECF";
+pub const CONFIG_VERSION: (u8, u8) = (1, 3);
+pub const CONFIG_HEADER_SIZE: usize = 8;
+pub const CONFIG_PAYLOAD_SIZE: usize = 24;
+pub const CONFIG_MIN_PAYLOAD_SIZE: usize = 16;
+pub const CONFIG_FLAG_BOUNDS_CHECK: u8 = 0b1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExecutorKind {
     Atomic = 0,
     SysLockInst = 1,
@@ -10,31 +36,224 @@ pub enum ExecutorKind {
     SpinLockBlock = 4,
 }
 
-#[allow(dead_code)] // Constructed with ptr::read::<ThreadingKind>()
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ThreadingKind {
     Single = 0,
     Managed = 1,
     Unmanaged = 2,
 }
 
+// What a runtime error that is not caught by the guest does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    Shutdown = 0,
+    Isolate = 1,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    InvalidSize(usize),
+    UnsupportedVersion(u8, u8),
+    PayloadTooShort(usize),
+    LengthMismatch { expected: usize, found: usize },
+    InvalidExecutorKind(u8),
+    InvalidThreadingKind(u8),
+    InvalidErrorPolicy(u8),
+    InvalidStackSize(u64),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidSize(size) => write!(f, "Missing `OECF` header and not a {CONFIG_MIN_PAYLOAD_SIZE}-byte legacy config ({size} bytes)."),
+            ConfigError::UnsupportedVersion(major, minor) => write!(f, "Unsupported config version {major}.{minor}, this VM supports {}.x.", CONFIG_VERSION.0),
+            ConfigError::PayloadTooShort(size) => write!(f, "Payload is {size} bytes, at least {CONFIG_MIN_PAYLOAD_SIZE} bytes are required."),
+            ConfigError::LengthMismatch { expected, found } => write!(f, "Header declares {expected} bytes, found {found} bytes."),
+            ConfigError::InvalidExecutorKind(value) => write!(f, "Unknown executor kind {value}."),
+            ConfigError::InvalidThreadingKind(value) => write!(f, "Unknown threading kind {value}."),
+            ConfigError::InvalidErrorPolicy(value) => write!(f, "Unknown error policy {value}."),
+            ConfigError::InvalidStackSize(value) => write!(f, "Stack size {value} must be a non-zero multiple of 8."),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl TryFrom<u8> for ExecutorKind {
+    type Error = ConfigError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ExecutorKind::Atomic),
+            1 => Ok(ExecutorKind::SysLockInst),
+            2 => Ok(ExecutorKind::SpinLockInst),
+            3 => Ok(ExecutorKind::SysLockBlock),
+            4 => Ok(ExecutorKind::SpinLockBlock),
+            x => Err(ConfigError::InvalidExecutorKind(x))
+        }
+    }
+}
+
+impl TryFrom<u8> for ThreadingKind {
+    type Error = ConfigError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ThreadingKind::Single),
+            1 => Ok(ThreadingKind::Managed),
+            2 => Ok(ThreadingKind::Unmanaged),
+            x => Err(ConfigError::InvalidThreadingKind(x))
+        }
+    }
+}
+
+impl TryFrom<u8> for ErrorPolicy {
+    type Error = ConfigError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ErrorPolicy::Shutdown),
+            1 => Ok(ErrorPolicy::Isolate),
+            x => Err(ConfigError::InvalidErrorPolicy(x))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VMConfig {
     pub executor_kind: ExecutorKind,
     pub threading_kind: ThreadingKind,
     pub max_threads: u16,
     pub stack_size: u64,
+    pub bounds_check: bool,
+    pub max_call_depth: u32,
+    pub error_policy: ErrorPolicy,
 }
 
 impl VMConfig {
-    pub fn read(buffer: Box<[u8]>) -> VMConfig {
+    pub fn read(buffer: Box<[u8]>) -> Result<VMConfig, ConfigError> {
+        if !buffer.starts_with(&CONFIG_MAGIC) {
+            return match buffer.len() {
+                CONFIG_MIN_PAYLOAD_SIZE => VMConfig::read_payload(&buffer, 0),
+                x => Err(ConfigError::InvalidSize(x))
+            };
+        }
+
+        if buffer.len() < CONFIG_HEADER_SIZE {
+            return Err(ConfigError::InvalidSize(buffer.len()));
+        }
+
+        let (major, minor) = (buffer[4], buffer[5]);
+        let length = u16::from_le_bytes([buffer[6], buffer[7]]) as usize;
+
+        if major != CONFIG_VERSION.0 {
+            return Err(ConfigError::UnsupportedVersion(major, minor));
+        }
+
+        if buffer.len() != CONFIG_HEADER_SIZE + length {
+            return Err(ConfigError::LengthMismatch { expected: CONFIG_HEADER_SIZE + length, found: buffer.len() });
+        }
+
+        VMConfig::read_payload(&buffer[CONFIG_HEADER_SIZE..], minor)
+    }
+
+    // Fields newer than `minor` are reserved bytes in that version and keep their defaults
+    fn read_payload(payload: &[u8], minor: u8) -> Result<VMConfig, ConfigError> {
+        if payload.len() < CONFIG_MIN_PAYLOAD_SIZE {
+            return Err(ConfigError::PayloadTooShort(payload.len()));
+        }
+
+        let stack_size = u64::from_le_bytes(payload[8..16].try_into().unwrap());
+
+        if stack_size == 0 || stack_size % 8 != 0 {
+            return Err(ConfigError::InvalidStackSize(stack_size));
+        }
+
+        Ok(VMConfig {
+            executor_kind: ExecutorKind::try_from(payload[0])?,
+            threading_kind: ThreadingKind::try_from(payload[1])?,
+            max_threads: u16::from_le_bytes([payload[2], payload[3]]),
+            bounds_check: minor >= 1 && payload[4] & CONFIG_FLAG_BOUNDS_CHECK != 0,
+            max_call_depth: payload.get(16..20).filter(|_| minor >= 2).map_or(0, |x| u32::from_le_bytes(x.try_into().unwrap())),
+            error_policy: if minor >= 3 { ErrorPolicy::try_from(payload[5])? } else { ErrorPolicy::Shutdown },
+            stack_size,
+        })
+    }
+
+    pub fn write(&self) -> Box<[u8]> {
+        let mut buffer = Vec::with_capacity(CONFIG_HEADER_SIZE + CONFIG_PAYLOAD_SIZE);
+
+        buffer.extend_from_slice(&CONFIG_MAGIC);
+        buffer.extend_from_slice(&[CONFIG_VERSION.0, CONFIG_VERSION.1]);
+        buffer.extend_from_slice(&(CONFIG_PAYLOAD_SIZE as u16).to_le_bytes());
+        buffer.extend_from_slice(&[self.executor_kind as u8, self.threading_kind as u8]);
+        buffer.extend_from_slice(&self.max_threads.to_le_bytes());
+        buffer.extend_from_slice(&[if self.bounds_check { CONFIG_FLAG_BOUNDS_CHECK } else { 0 }, self.error_policy as u8, 0, 0]);
+        buffer.extend_from_slice(&self.stack_size.to_le_bytes());
+        buffer.extend_from_slice(&self.max_call_depth.to_le_bytes());
+        buffer.extend_from_slice(&[0; 4]);
+
+        buffer.into_boxed_slice()
+    }
+
+    pub fn worker_threads(&self) -> usize {
+        match self.max_threads {
+            0 => num_cpus::get(),
+            x => x as usize
+        }
+    }
+}
+
+impl Default for VMConfig {
+    fn default() -> Self {
         VMConfig {
-            executor_kind: buffer.const_read::<0, ExecutorKind>(),
-            threading_kind: buffer.const_read::<1, ThreadingKind>(),
-            max_threads: match buffer.const_read::<2, u16>() {
-                0 => num_cpus::get() as u16,
-                x => x
-            },
-            stack_size: buffer.const_read::<8, u64>(),
+            executor_kind: ExecutorKind::Atomic,
+            threading_kind: ThreadingKind::Managed,
+            max_threads: 0,
+            stack_size: 1024 * 1024,
+            bounds_check: false,
+            max_call_depth: 0,
+            error_policy: ErrorPolicy::Shutdown,
         }
     }
-}
\ No newline at end of file
+}
+
+impl FromStr for ExecutorKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "0" | "atomic" => Ok(ExecutorKind::Atomic),
+            "1" | "sys-lock-inst" => Ok(ExecutorKind::SysLockInst),
+            "2" | "spin-lock-inst" => Ok(ExecutorKind::SpinLockInst),
+            "3" | "sys-lock-block" => Ok(ExecutorKind::SysLockBlock),
+            "4" | "spin-lock-block" => Ok(ExecutorKind::SpinLockBlock),
+            _ => Err(format!("Unknown executor kind `{value}`."))
+        }
+    }
+}
+
+impl FromStr for ThreadingKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "0" | "single" => Ok(ThreadingKind::Single),
+            "1" | "managed" => Ok(ThreadingKind::Managed),
+            "2" | "unmanaged" => Ok(ThreadingKind::Unmanaged),
+            _ => Err(format!("Unknown threading kind `{value}`."))
+        }
+    }
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "0" | "shutdown" => Ok(ErrorPolicy::Shutdown),
+            "1" | "isolate" => Ok(ErrorPolicy::Isolate),
+            _ => Err(format!("Unknown error policy `{value}`."))
+        }
+    }
+}
@@ -3,6 +3,26 @@ use crate::utils;
 utils::gen_enum!(Intrinsic, u8, [
     Debug = 0x00,
     Sleep = 0x01,
+    MutexLock = 0x02,
+    MutexUnlock = 0x03,
+    SemInit = 0x04,
+    SemAcquire = 0x05,
+    SemRelease = 0x06,
+    CondWait = 0x07,
+    CondNotify = 0x08,
+    CondNotifyAll = 0x09,
+    ChanCreate = 0x0A,
+    ChanSend = 0x0B,
+    ChanRecv = 0x0C,
+    ChanTryRecv = 0x0D,
+    SignalListen = 0x0E,
+    SignalWait = 0x0F,
+    Broadcast = 0x10,
+    ThreadJoin = 0x11,
+    ThreadAlive = 0x12,
+    ThreadCancel = 0x13,
+    StopSelf = 0x14,
+    StopOthers = 0x15,
     Restart = 0xFE,
     Throw = 0xFF,
 ]);
\ No newline at end of file
@@ -1,31 +1,117 @@
+use std::{fmt::Write, future::Future};
+
 use tokio::time::Duration;
 
-use crate::{virtual_thread::VThread, vm_value::VMValue, thread_counter::ShutdownType, executor::executor::{ExecutorBehaviour, Lock}, utils::handle_lock};
+use crate::{virtual_thread::VThread, vm_value::{VMValue, STR_SIGNATURE}, thread_counter::ShutdownType, executor::executor::{ExecutorBehaviour, Lock}, register::register_name, utils::handle_lock};
 
 use self::intrinsics::Intrinsic;
 
-mod intrinsics;
+pub mod intrinsics;
+
+// Name of a sync object, e.g. `lstr r0, [base+name]` before `int MutexLock`
+async fn name(thread: &VThread, reg: u8) -> Result<String, String> {
+    match thread.value(reg).await? {
+        VMValue::ConstStr(name) | VMValue::VarStr(name) => Ok(name.as_str().to_owned()),
+        VMValue::Float(_) => Err(format!("{} is not the name of a sync object.", register_name(reg).unwrap()))
+    }
+}
+
+fn permits(thread: &VThread, reg: u8) -> Result<usize, String> {
+    match VMValue::from(thread.get_reg::<u64>(reg), thread.clone()) {
+        VMValue::Float(value) if value >= 0.0 && value.fract() == 0.0 => Ok(value as usize),
+        _ => Err(format!("{} is not a permit count.", register_name(reg).unwrap()))
+    }
+}
+
+fn channel(thread: &VThread, reg: u8) -> Result<u64, String> {
+    match VMValue::from(thread.get_reg::<u64>(reg), thread.clone()) {
+        VMValue::Float(value) if value >= 1.0 && value.fract() == 0.0 => Ok(value as u64),
+        _ => Err(format!("{} is not a channel handle.", register_name(reg).unwrap()))
+    }
+}
+
+// Id stored by `spawn target, reg`
+fn thread_id(thread: &VThread, reg: u8) -> Result<u64, String> {
+    match VMValue::from(thread.get_reg::<u64>(reg), thread.clone()) {
+        VMValue::Float(value) if value.fract() == 0.0 && thread.runtime.is_thread_id(value as u64) => Ok(value as u64),
+        _ => Err(format!("{} is not a thread id.", register_name(reg).unwrap()))
+    }
+}
+
+// Code offset of a pointer made by `lea1 r1, [base+handler]`
+fn handler(thread: &VThread, reg: u8) -> Result<u64, String> {
+    let offset = thread.get_reg::<u64>(reg).wrapping_sub(thread.get_reg::<u64>(1));
+
+    match offset >= thread.runtime.initial_inst && offset < thread.runtime.archive.code.len() as u64 && offset & 7 == 0 {
+        true => Ok(offset),
+        false => Err(format!("{} is not a code address.", register_name(reg).unwrap()))
+    }
+}
+
+// None if the thread is cancelled first, the executor then disposes it
+async fn cancellable<T>(thread: &VThread, future: impl Future<Output = T>) -> Option<T> {
+    tokio::select! {
+        value = future => Some(value),
+        _ = thread.handle.cancelled() => None
+    }
+}
+
+// Raises the error of a sync or channel intrinsic in the calling thread
+async fn check(thread: &VThread, result: Result<(), String>) -> ExecutorBehaviour {
+    match result {
+        Ok(()) => ExecutorBehaviour::None,
+        Err(message) => match thread.clone().raise(message).await {
+            true => ExecutorBehaviour::None,
+            false => ExecutorBehaviour::Shutdown(ShutdownType::Error)
+        }
+    }
+}
 
 pub async fn call<const DROP: bool>(thread: VThread, id: u8, mut lock: Lock) -> (Lock, ExecutorBehaviour) {
     let behaviour = match id {
         Intrinsic::Debug => {
-            eprintln!("----- Register Dump -----");
-            eprintln!("INST: {}", VMValue::from(thread.get_reg::<u64>(0), thread.clone()));
-            eprintln!("BASE: {}", VMValue::from(thread.get_reg::<u64>(1), thread.clone()));
-            eprintln!("FUNC: {}", VMValue::from(thread.get_reg::<u64>(2), thread.clone()));
-            eprintln!("OBJ:  {}", VMValue::from(thread.get_reg::<u64>(3), thread.clone()));
-            eprintln!("TOP:  {}\n", VMValue::from(thread.get_reg::<u64>(4), thread.clone()));
-            eprintln!("RET0: {}\n", VMValue::from(thread.get_reg::<u64>(5), thread.clone()));
-            eprintln!("D0:   {}", VMValue::from(thread.get_reg::<u64>(6), thread.clone()));
-            eprintln!("D1:   {}\n", VMValue::from(thread.get_reg::<u64>(7), thread.clone()));
-            eprintln!("R0:   {}", VMValue::from(thread.get_reg::<u64>(8), thread.clone()));
-            eprintln!("R1:   {}", VMValue::from(thread.get_reg::<u64>(9), thread.clone()));
-            eprintln!("R2:   {}", VMValue::from(thread.get_reg::<u64>(10), thread.clone()));
-            eprintln!("R3:   {}", VMValue::from(thread.get_reg::<u64>(11), thread.clone()));
-            eprintln!("R4:   {}", VMValue::from(thread.get_reg::<u64>(12), thread.clone()));
-            eprintln!("R5:   {}", VMValue::from(thread.get_reg::<u64>(13), thread.clone()));
-            eprintln!("R6:   {}", VMValue::from(thread.get_reg::<u64>(14), thread.clone()));
-            eprintln!("R7:   {}", VMValue::from(thread.get_reg::<u64>(15), thread.clone()));
+            let mut values = Vec::new();
+
+            // Invalid string pointers are dumped without being dereferenced, NULL is displayed as is
+            for reg in 0..16 {
+                let raw = thread.get_reg::<u64>(reg);
+                let value = VMValue::from(raw, thread.clone());
+
+                values.push(match thread.value(reg).await {
+                    Err(_) if !matches!(&value, VMValue::ConstStr(x) | VMValue::VarStr(x) if x.ptr().is_null()) => format!("0x{raw:016X} [invalid str]"),
+                    _ => value.to_string()
+                });
+            }
+
+            let value = |reg: usize| &values[reg];
+            let mut out = String::from("----- Register Dump -----\n");
+
+            if let Some(range) = thread.source_range() {
+                let _ = writeln!(out, "{range}\n");
+            }
+
+            let _ = writeln!(out, "INST: {}", value(0));
+            let _ = writeln!(out, "BASE: {}", value(1));
+            let _ = writeln!(out, "FUNC: {}", value(2));
+            let _ = writeln!(out, "OBJ:  {}", value(3));
+            let _ = writeln!(out, "TOP:  {}\n", value(4));
+            let _ = writeln!(out, "RET0: {}\n", value(5));
+            let _ = writeln!(out, "D0:   {}", value(6));
+            let _ = writeln!(out, "D1:   {}\n", value(7));
+            let _ = writeln!(out, "R0:   {}", value(8));
+            let _ = writeln!(out, "R1:   {}", value(9));
+            let _ = writeln!(out, "R2:   {}", value(10));
+            let _ = writeln!(out, "R3:   {}", value(11));
+            let _ = writeln!(out, "R4:   {}", value(12));
+            let _ = writeln!(out, "R5:   {}", value(13));
+            let _ = writeln!(out, "R6:   {}", value(14));
+            let _ = writeln!(out, "R7:   {}", value(15));
+
+            if !thread.runtime.sync.is_empty() {
+                let _ = write!(out, "\n----- Sync Objects -----\n{}", thread.runtime.sync);
+            }
+
+            thread.runtime.write_stderr(&out);
 
             ExecutorBehaviour::None
         }
@@ -36,8 +122,193 @@ pub async fn call<const DROP: bool>(thread: VThread, id: u8, mut lock: Lock) ->
                 // So, We MUST drop after we passed critical section.
                 drop(lock.take());
 
-                tokio::time::sleep(Duration::from_secs_f64(value)).await;
+                cancellable(&thread, tokio::time::sleep(Duration::from_secs_f64(value))).await;
+            }
+
+            ExecutorBehaviour::None
+        }
+        Intrinsic::MutexLock => {
+            let result = match name(&thread, 8).await {
+                Ok(name) => {
+                    // Waiting for the mutex must not hold the executor lock, like `Sleep`
+                    drop(lock.take());
+
+                    cancellable(&thread, thread.runtime.sync.lock(&name, thread.id)).await.unwrap_or(Ok(())).map_err(|err| err.to_string())
+                }
+                Err(message) => Err(message)
+            };
+
+            check(&thread, result).await
+        }
+        Intrinsic::MutexUnlock => {
+            let result = name(&thread, 8).await.and_then(|name| thread.runtime.sync.unlock(&name, thread.id).map_err(|err| err.to_string()));
+
+            check(&thread, result).await
+        }
+        Intrinsic::SemInit => {
+            let result = name(&thread, 8).await.and_then(|name| {
+                thread.runtime.sync.sem_init(&name, permits(&thread, 9)?).map_err(|err| err.to_string())
+            });
+
+            check(&thread, result).await
+        }
+        Intrinsic::SemAcquire => {
+            let result = match name(&thread, 8).await {
+                Ok(name) => {
+                    drop(lock.take());
+
+                    cancellable(&thread, thread.runtime.sync.acquire(&name, thread.id)).await;
+
+                    Ok(())
+                }
+                Err(message) => Err(message)
+            };
+
+            check(&thread, result).await
+        }
+        Intrinsic::SemRelease => {
+            let result = name(&thread, 8).await.and_then(|name| thread.runtime.sync.release(&name).map_err(|err| err.to_string()));
+
+            check(&thread, result).await
+        }
+        Intrinsic::CondWait => {
+            let names = match name(&thread, 8).await {
+                Ok(name) => self::name(&thread, 9).await.map(|mutex| (name, mutex)),
+                Err(message) => Err(message)
+            };
+
+            let result = match names {
+                Ok((name, mutex)) => {
+                    drop(lock.take());
+
+                    cancellable(&thread, thread.runtime.sync.wait(&name, &mutex, thread.id)).await.unwrap_or(Ok(())).map_err(|err| err.to_string())
+                }
+                Err(message) => Err(message)
+            };
+
+            check(&thread, result).await
+        }
+        Intrinsic::CondNotify | Intrinsic::CondNotifyAll => {
+            let result = name(&thread, 8).await.map(|name| thread.runtime.sync.notify(&name, id == Intrinsic::CondNotifyAll));
+
+            check(&thread, result).await
+        }
+        Intrinsic::ChanCreate => {
+            thread.set_reg(5, (thread.runtime.channels.create() as f64).to_bits());
+
+            ExecutorBehaviour::None
+        }
+        Intrinsic::ChanSend => {
+            let value = thread.get_reg::<u64>(9);
+            let result = channel(&thread, 8).and_then(|id| thread.runtime.channels.send(id, value).map_err(|err| err.to_string()));
+
+            // A sent var string now belongs to the receiver
+            if result.is_ok() && let VMValue::VarStr(_) = VMValue::from(value, thread.clone()) {
+                thread.set_reg::<u64>(9, STR_SIGNATURE);
+            }
+
+            check(&thread, result).await
+        }
+        Intrinsic::ChanRecv => {
+            let result = match channel(&thread, 8) {
+                Ok(id) => {
+                    drop(lock.take());
+
+                    match cancellable(&thread, thread.runtime.channels.recv(id)).await {
+                        Some(result) => result.map(|value| thread.set_reg(5, value)).map_err(|err| err.to_string()),
+                        None => Ok(())
+                    }
+                }
+                Err(message) => Err(message)
+            };
+
+            check(&thread, result).await
+        }
+        Intrinsic::ChanTryRecv => {
+            // Sets the flag `jt` reads if a value was received
+            let result = channel(&thread, 8).and_then(|id| thread.runtime.channels.try_recv(id).map_err(|err| err.to_string())).map(|value| {
+                if let Some(value) = value {
+                    thread.set_reg(5, value);
+                }
+
+                thread.set_flag(0, value.is_some());
+            });
+
+            check(&thread, result).await
+        }
+        Intrinsic::SignalListen => {
+            let result = name(&thread, 8).await.and_then(|name| Ok((name, handler(&thread, 9)?))).map(|(name, addr)| {
+                thread.runtime.channels.listen(&name, addr);
+            });
+
+            check(&thread, result).await
+        }
+        Intrinsic::SignalWait => {
+            let result = match name(&thread, 8).await {
+                Ok(name) => {
+                    drop(lock.take());
+
+                    cancellable(&thread, thread.runtime.channels.wait(&name)).await;
+
+                    Ok(())
+                }
+                Err(message) => Err(message)
+            };
+
+            check(&thread, result).await
+        }
+        Intrinsic::Broadcast => {
+            match name(&thread, 8).await {
+                Ok(name) => {
+                    for addr in thread.runtime.channels.broadcast(&name) {
+                        thread.runtime.spawn(addr).await;
+                    }
+
+                    ExecutorBehaviour::None
+                }
+                Err(message) => check(&thread, Err(message)).await
             }
+        }
+        Intrinsic::ThreadJoin => {
+            let result = match thread_id(&thread, 8) {
+                Ok(id) if id == thread.id => Err(String::from("A thread cannot join itself.")),
+                Ok(id) => {
+                    if let Some(handle) = thread.runtime.thread_handle(id) {
+                        drop(lock.take());
+
+                        cancellable(&thread, handle.join()).await;
+                    }
+
+                    Ok(())
+                }
+                Err(message) => Err(message)
+            };
+
+            check(&thread, result).await
+        }
+        Intrinsic::ThreadAlive => {
+            // Sets the flag `jt` reads if the thread hasn't ended
+            let result = thread_id(&thread, 8).map(|id| thread.set_flag(0, thread.runtime.thread_handle(id).is_some()));
+
+            check(&thread, result).await
+        }
+        Intrinsic::ThreadCancel => {
+            let result = thread_id(&thread, 8).map(|id| {
+                if let Some(handle) = thread.runtime.thread_handle(id) {
+                    handle.cancel();
+                }
+            });
+
+            check(&thread, result).await
+        }
+        Intrinsic::StopSelf => {
+            // Disposed by the executor
+            thread.runtime.stop_thread(&thread.handle);
+
+            ExecutorBehaviour::None
+        }
+        Intrinsic::StopOthers => {
+            thread.runtime.stop_other_threads(thread.id);
 
             ExecutorBehaviour::None
         }
@@ -45,9 +316,10 @@ pub async fn call<const DROP: bool>(thread: VThread, id: u8, mut lock: Lock) ->
             ExecutorBehaviour::Shutdown(ShutdownType::Restarting)
         }
         Intrinsic::Throw => {
-            thread.set_error_data("Called Intrinsic::Throw").await;
-            
-            ExecutorBehaviour::Shutdown(ShutdownType::Error)
+            match thread.clone().raise("Called Intrinsic::Throw").await {
+                true => ExecutorBehaviour::None,
+                false => ExecutorBehaviour::Shutdown(ShutdownType::Error)
+            }
         }
         _ => panic!("Unsupported VM Intrinsic Call")
     };